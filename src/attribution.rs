@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use spdx::{Expression, LicenseItem};
+
+use crate::error::AttributionError;
+use crate::extractor::{Record, open_zip};
+use crate::license::{NOASSERTION, copyright_holders, license_file_holders, license_files};
+use crate::sink::read_records;
+
+const ATTRIBUTION_FILE: &str = "ATTRIBUTION.md";
+const LICENSES_FILE: &str = "LICENSES.txt";
+const RULE: &str =
+    "================================================================================";
+
+// What the bundle says about one source repository
+#[derive(Default)]
+struct RepoAttribution {
+    branch: String,
+    commit_sha: Option<String>,
+    licenses: BTreeSet<String>,
+    holders: BTreeSet<String>,
+    files: usize,
+    // Root license files of the archive, with --zip-dir
+    license_texts: Vec<String>,
+}
+
+impl RepoAttribution {
+    fn add(&mut self, record: &Record) {
+        // Later windows of a chunked file start mid-file
+        if record.chunk_index.is_some_and(|i| i > 0) {
+            return;
+        }
+        if self.files == 0 {
+            self.branch = record.branch.clone();
+            self.commit_sha = record.commit_sha.clone();
+        }
+        self.files += 1;
+        self.licenses.insert(
+            record
+                .license
+                .clone()
+                .unwrap_or_else(|| NOASSERTION.to_owned()),
+        );
+        self.holders.extend(copyright_holders(&record.text));
+    }
+
+    // Licenses of the repository without a bundled SPDX text, NOASSERTION
+    // parses but has none
+    fn unknown_license(&self) -> bool {
+        self.licenses.iter().any(|l| {
+            Expression::parse(l).map_or(true, |expr| {
+                expr.requirements().any(|req| match req.req.license {
+                    LicenseItem::Spdx { id, .. } => id.text().is_empty(),
+                    LicenseItem::Other { .. } => true,
+                })
+            })
+        })
+    }
+}
+
+// Reads the root license files of a repository's archive
+fn read_license_files(
+    zip_dir: &Path,
+    repo_name: &str,
+) -> Result<Option<Vec<String>>, AttributionError> {
+    let zip_path = zip_dir.join(format!("{}.zip", repo_name.replace('/', "-")));
+    if !zip_path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(license_files(&mut open_zip(&zip_path)?)?))
+}
+
+// SPDX licenses and exceptions named by the license expressions, with their texts
+fn spdx_texts(repos: &BTreeMap<String, RepoAttribution>) -> BTreeMap<&'static str, &'static str> {
+    let mut texts = BTreeMap::new();
+    let expressions = repos.values().flat_map(|r| &r.licenses);
+    for expr in expressions.filter_map(|l| Expression::parse(l).ok()) {
+        for req in expr.requirements() {
+            if let LicenseItem::Spdx { id, .. } = req.req.license
+                && !id.text().is_empty()
+            {
+                texts.insert(id.name, id.text());
+            }
+            if let Some(exception) = req.req.exception {
+                texts.insert(exception.name, exception.text());
+            }
+        }
+    }
+    texts
+}
+
+fn attribution_table(repos: &BTreeMap<String, RepoAttribution>) -> String {
+    let mut out = String::from(
+        "# Attribution\n\nSource repositories of this dataset, the commit their files were taken \
+         from, the licenses of those files and the copyright holders named in their \
+         copyright notices. The license texts are in LICENSES.txt.\n\n\
+         | Repository | Branch | Commit | License | Copyright holders | Files |\n\
+         |---|---|---|---|---|---:|\n",
+    );
+    for (repo_name, r) in repos {
+        let licenses: Vec<&str> = r.licenses.iter().map(String::as_str).collect();
+        let holders: Vec<String> = r.holders.iter().map(|h| h.replace('|', "\\|")).collect();
+        let _ = writeln!(
+            out,
+            "| [{repo_name}](https://github.com/{repo_name}) | {} | {} | {} | {} | {} |",
+            r.branch,
+            r.commit_sha.as_deref().unwrap_or("-"),
+            licenses.join(", "),
+            if holders.is_empty() {
+                String::from("-")
+            } else {
+                holders.join("; ")
+            },
+            r.files
+        );
+    }
+    out
+}
+
+// The text of every SPDX license once, then the license files of the
+// repositories whose license is not an SPDX one
+fn license_texts(repos: &BTreeMap<String, RepoAttribution>) -> String {
+    let mut out = String::new();
+    for (id, text) in spdx_texts(repos) {
+        let _ = write!(out, "{RULE}\n{id}\n{RULE}\n\n{}\n\n", text.trim_end());
+    }
+    for (repo_name, r) in repos.iter().filter(|(_, r)| r.unknown_license()) {
+        for text in &r.license_texts {
+            let _ = write!(
+                out,
+                "{RULE}\n{NOASSERTION}: {repo_name}\n{RULE}\n\n{}\n\n",
+                text.trim_end()
+            );
+        }
+    }
+    out
+}
+
+// Writes ATTRIBUTION.md and LICENSES.txt for the records of `paths` to
+// `dest_dir`. `repos` restricts sharded inputs, which mix repositories.
+// With `zip_dir`, holders of the root license files are added too, and
+// the license files of repositories without an SPDX license are appended.
+pub fn write_attribution(
+    paths: &[PathBuf],
+    repos: Option<&HashSet<String>>,
+    zip_dir: Option<&Path>,
+    dest_dir: &Path,
+) -> Result<usize, AttributionError> {
+    let mut by_repo: BTreeMap<String, RepoAttribution> = BTreeMap::new();
+    for path in paths {
+        for record in read_records(path)? {
+            if repos.is_some_and(|r| !r.contains(&record.repo_name.replace('/', "-"))) {
+                continue;
+            }
+            by_repo
+                .entry(record.repo_name.clone())
+                .or_default()
+                .add(&record);
+        }
+    }
+    if let Some(zip_dir) = zip_dir {
+        for (repo_name, r) in by_repo.iter_mut() {
+            let Some(texts) = read_license_files(zip_dir, repo_name)? else {
+                eprintln!(
+                    "{} No archive of {} in {}, its license files are not read",
+                    "[WARNING]".truecolor(214, 143, 0),
+                    repo_name,
+                    zip_dir.display()
+                );
+                continue;
+            };
+            for text in &texts {
+                r.holders.extend(license_file_holders(text));
+            }
+            r.license_texts = texts;
+        }
+    } else {
+        let unknown: Vec<&str> = by_repo
+            .iter()
+            .filter(|(_, r)| r.unknown_license())
+            .map(|(repo_name, _)| repo_name.as_str())
+            .collect();
+        if !unknown.is_empty() {
+            eprintln!(
+                "{} {} have no SPDX license text, pass --zip-dir to add their license files: {}",
+                "[WARNING]".truecolor(214, 143, 0),
+                unknown.len(),
+                unknown.join(", ")
+            );
+        }
+    }
+
+    fs::create_dir_all(dest_dir)?;
+    fs::write(dest_dir.join(ATTRIBUTION_FILE), attribution_table(&by_repo))?;
+    fs::write(dest_dir.join(LICENSES_FILE), license_texts(&by_repo))?;
+    Ok(by_repo.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::attribution::{RepoAttribution, attribution_table, license_texts};
+    use crate::extractor::Record;
+
+    #[test]
+    fn test_attribution() {
+        let mut repo = RepoAttribution::default();
+        for (license, text) in [
+            ("MIT", "# Copyright (c) 2024 Jane Doe\nx = 1\n"),
+            ("MIT OR Apache-2.0", "y = 2\n"),
+        ] {
+            let mut r = Record::for_test("user/repo", "a.py", "Python", text);
+            r.commit_sha = Some(String::from("abc"));
+            r.license = Some(license.to_owned());
+            repo.add(&r);
+        }
+        let repos = BTreeMap::from([(String::from("user/repo"), repo)]);
+
+        let table = attribution_table(&repos);
+        assert!(table.contains(
+            "| [user/repo](https://github.com/user/repo) | main | abc | MIT, MIT OR Apache-2.0 | Jane Doe | 2 |"
+        ));
+        let texts = license_texts(&repos);
+        let headings: Vec<&str> = texts
+            .lines()
+            .filter(|l| *l == "MIT" || *l == "Apache-2.0")
+            .collect();
+        assert_eq!(headings, vec!["Apache-2.0", "MIT"]);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::{BTreeMap, HashMap};
+
+use colored::Colorize;
+
+use crate::exclusion::{Exclusion, ExclusionReason};
+use crate::extractor::{Record, count_tokens};
+use crate::tokenization::Tokenizers;
+
+// Token windows of files longer than the budget
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkOptions {
+    pub max_tokens: usize,
+    // Tokens repeated at the start of the next window
+    pub overlap: usize,
+}
+
+// Start of a line that opens a new top-level block: the line after a blank
+// line, or an unindented line that does not close the previous block
+fn boundaries(text: &str) -> (Vec<usize>, Vec<usize>) {
+    let mut preferred = Vec::new();
+    let mut line_starts = Vec::new();
+    let mut offset = 0;
+    let mut after_blank = false;
+    for line in text.split_inclusive('\n') {
+        if offset > 0 {
+            line_starts.push(offset);
+            let top_level = line
+                .chars()
+                .next()
+                .is_some_and(|c| !c.is_whitespace() && !matches!(c, '}' | ')' | ']'));
+            if after_blank || top_level {
+                preferred.push(offset);
+            }
+        }
+        after_blank = line.trim().is_empty();
+        offset += line.len();
+    }
+    (preferred, line_starts)
+}
+
+// Last boundary in (from, to], if any
+fn last_in(bounds: &[usize], from: usize, to: usize) -> Option<usize> {
+    let i = bounds.partition_point(|&b| b <= to);
+    bounds[..i].last().copied().filter(|&b| b > from)
+}
+
+// Byte ranges of overlapping windows of at most `max_tokens` tokens, given
+// the byte offsets where the tokens of `text` start. Windows end at the
+// last top-level boundary that fits, else at a line start, and only split
+// mid-line when a single line is longer than the window.
+pub fn chunk_ranges(
+    text: &str,
+    token_starts: &[usize],
+    opts: &ChunkOptions,
+) -> Vec<(usize, usize)> {
+    let max_tokens = opts.max_tokens.max(1);
+    let overlap = opts.overlap.min(max_tokens / 2);
+    if token_starts.len() <= max_tokens {
+        return vec![(0, text.len())];
+    }
+    let (preferred, line_starts) = boundaries(text);
+    // Tokens starting before a byte offset
+    let token_at = |byte: usize| token_starts.partition_point(|&s| s < byte);
+
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    loop {
+        let first = token_at(start);
+        if token_starts.len() - first <= max_tokens {
+            ranges.push((start, text.len()));
+            return ranges;
+        }
+        let limit = token_starts[first + max_tokens];
+        // Windows under half the budget are not worth a clean cut
+        let min_end = token_starts[first + max_tokens / 2];
+        let end = last_in(&preferred, min_end, limit)
+            .or_else(|| last_in(&line_starts, min_end, limit))
+            .unwrap_or(limit);
+        ranges.push((start, end));
+
+        // The next window starts at the line holding the overlap
+        let target = token_starts[token_at(end).saturating_sub(overlap)];
+        let next = last_in(&line_starts, start, target).unwrap_or(target);
+        start = if next > start && next <= end {
+            next
+        } else {
+            end
+        };
+    }
+}
+
+// Windows of `record`, whose text was taken out as `whole`, with their
+// own ids, offsets and metrics
+fn windows(
+    record: &Record,
+    whole: &str,
+    ranges: &[(usize, usize)],
+    record_id: &impl Fn(&str) -> String,
+) -> Vec<Record> {
+    ranges
+        .iter()
+        .enumerate()
+        .map(|(i, &(start, end))| {
+            let mut chunk = record.clone();
+            chunk.id = record_id(&format!("{}#{i}", record.path));
+            chunk.text = whole[start..end].to_owned();
+            chunk.parent_id = Some(record.id.clone());
+            chunk.chunk_index = Some(i as u32);
+            chunk.chunk_count = Some(ranges.len() as u32);
+            chunk.chunk_offset = Some(start as u64);
+            chunk.text_changed();
+            chunk
+        })
+        .collect()
+}
+
+// Replaces records longer than the token budget of the first tokenizer by
+// their windows, in file order. `record_id` gives the id of a window from
+// "<path>#<index>".
+pub fn chunk_records(
+    records: Vec<Record>,
+    chunking: &ChunkOptions,
+    record_id: impl Fn(&str) -> String,
+    tokenizers: &Tokenizers,
+    excluded: &mut Vec<Exclusion>,
+) -> Vec<Record> {
+    let Some(primary) = tokenizers.primary() else {
+        return records;
+    };
+    let mut out = Vec::with_capacity(records.len());
+    'files: for mut r in records {
+        if r.tokens
+            .get(primary)
+            .is_none_or(|&n| n <= chunking.max_tokens)
+        {
+            out.push(r);
+            continue;
+        }
+        let whole = std::mem::take(&mut r.text);
+        let starts = match tokenizers.token_starts(&whole) {
+            Ok(starts) => starts,
+            Err(e) => {
+                excluded.push(Exclusion {
+                    path: r.path,
+                    reason: ExclusionReason::Tokenizer,
+                    rule: e.to_string(),
+                });
+                continue;
+            }
+        };
+        // Windows are cut on the tokens of the whole file. Tokenized on its
+        // own, a window that starts inside a token ("\n    return") or
+        // whose edges merge differently can exceed the budget, the file is
+        // then cut again with the budget lowered by the excess.
+        let mut budget = chunking.max_tokens;
+        let chunks = loop {
+            let opts = ChunkOptions {
+                max_tokens: budget,
+                ..*chunking
+            };
+            let ranges = chunk_ranges(&whole, &starts, &opts);
+            // A window that fails to tokenize leaves a gap, drop the whole file
+            let mut failed = Vec::new();
+            let chunks = count_tokens(
+                windows(&r, &whole, &ranges, &record_id),
+                tokenizers,
+                &mut failed,
+            );
+            if let Some(e) = failed.pop() {
+                excluded.push(e);
+                continue 'files;
+            }
+            let longest = chunks
+                .iter()
+                .filter_map(|c| c.tokens.get(primary).copied())
+                .max()
+                .unwrap_or_default();
+            if longest <= chunking.max_tokens || budget == 1 {
+                break chunks;
+            }
+            budget = budget.saturating_sub(longest - chunking.max_tokens).max(1);
+        };
+        out.extend(chunks);
+    }
+    out
+}
+
+// The whole file of a complete set of windows. Its token counts are the
+// sums of the window counts less the repeated text, whose share of a window
+// is taken in proportion to its bytes and rounded down, so the sum errs high.
+fn join_windows(parent_id: String, mut windows: Vec<Record>) -> Option<Record> {
+    windows.sort_by_key(|w| w.chunk_index);
+    let complete = windows.iter().enumerate().all(|(i, w)| {
+        w.chunk_index == Some(i as u32)
+            && w.chunk_count == Some(windows.len() as u32)
+            && w.chunk_offset.is_some()
+    });
+    if !complete {
+        return None;
+    }
+    let mut text = String::new();
+    let mut tokens: BTreeMap<String, usize> = BTreeMap::new();
+    for w in &windows {
+        // Each window repeats the end of the previous one
+        let offset = w.chunk_offset.unwrap_or_default() as usize;
+        if offset > text.len() || !text.is_char_boundary(offset) {
+            return None;
+        }
+        let repeated = (text.len() - offset).min(w.text.len());
+        for (name, count) in &w.tokens {
+            let count = count - count * repeated / w.text.len().max(1);
+            *tokens.entry(name.clone()).or_default() += count;
+        }
+        text.truncate(offset);
+        text.push_str(&w.text);
+    }
+    // Only tokenizers that counted every window
+    tokens.retain(|name, _| windows.iter().all(|w| w.tokens.contains_key(name)));
+    let mut whole = windows.swap_remove(0);
+    whole.id = parent_id;
+    whole.text = text;
+    whole.parent_id = None;
+    whole.chunk_index = None;
+    whole.chunk_count = None;
+    whole.chunk_offset = None;
+    whole.text_changed();
+    whole.tokens = tokens;
+    Some(whole)
+}
+
+// Puts the windows of chunked files back together, each whole file taking
+// the place of its first window. Files missing a window are dropped.
+pub fn join_chunks(records: Vec<Record>) -> Vec<Record> {
+    let mut out: Vec<Option<Record>> = Vec::with_capacity(records.len());
+    // Parent id -> slot in `out` and the windows seen so far
+    let mut files: HashMap<String, (usize, Vec<Record>)> = HashMap::new();
+    for r in records {
+        let Some(parent_id) = r.parent_id.clone() else {
+            out.push(Some(r));
+            continue;
+        };
+        let slot = out.len();
+        let (_, windows) = files.entry(parent_id).or_insert_with(|| (slot, Vec::new()));
+        if windows.is_empty() {
+            out.push(None);
+        }
+        windows.push(r);
+    }
+    for (parent_id, (slot, windows)) in files {
+        let path = windows[0].path.clone();
+        out[slot] = join_windows(parent_id, windows);
+        if out[slot].is_none() {
+            eprintln!(
+                "{} Windows of {} are incomplete, skipping the file",
+                "[WARNING]".truecolor(214, 143, 0),
+                path
+            );
+        }
+    }
+    out.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{ChunkOptions, chunk_ranges, chunk_records, join_chunks};
+    use crate::extractor::{Record, count_tokens};
+    use crate::tokenization::Tokenizers;
+
+    // One token per word, like a whitespace tokenizer
+    fn word_starts(text: &str) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut prev = ' ';
+        for (i, c) in text.char_indices() {
+            if !c.is_whitespace() && prev.is_whitespace() {
+                starts.push(i);
+            }
+            prev = c;
+        }
+        starts
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        let text = "def a():\n    return 1\n\ndef b():\n    return 2\n\ndef c():\n    return 3\n";
+        let starts = word_starts(text);
+        let opts = ChunkOptions {
+            max_tokens: 7,
+            overlap: 0,
+        };
+        let chunks: Vec<&str> = chunk_ranges(text, &starts, &opts)
+            .iter()
+            .map(|&(s, e)| &text[s..e])
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                "def a():\n    return 1\n\n",
+                "def b():\n    return 2\n\n",
+                "def c():\n    return 3\n"
+            ]
+        );
+
+        // Windows repeat the lines of the overlap
+        let opts = ChunkOptions {
+            max_tokens: 7,
+            overlap: 2,
+        };
+        let ranges = chunk_ranges(text, &starts, &opts);
+        assert_eq!(
+            &text[ranges[1].0..ranges[1].1],
+            "    return 1\n\ndef b():\n    return 2\n\n"
+        );
+        assert_eq!(ranges.last().unwrap().1, text.len());
+
+        let opts = ChunkOptions {
+            max_tokens: 100,
+            overlap: 0,
+        };
+        assert_eq!(chunk_ranges(text, &starts, &opts), [(0, text.len())]);
+    }
+
+    #[test]
+    fn test_window_budget() {
+        // Line starts fall inside the "\n    " tokens of the whole file, so
+        // windows cut on its tokens come out longer on their own
+        let tokenizers = Tokenizers::for_test_byte_level();
+        let text = "def a():\n    return 1\n\ndef b():\n    x = [\n        1,\n        2,\n    ]\n    return x\n\nclass C:\n    def c(self):\n        return 3\n";
+        for max_tokens in 4..16 {
+            for overlap in 0..4 {
+                let opts = ChunkOptions {
+                    max_tokens,
+                    overlap,
+                };
+                let mut excluded = Vec::new();
+                let records = vec![Record::for_test("user/repo", "a.py", "Python", text)];
+                let records = count_tokens(records, &tokenizers, &mut excluded);
+                let chunks =
+                    chunk_records(records, &opts, str::to_owned, &tokenizers, &mut excluded);
+                assert!(excluded.is_empty());
+                for c in &chunks {
+                    assert!(
+                        c.tokens["test"] <= max_tokens,
+                        "{max_tokens}/{overlap}: {:?}",
+                        c.text
+                    );
+                }
+                assert_eq!(join_chunks(chunks)[0].text, text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunk_records() {
+        let vocab = ["def", "a", "b", "c", "():", "return", "1", "2", "3"];
+        let tokenizers = Tokenizers::for_test(&vocab);
+        let text = "def a():\n    return 1\n\ndef b():\n    return 2\n\ndef c():\n    return 3\n";
+        let mut excluded = Vec::new();
+        let records = vec![
+            Record::for_test("user/repo", "a.py", "Python", text),
+            Record::for_test("user/repo", "b.py", "Python", "def a():"),
+        ];
+        let records = count_tokens(records, &tokenizers, &mut excluded);
+        let opts = ChunkOptions {
+            max_tokens: 7,
+            overlap: 2,
+        };
+        let chunks = chunk_records(
+            records,
+            &opts,
+            |path| format!("id:{path}"),
+            &tokenizers,
+            &mut excluded,
+        );
+        assert!(excluded.is_empty());
+
+        let windows: Vec<_> = chunks
+            .iter()
+            .map(|r| {
+                (
+                    r.id.as_str(),
+                    r.parent_id.as_deref(),
+                    r.chunk_index,
+                    r.chunk_count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            windows,
+            [
+                ("id:a.py#0", Some("a.py"), Some(0), Some(3)),
+                ("id:a.py#1", Some("a.py"), Some(1), Some(3)),
+                ("id:a.py#2", Some("a.py"), Some(2), Some(3)),
+                ("b.py", None, None, None),
+            ]
+        );
+        assert!(chunks[1].text.starts_with("    return 1\n"));
+        assert_eq!(chunks[1].tokens["test"], 7);
+        assert_eq!(
+            chunks[1].content_hash,
+            format!("{:x}", md5::compute(&chunks[1].text))
+        );
+
+        // The windows put back together are the file again
+        let mut joined = join_chunks(chunks.clone());
+        assert_eq!(joined.len(), 2);
+        assert_eq!(
+            (joined[0].id.as_str(), joined[0].text.as_str()),
+            ("a.py", text)
+        );
+        assert_eq!(joined[0].chunk_count, None);
+        // The whole file count, without the repeated "return 1" and "return 2"
+        assert_eq!(joined[0].tokens["test"], 15);
+        // Not without all of its windows
+        let mut partial = chunks;
+        partial.remove(1);
+        joined = join_chunks(partial);
+        let paths: Vec<&str> = joined.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["b.py"]);
+    }
+}
@@ -2,6 +2,13 @@ use std::path::PathBuf;
 
 use structopt::StructOpt;
 
+use crate::concat::FileOrder;
+use crate::fim::SplitLevel;
+use crate::normalize::Transform;
+use crate::sink::Format;
+use crate::syntax::SyntaxMode;
+use crate::tokenization::TokenizerSpec;
+
 #[derive(Debug, StructOpt)]
 pub enum Command {
     Download {
@@ -35,6 +42,91 @@ pub enum Command {
 
         #[structopt(short, long)]
         languages: Option<Vec<String>>,
+
+        #[structopt(long, parse(from_os_str))]
+        vendor_path: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str))]
+        documentation_path: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str))]
+        quality_config: Option<PathBuf>,
+
+        #[structopt(long)]
+        no_quality_filter: bool,
+
+        /// strip-bom, crlf, expand-tabs[=N], trailing-whitespace, trim. Of several
+        /// expand-tabs widths the last one is used
+        #[structopt(long)]
+        normalize: Option<Vec<Transform>>,
+
+        /// [name=]tokenizer.json path or hub id, e.g. gpt2=openai-community/gpt2
+        #[structopt(long)]
+        tokenizer: Option<Vec<TokenizerSpec>>,
+
+        /// Only use local or cached tokenizers
+        #[structopt(long)]
+        offline: bool,
+
+        /// Skip tokenization, records are written without token counts
+        #[structopt(long, conflicts_with = "tokenizer")]
+        no_tokenize: bool,
+
+        /// Extract .ipynb notebooks as scripts of their code cells
+        #[structopt(long)]
+        notebooks: bool,
+
+        /// Keep notebook markdown cells as comments, implies --notebooks
+        #[structopt(long)]
+        notebook_markdown: bool,
+
+        /// Split files longer than this many tokens of the first tokenizer into windows
+        #[structopt(long, conflicts_with = "no-tokenize")]
+        chunk_tokens: Option<usize>,
+
+        /// Tokens repeated between consecutive windows
+        #[structopt(long)]
+        chunk_overlap: Option<usize>,
+
+        /// Parse files with a tree-sitter grammar and reject or tag those with parse errors
+        #[structopt(long)]
+        syntax_check: Option<SyntaxMode>,
+
+        /// Parse errors tolerated per file by --syntax-check
+        #[structopt(long)]
+        max_syntax_errors: Option<usize>,
+
+        /// Link test files to the source files they exercise
+        #[structopt(long)]
+        link_tests: bool,
+
+        /// Keep documentation and example files, tagged by file_class, instead of dropping them
+        #[structopt(long)]
+        keep_docs: bool,
+
+        /// Keep only files under these SPDX licenses, comma-separated ids or "permissive"
+        #[structopt(long, use_delimiter = true)]
+        licenses: Option<Vec<String>>,
+
+        /// Output format: jsonl, jsonl.gz, jsonl.zst or parquet
+        #[structopt(long)]
+        format: Option<Format>,
+
+        /// Rows per Parquet row group
+        #[structopt(long)]
+        row_group_size: Option<usize>,
+
+        /// Shard output into data/train-NNNNN-of-NNNNN files of about this many text bytes
+        #[structopt(long)]
+        shard_bytes: Option<u64>,
+
+        /// Shard output into files of at most this many records
+        #[structopt(long)]
+        shard_records: Option<usize>,
+
+        /// Partition output by language, language=<name>/part-NNN files
+        #[structopt(long)]
+        partition_by_language: bool,
     },
     Dedupe {
         #[structopt(parse(from_os_str))]
@@ -48,6 +140,186 @@ pub enum Command {
 
         #[structopt(parse(from_os_str))]
         dest_dir: Option<PathBuf>,
+
+        /// Output format: jsonl, jsonl.gz, jsonl.zst or parquet
+        #[structopt(long)]
+        format: Option<Format>,
+
+        /// Rows per Parquet row group
+        #[structopt(long)]
+        row_group_size: Option<usize>,
+
+        /// Shard output into data/train-NNNNN-of-NNNNN files of about this many text bytes
+        #[structopt(long)]
+        shard_bytes: Option<u64>,
+
+        /// Shard output into files of at most this many records
+        #[structopt(long)]
+        shard_records: Option<usize>,
+
+        /// Partition output by language, language=<name>/part-NNN files
+        #[structopt(long)]
+        partition_by_language: bool,
+    },
+    Export {
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// Extracted or deduplicated records to export
+        #[structopt(parse(from_os_str))]
+        input_dir: Option<PathBuf>,
+
+        /// Hugging Face datasets directory to write, data/ shards and a README.md card
+        #[structopt(long, parse(from_os_str))]
+        hf: PathBuf,
+
+        /// Extraction output holding the exclusion logs
+        #[structopt(long, parse(from_os_str))]
+        jsonl_dir: Option<PathBuf>,
+
+        /// Dataset name shown on the card, defaults to the source file name
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Share of repositories held out as a validation split
+        #[structopt(long)]
+        validation_fraction: Option<f64>,
+
+        /// Text bytes per Parquet shard
+        #[structopt(long)]
+        shard_bytes: Option<u64>,
+
+        /// Rows per Parquet row group
+        #[structopt(long)]
+        row_group_size: Option<usize>,
+
+        /// The input was deduplicated, the card says exact duplicates were removed
+        #[structopt(long)]
+        deduplicated: bool,
+    },
+    Concat {
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// Extracted or deduplicated records to concatenate
+        #[structopt(parse(from_os_str))]
+        input_dir: Option<PathBuf>,
+
+        #[structopt(parse(from_os_str))]
+        repo_dir: Option<PathBuf>,
+
+        /// Token budget of a document
+        #[structopt(long)]
+        max_tokens: Option<usize>,
+
+        /// Tokenizer whose counts enforce the budget, required when records carry
+        /// the counts of several
+        #[structopt(long)]
+        tokenizer: Option<String>,
+
+        /// Token opening a document, followed by the repository name
+        #[structopt(long)]
+        repo_token: Option<String>,
+
+        /// Token opening every file, followed by its path
+        #[structopt(long)]
+        file_sep_token: Option<String>,
+
+        /// File order within a repository: directory or dependency
+        #[structopt(long)]
+        order: Option<FileOrder>,
+
+        /// Output format: jsonl, jsonl.gz, jsonl.zst or parquet
+        #[structopt(long)]
+        format: Option<Format>,
+
+        /// Rows per Parquet row group
+        #[structopt(long)]
+        row_group_size: Option<usize>,
+    },
+    Fim {
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// Extracted or deduplicated records to transform
+        #[structopt(parse(from_os_str))]
+        input_dir: Option<PathBuf>,
+
+        #[structopt(parse(from_os_str))]
+        fim_dir: Option<PathBuf>,
+
+        /// Share of records rewritten into FIM form
+        #[structopt(long)]
+        fim_rate: Option<f64>,
+
+        /// Share of the rewritten records in SPM instead of PSM order
+        #[structopt(long)]
+        spm_rate: Option<f64>,
+
+        #[structopt(long)]
+        seed: Option<u64>,
+
+        /// Split points at char or token boundaries. At char level, rewritten
+        /// records lose their token counts unless a --tokenizer is given
+        #[structopt(long)]
+        split_level: Option<SplitLevel>,
+
+        /// [name=]tokenizer.json path or hub id recounting rewritten records, the
+        /// first one gives token split points (gpt2 by default)
+        #[structopt(long)]
+        tokenizer: Option<Vec<TokenizerSpec>>,
+
+        /// Only use a local or cached tokenizer
+        #[structopt(long)]
+        offline: bool,
+
+        #[structopt(long)]
+        prefix_token: Option<String>,
+
+        #[structopt(long)]
+        middle_token: Option<String>,
+
+        #[structopt(long)]
+        suffix_token: Option<String>,
+
+        /// Rows per Parquet row group
+        #[structopt(long)]
+        row_group_size: Option<usize>,
+    },
+    Definitions {
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// Extracted or deduplicated records to parse
+        #[structopt(parse(from_os_str))]
+        input_dir: Option<PathBuf>,
+
+        #[structopt(parse(from_os_str))]
+        definitions_dir: Option<PathBuf>,
+
+        /// Output format: jsonl, jsonl.gz, jsonl.zst or parquet
+        #[structopt(long)]
+        format: Option<Format>,
+
+        /// Rows per Parquet row group
+        #[structopt(long)]
+        row_group_size: Option<usize>,
+    },
+    Attribution {
+        #[structopt(parse(from_os_str))]
+        source: PathBuf,
+
+        /// Extracted or deduplicated records to attribute
+        #[structopt(parse(from_os_str))]
+        input_dir: Option<PathBuf>,
+
+        /// Directory to write ATTRIBUTION.md and LICENSES.txt to
+        #[structopt(parse(from_os_str))]
+        attribution_dir: Option<PathBuf>,
+
+        /// Downloaded archives, to read the copyright holders of their license files
+        #[structopt(long, parse(from_os_str))]
+        zip_dir: Option<PathBuf>,
     },
 }
 
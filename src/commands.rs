@@ -1,18 +1,37 @@
 use std::collections::{HashMap, HashSet};
-use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::{fs, process};
 
 use colored::Colorize;
-use tokenizers::Tokenizer;
 use yaml_rust::{Yaml, YamlLoader};
 
-use crate::config::{DedupeConfig, DownloadConfig, ExtractionConfig};
-use crate::deduplication::exact_deduplication;
+use crate::attribution::write_attribution;
+use crate::chunk::ChunkOptions;
+use crate::concat::{ConcatOptions, concat_repos};
+use crate::config::{
+    AttributionConfig, ConcatConfig, DedupeConfig, DefinitionsConfig, DownloadConfig, ExportConfig,
+    ExtractionConfig, FimConfig,
+};
+use crate::deduplication::{DedupOutput, exact_deduplication};
+use crate::definitions::extract_definitions;
 use crate::downloader::download_repos;
+use crate::exclusion::ExclusionRules;
+use crate::export::{ExportOptions, export_hf};
+use crate::fim::{FimOptions, SplitLevel, fim_transform};
+use crate::license::allowlist;
+use crate::normalize::Normalizer;
+use crate::notebook::{NOTEBOOK_LANGUAGE, NotebookOptions};
+use crate::quality::QualityFilter;
+use crate::shard::{ShardOptions, read_index};
+use crate::sink::{Format, SinkOptions};
 use crate::source::parse_source;
 use crate::source::parse_source_as_hashset;
-use crate::{error::ExtractionError, extractor::extract_text};
+use crate::syntax::SyntaxCheck;
+use crate::tokenization::{TokenizerSpec, Tokenizers};
+use crate::{
+    error::ExtractionError,
+    extractor::{ExtractOptions, Languages, extract_text},
+};
 
 pub fn listdir(dir: &Path, match_extension: String) -> Result<Vec<PathBuf>, ExtractionError> {
     let mut files: Vec<PathBuf> = Vec::new();
@@ -24,9 +43,14 @@ pub fn listdir(dir: &Path, match_extension: String) -> Result<Vec<PathBuf>, Extr
             });
         }
     };
+    // Suffix match so multi-part extensions like "jsonl.zst" work
+    let suffix = format!(".{match_extension}");
     for file in dir_files {
         let f = file?.path();
-        if f.extension() == Some(OsStr::new(&match_extension)) {
+        if f.file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with(&suffix))
+        {
             files.push(f.clone());
         }
     }
@@ -35,6 +59,8 @@ pub fn listdir(dir: &Path, match_extension: String) -> Result<Vec<PathBuf>, Extr
             message: String::from("Empty directory"),
         });
     }
+    // read_dir order depends on the filesystem
+    files.sort();
     Ok(files)
 }
 
@@ -45,18 +71,38 @@ fn read_linguist(path: &Path) -> Result<Yaml, ExtractionError> {
     Ok(doc.clone())
 }
 
-fn get_ext_ft(doc: &Yaml) -> Result<HashMap<String, String>, ExtractionError> {
+// Extensions claimed by several languages that Linguist disambiguates with
+// heuristics, we settle them on the most common one instead.
+const PREFERRED_LANGUAGES: &[(&str, &str)] = &[
+    (".h", "C"),
+    (".m", "Objective-C"),
+    (".pl", "Perl"),
+    (".rs", "Rust"),
+    (".ts", "TypeScript"),
+    (".v", "Verilog"),
+];
+
+fn insert_ext_lang(exts: &mut HashMap<String, String>, ext: &str, lang: &str) {
+    let preferred = PREFERRED_LANGUAGES
+        .iter()
+        .any(|(e, l)| *e == ext && *l == lang);
+    if preferred || !exts.contains_key(ext) {
+        exts.insert(ext.to_owned(), lang.to_owned());
+    }
+}
+
+fn get_ext_lang(doc: &Yaml) -> Result<HashMap<String, String>, ExtractionError> {
     let mut ret: HashMap<String, String> = HashMap::new();
-    for (_, v) in doc.as_hash().unwrap() {
-        let Some(file_type) = v["type"].as_str() else {
+    for (k, v) in doc.as_hash().unwrap() {
+        let (Some(lang), Some(file_type)) = (k.as_str(), v["type"].as_str()) else {
             continue;
         };
-        if file_type == "programming" {
-            if let Some(ext_list) = v["extensions"].as_vec() {
-                for ext in ext_list {
-                    ret.insert(ext.clone().into_string().unwrap(), file_type.to_owned());
-                }
-            };
+        if file_type == "programming"
+            && let Some(ext_list) = v["extensions"].as_vec()
+        {
+            for ext in ext_list.iter().filter_map(|e| e.as_str()) {
+                insert_ext_lang(&mut ret, ext, lang);
+            }
         }
     }
     if ret.is_empty() {
@@ -76,13 +122,11 @@ fn get_ext_pl(
     for lang in langs {
         let lang_key = Yaml::from_str(lang);
         if let Some(v) = doc.get(&lang_key) {
-            if let Some(file_type) = v["type"].as_str() {
-                if let Some(ext_list) = v["extensions"].as_vec() {
-                    for ext in ext_list {
-                        union_exts.insert(ext.clone().into_string().unwrap(), file_type.to_owned());
-                    }
-                };
-            };
+            if let Some(ext_list) = v["extensions"].as_vec() {
+                for ext in ext_list.iter().filter_map(|e| e.as_str()) {
+                    insert_ext_lang(&mut union_exts, ext, lang);
+                }
+            }
         } else {
             eprintln!(
                 "{} Language {} is not defined in linguist",
@@ -92,11 +136,47 @@ fn get_ext_pl(
         }
     }
     if union_exts.is_empty() {
-        return get_ext_ft(yaml_doc);
+        return get_ext_lang(yaml_doc);
     }
     Ok(union_exts)
 }
 
+// Lowercased names and aliases of the selected languages, as used by
+// `linguist-language=<name>` in .gitattributes ("c++", "cpp", "common-lisp").
+fn get_lang_aliases(doc: &Yaml, ext_langs: &HashMap<String, String>) -> HashMap<String, String> {
+    let selected: HashSet<&String> = ext_langs.values().collect();
+    let mut aliases: HashMap<String, String> = HashMap::new();
+    for lang in selected {
+        let v = &doc[lang.as_str()];
+        let lower = lang.to_lowercase();
+        aliases.insert(lower.replace(' ', "-"), lang.to_owned());
+        aliases.insert(lower, lang.to_owned());
+        if let Some(alias_list) = v["aliases"].as_vec() {
+            for alias in alias_list.iter().filter_map(|a| a.as_str()) {
+                aliases.insert(alias.to_lowercase(), lang.to_owned());
+            }
+        }
+    }
+    aliases
+}
+
+// Linguist file names of the selected languages, build files without a
+// telling extension ("Makefile", "CMakeLists.txt", "Dockerfile")
+fn get_lang_filenames(doc: &Yaml, ext_langs: &HashMap<String, String>) -> HashMap<String, String> {
+    let selected: HashSet<&String> = ext_langs.values().collect();
+    let mut filenames: HashMap<String, String> = HashMap::new();
+    for lang in selected {
+        if let Some(names) = doc[lang.as_str()]["filenames"].as_vec() {
+            for name in names.iter().filter_map(|n| n.as_str()) {
+                filenames
+                    .entry(name.to_owned())
+                    .or_insert_with(|| lang.to_owned());
+            }
+        }
+    }
+    filenames
+}
+
 pub fn filter_listdir_by_source(
     paths: &Vec<PathBuf>,
     source_hs: &HashSet<String>,
@@ -106,12 +186,14 @@ pub fn filter_listdir_by_source(
     // We compare the provided source file with the local zips in disk
 
     for path in paths {
-        if let Some(stem_os) = path.file_stem() {
-            if let Some(stem) = stem_os.to_str() {
-                let prefix = stem.split("_").next().unwrap_or(stem);
-                if source_hs.contains(prefix) {
-                    filtered.push(path.clone());
-                }
+        if let Some(name) = path.file_name().and_then(|n| n.to_str())
+            && let Some(stem) = Format::split_name(name)
+                .map(|(_, stem)| stem)
+                .or_else(|| Path::new(name).file_stem()?.to_str())
+        {
+            let prefix = stem.split("_").next().unwrap_or(stem);
+            if source_hs.contains(prefix) {
+                filtered.push(path.clone());
             }
         }
     }
@@ -123,6 +205,29 @@ pub fn filter_listdir_by_source(
     Ok(filtered)
 }
 
+// Outputs of extract or dedupe in `dir`, whatever format they were written
+// in: the shards of its index or one file per repo of the source file.
+// Shards mix repositories, the flag tells records must be filtered.
+fn list_outputs(
+    dir: &Path,
+    repos_hs: &HashSet<String>,
+) -> Result<(Vec<PathBuf>, bool), ExtractionError> {
+    if let Some(paths) = read_index(dir) {
+        return Ok((paths, true));
+    }
+    let paths: Vec<PathBuf> = Format::ALL
+        .iter()
+        .filter_map(|f| listdir(dir, f.extension().to_string()).ok())
+        .flatten()
+        .collect();
+    if paths.is_empty() {
+        return Err(ExtractionError::Validation {
+            message: format!("No extracted files in {}", dir.display()),
+        });
+    }
+    Ok((filter_listdir_by_source(&paths, repos_hs)?, false))
+}
+
 pub async fn download(ctx: &DownloadConfig) {
     // read source file
     let uris: Vec<(String, String)> = match parse_source(&ctx.source) {
@@ -165,42 +270,285 @@ pub async fn extract(ctx: &ExtractionConfig) {
         }
     };
 
-    // Load tokenizer and linguist yaml
-    let gpt2tokenizer = Tokenizer::from_pretrained("openai-community/gpt2", None)
-        .expect("Failed to load the tokenizer");
+    // Unknown --licenses ids fail before any work
+    let licenses = match ctx.licenses.as_deref().map(allowlist).transpose() {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    // Load tokenizers and linguist yaml
+    let tokenizers = match Tokenizers::load(&ctx.tokenizers, ctx.offline) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
     let linguist_file =
         read_linguist(&ctx.linguist_path).expect("Unable to read linguist languages yaml");
 
-    let ext_file_types = match &ctx.languages {
+    let ext_languages = match &ctx.languages {
         Some(langs) => get_ext_pl(&linguist_file, langs)
             .expect("Unable to get programming file types extensions from yaml"),
-        None => get_ext_ft(&linguist_file)
+        None => get_ext_lang(&linguist_file)
             .expect("Unable to get programming file types extensions from yaml"),
     };
+    let languages = Languages {
+        aliases: get_lang_aliases(&linguist_file, &ext_languages),
+        filenames: get_lang_filenames(&linguist_file, &ext_languages),
+        extensions: ext_languages,
+    };
+
+    let exclusion_rules = ExclusionRules::load(&ctx.vendor_path, &ctx.documentation_path)
+        .expect("Unable to load linguist vendor and documentation rules");
+
+    let quality = ctx.quality_config.as_ref().map(|q| {
+        if !q.exists() {
+            eprintln!(
+                "{} Quality config {} not found, using the built-in thresholds",
+                "[WARNING]".truecolor(214, 143, 0),
+                q.display()
+            );
+            return QualityFilter::builtin();
+        }
+        QualityFilter::load(q).expect("Unable to read quality filter config")
+    });
+
+    // Repo names as written in the source file, keyed like the zip names
+    let repo_names: HashMap<String, String> = parse_source(&ctx.source)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(user, repo)| (format!("{user}-{repo}"), format!("{user}/{repo}")))
+        .collect();
+
+    // Selecting the notebook language extracts notebooks as scripts too
+    let notebook_selected = ctx
+        .languages
+        .as_ref()
+        .is_some_and(|langs| langs.iter().any(|l| l == NOTEBOOK_LANGUAGE));
+    let opts = ExtractOptions {
+        repo_names,
+        languages,
+        exclusion_rules,
+        quality,
+        normalizer: Normalizer::new(ctx.normalize.clone()),
+        max_file_size: ctx.max_file_size,
+        notebooks: (ctx.notebooks || notebook_selected).then_some(NotebookOptions {
+            keep_markdown: ctx.notebook_markdown,
+        }),
+        chunking: ctx.chunk_tokens.map(|max_tokens| ChunkOptions {
+            max_tokens,
+            overlap: ctx.chunk_overlap,
+        }),
+        syntax: ctx.syntax_check.map(|mode| SyntaxCheck {
+            mode,
+            max_errors: ctx.max_syntax_errors,
+        }),
+        link_tests: ctx.link_tests,
+        keep_docs: ctx.keep_docs,
+        licenses,
+        output: SinkOptions {
+            format: ctx.format,
+            row_group_size: ctx.row_group_size,
+        },
+        shards: ShardOptions::new(
+            ctx.shard_bytes,
+            ctx.shard_records,
+            ctx.partition_by_language,
+        ),
+    };
 
     // Extract
-    let _ = extract_text(&ctx.jsonl_dir, paths, ext_file_types, gpt2tokenizer);
+    let _ = extract_text(&ctx.jsonl_dir, paths, opts, tokenizers);
 }
 
 pub async fn dedupe(ctx: &DedupeConfig) {
-    // List zip dir
-    let paths = match listdir(&ctx.jsonl_dir, "jsonl".to_string()) {
-        Ok(paths) => paths,
+    let repos_hs = parse_source_as_hashset(&ctx.source);
+    let output = DedupOutput {
+        dir: &ctx.exact_dedup_dir,
+        sink: SinkOptions {
+            format: ctx.format,
+            row_group_size: ctx.row_group_size,
+        },
+        shards: ShardOptions::new(
+            ctx.shard_bytes,
+            ctx.shard_records,
+            ctx.partition_by_language,
+        ),
+    };
+
+    let (paths, sharded) = match list_outputs(&ctx.jsonl_dir, &repos_hs) {
+        Ok(outputs) => outputs,
         Err(e) => {
             eprintln!("{} {}", "[WARNING]".truecolor(214, 143, 0), e);
             return;
         }
     };
+    exact_deduplication(
+        &paths,
+        sharded.then_some(&repos_hs),
+        &ctx.jsonl_dir,
+        &output,
+    );
+}
 
-    // Filter zip files for those ennumerated in source file
+pub async fn export(ctx: &ExportConfig) {
     let repos_hs = parse_source_as_hashset(&ctx.source);
-    let paths = match filter_listdir_by_source(&paths, &repos_hs) {
-        Ok(paths) => paths,
+    let (paths, sharded) = match list_outputs(&ctx.input_dir, &repos_hs) {
+        Ok(outputs) => outputs,
         Err(e) => {
             eprintln!("{} {}", "[WARNING]".truecolor(214, 143, 0), e);
             return;
         }
     };
+    let opts = ExportOptions {
+        name: ctx.name.clone(),
+        validation_fraction: ctx.validation_fraction,
+        shard_bytes: ctx.shard_bytes,
+        row_group_size: ctx.row_group_size,
+        jsonl_dir: ctx.jsonl_dir.clone(),
+        deduplicated: ctx.deduplicated,
+    };
+    match export_hf(&paths, sharded.then_some(&repos_hs), &ctx.hf_dir, &opts) {
+        Ok(n) => println!("Exported {} records to {}", n, ctx.hf_dir.display()),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+pub async fn concat(ctx: &ConcatConfig) {
+    let repos_hs = parse_source_as_hashset(&ctx.source);
+    let (paths, sharded) = match list_outputs(&ctx.input_dir, &repos_hs) {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            eprintln!("{} {}", "[WARNING]".truecolor(214, 143, 0), e);
+            return;
+        }
+    };
+    let opts = ConcatOptions {
+        max_tokens: ctx.max_tokens,
+        tokenizer: ctx.tokenizer.clone(),
+        repo_token: ctx.repo_token.clone(),
+        file_sep_token: ctx.file_sep_token.clone(),
+        order: ctx.order,
+        output: SinkOptions {
+            format: ctx.format,
+            row_group_size: ctx.row_group_size,
+        },
+    };
+    match concat_repos(&paths, sharded.then_some(&repos_hs), &ctx.repo_dir, &opts) {
+        Ok(n) => println!(
+            "Wrote {} repository documents to {}",
+            n,
+            ctx.repo_dir.display()
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
 
-    exact_deduplication(&paths, &ctx.exact_dedup_dir);
+pub async fn fim(ctx: &FimConfig) {
+    let repos_hs = parse_source_as_hashset(&ctx.source);
+    let (paths, sharded) = match list_outputs(&ctx.input_dir, &repos_hs) {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            eprintln!("{} {}", "[WARNING]".truecolor(214, 143, 0), e);
+            return;
+        }
+    };
+    // Token split points need a tokenizer, at char level counts are only
+    // recounted with the given ones
+    let specs = match ctx.split_level {
+        SplitLevel::Token if ctx.tokenizers.is_empty() => vec![TokenizerSpec::default()],
+        _ => ctx.tokenizers.clone(),
+    };
+    let tokenizers = match Tokenizers::load(&specs, ctx.offline) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let opts = FimOptions {
+        rate: ctx.fim_rate,
+        spm_rate: ctx.spm_rate,
+        seed: ctx.seed,
+        level: ctx.split_level,
+        prefix_token: ctx.prefix_token.clone(),
+        middle_token: ctx.middle_token.clone(),
+        suffix_token: ctx.suffix_token.clone(),
+        row_group_size: ctx.row_group_size,
+    };
+    let repos = sharded.then_some(&repos_hs);
+    match fim_transform(
+        &paths,
+        repos,
+        &ctx.input_dir,
+        &ctx.fim_dir,
+        &opts,
+        &tokenizers,
+    ) {
+        Ok(n) => println!("Wrote {} records to {}", n, ctx.fim_dir.display()),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+pub async fn definitions(ctx: &DefinitionsConfig) {
+    let repos_hs = parse_source_as_hashset(&ctx.source);
+    let (paths, sharded) = match list_outputs(&ctx.input_dir, &repos_hs) {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            eprintln!("{} {}", "[WARNING]".truecolor(214, 143, 0), e);
+            return;
+        }
+    };
+    let output = SinkOptions {
+        format: ctx.format,
+        row_group_size: ctx.row_group_size,
+    };
+    let repos = sharded.then_some(&repos_hs);
+    match extract_definitions(&paths, repos, &ctx.definitions_dir, &output) {
+        Ok(n) => println!(
+            "Wrote {} definitions to {}",
+            n,
+            ctx.definitions_dir.display()
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+pub async fn attribution(ctx: &AttributionConfig) {
+    let repos_hs = parse_source_as_hashset(&ctx.source);
+    let (paths, sharded) = match list_outputs(&ctx.input_dir, &repos_hs) {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            eprintln!("{} {}", "[WARNING]".truecolor(214, 143, 0), e);
+            return;
+        }
+    };
+    let repos = sharded.then_some(&repos_hs);
+    match write_attribution(&paths, repos, ctx.zip_dir.as_deref(), &ctx.attribution_dir) {
+        Ok(n) => println!(
+            "Wrote the attribution of {} repositories to {}",
+            n,
+            ctx.attribution_dir.display()
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
 }
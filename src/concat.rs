@@ -0,0 +1,530 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use polars::prelude::{Column, DataFrame, DataType, NamedFrom, PolarsResult, Series};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::chunk::join_chunks;
+use crate::error::ConcatError;
+use crate::extractor::Record;
+use crate::file_class::BUILD_FILES;
+use crate::imports::build_graph;
+use crate::sink::{Format, RecordSink, SinkOptions, Tabular, read_records};
+
+// Files read first in every directory, most important first
+const README_NAMES: &[&str] = &["readme", "readme.md", "readme.rst", "readme.txt"];
+
+// Bytes per token when a record has no count for the budget tokenizer
+const BYTES_PER_TOKEN: usize = 4;
+
+// Import graph sidecars, "<repo_dir>/graphs/<user>-<repo>.json"
+const GRAPHS_DIR: &str = "graphs";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FileOrder {
+    // READMEs and build files, then directory by directory
+    #[default]
+    Directory,
+    // Imported files before the files that import them
+    Dependency,
+}
+
+impl FromStr for FileOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "directory" => Ok(FileOrder::Directory),
+            "dependency" => Ok(FileOrder::Dependency),
+            _ => Err(format!(
+                "unknown order {s:?}, expected one of: directory, dependency"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcatOptions {
+    // Token budget of a document, only files over it on their own are split
+    // across documents
+    pub max_tokens: usize,
+    // Tokenizer whose counts enforce the budget, None when records carry
+    // the counts of a single one
+    pub tokenizer: Option<String>,
+    pub repo_token: String,
+    pub file_sep_token: String,
+    pub order: FileOrder,
+    pub output: SinkOptions,
+}
+
+// Concatenation of the files of one repository, in reading order:
+// "<repo_name>user/repo\n<file_sep>src/lib.rs\n...<file_sep>src/main.rs\n..."
+#[derive(Serialize, Debug)]
+pub struct RepoDocument {
+    pub text: String,
+    // "<user>/<repo>:<doc_index>"
+    pub id: String,
+    pub repo_name: String,
+    pub branch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    pub doc_index: usize,
+    pub doc_count: usize,
+    // Files in the document, in order
+    pub paths: Vec<String>,
+    pub tokens: usize,
+}
+
+impl Tabular for RepoDocument {
+    fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame> {
+        let str_column = |name: &str, f: fn(&RepoDocument) -> &str| {
+            Column::new(name.into(), rows.iter().map(f).collect::<Vec<_>>())
+        };
+        let u64_column = |name: &str, f: fn(&RepoDocument) -> usize| {
+            Column::new(
+                name.into(),
+                rows.iter().map(|d| f(d) as u64).collect::<Vec<_>>(),
+            )
+        };
+        let paths: Vec<Series> = rows
+            .iter()
+            .map(|d| Series::new("".into(), &d.paths))
+            .collect();
+        DataFrame::new(vec![
+            str_column("text", |d| &d.text),
+            str_column("id", |d| &d.id),
+            str_column("repo_name", |d| &d.repo_name),
+            str_column("branch", |d| &d.branch),
+            Column::new(
+                "commit_sha".into(),
+                rows.iter()
+                    .map(|d| d.commit_sha.as_deref())
+                    .collect::<Vec<_>>(),
+            ),
+            u64_column("doc_index", |d| d.doc_index),
+            u64_column("doc_count", |d| d.doc_count),
+            Column::new("paths".into(), paths).cast(&DataType::List(Box::new(DataType::String)))?,
+            u64_column("tokens", |d| d.tokens),
+        ])
+    }
+}
+
+fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(BYTES_PER_TOKEN)
+}
+
+// 0 for READMEs, 1 for build files, 2 for everything else, with build
+// files in their order in `BUILD_FILES`
+fn file_rank(file_name: &str) -> (u8, usize) {
+    if README_NAMES.contains(&file_name.to_lowercase().as_str()) {
+        (0, 0)
+    } else if let Some(i) = BUILD_FILES.iter().position(|b| *b == file_name) {
+        (1, i)
+    } else {
+        (2, 0)
+    }
+}
+
+// Top-level READMEs and build files, then directory by directory with the
+// READMEs and build files of each directory ahead of its other files
+pub fn order_files(records: &mut [Record]) {
+    records.sort_by_cached_key(|r| {
+        let (dir, name) = r.path.rsplit_once('/').unwrap_or(("", &r.path));
+        let rank = file_rank(name);
+        let top_level = if dir.is_empty() { rank.0 } else { 2 };
+        (top_level, dir.to_owned(), rank, name.to_owned())
+    });
+}
+
+// Pieces of a file too long for a document, of at most `budget` tokens.
+// Pieces end at line starts, and only split lines longer than a document.
+// The file's tokens are spread over its text in proportion to the bytes.
+fn split_file(text: &str, tokens: usize, budget: usize) -> Vec<(&str, usize)> {
+    let piece_tokens = |len: usize| (tokens * len).div_ceil(text.len().max(1));
+    let max_bytes = (budget * text.len() / tokens.max(1)).max(1);
+    let mut pieces = Vec::new();
+    let (mut start, mut end) = (0, 0);
+    for line in text.split_inclusive('\n') {
+        if end > start && piece_tokens(end + line.len() - start) > budget {
+            pieces.push(&text[start..end]);
+            start = end;
+        }
+        end += line.len();
+        while piece_tokens(end - start) > budget {
+            let mut cut = start + max_bytes;
+            while !text.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            if cut == start {
+                // A single char over the budget
+                cut += text[start..].chars().next().map_or(1, char::len_utf8);
+            }
+            pieces.push(&text[start..cut]);
+            start = cut;
+        }
+    }
+    if end > start {
+        pieces.push(&text[start..end]);
+    }
+    pieces
+        .into_iter()
+        .map(|p| (p, piece_tokens(p.len())))
+        .collect()
+}
+
+// Packs the ordered files of a repository into documents of at most
+// `max_tokens`. A file that does not fit in an empty document is split into
+// pieces that fill documents of their own, each under the file separator.
+fn build_documents(records: &[Record], opts: &ConcatOptions) -> Vec<RepoDocument> {
+    let Some(first) = records.first() else {
+        return Vec::new();
+    };
+    let header = format!("{}{}\n", opts.repo_token, first.repo_name);
+    let header_tokens = estimate_tokens(&header);
+    let separator_tokens =
+        |r: &Record| estimate_tokens(&opts.file_sep_token) + estimate_tokens(&r.path) + 1;
+    let file_tokens = |r: &Record| {
+        let tokens = match &opts.tokenizer {
+            Some(name) => r.tokens.get(name),
+            None => r.tokens.values().next(),
+        };
+        tokens.copied().unwrap_or_else(|| estimate_tokens(&r.text))
+    };
+
+    let mut documents: Vec<RepoDocument> = Vec::new();
+    let mut current: Option<RepoDocument> = None;
+    for record in records {
+        let separator = separator_tokens(record);
+        let tokens = file_tokens(record);
+        let budget = opts.max_tokens.saturating_sub(header_tokens + separator);
+        let pieces = if tokens > budget {
+            split_file(&record.text, tokens, budget.max(1))
+        } else {
+            vec![(record.text.as_str(), tokens)]
+        };
+        for (text, tokens) in pieces {
+            let tokens = separator + tokens;
+            if let Some(doc) = &current
+                && !doc.paths.is_empty()
+                && doc.tokens + tokens > opts.max_tokens
+            {
+                documents.extend(current.take());
+            }
+            let doc = current.get_or_insert_with(|| RepoDocument {
+                text: header.clone(),
+                id: String::new(),
+                repo_name: record.repo_name.clone(),
+                branch: record.branch.clone(),
+                commit_sha: record.commit_sha.clone(),
+                doc_index: 0,
+                doc_count: 0,
+                paths: Vec::new(),
+                tokens: header_tokens,
+            });
+            doc.text.push_str(&opts.file_sep_token);
+            doc.text.push_str(&record.path);
+            doc.text.push('\n');
+            doc.text.push_str(text);
+            if !text.ends_with('\n') {
+                doc.text.push('\n');
+            }
+            doc.paths.push(record.path.clone());
+            doc.tokens += tokens;
+        }
+    }
+    documents.extend(current);
+
+    let doc_count = documents.len();
+    for (i, doc) in documents.iter_mut().enumerate() {
+        doc.id = format!("{}:{i}", doc.repo_name);
+        doc.doc_index = i;
+        doc.doc_count = doc_count;
+    }
+    documents
+}
+
+fn write_documents(
+    dest_dir: &Path,
+    name: &str,
+    records: Vec<Record>,
+    opts: &ConcatOptions,
+) -> Result<usize, ConcatError> {
+    // Counts are keyed by tokenizer name, which says nothing of the order
+    // they were given to extract in
+    if opts.tokenizer.is_none()
+        && let Some(r) = records.iter().find(|r| r.tokens.len() > 1)
+    {
+        let names: Vec<&str> = r.tokens.keys().map(String::as_str).collect();
+        return Err(ConcatError::Validation {
+            message: format!(
+                "Records of {name} carry counts of several tokenizers ({}), choose the one \
+                 enforcing the budget with --tokenizer",
+                names.join(", ")
+            ),
+        });
+    }
+    // Overlapping windows would repeat text, documents hold whole files
+    let mut records = join_chunks(records);
+    order_files(&mut records);
+    if opts.order == FileOrder::Dependency {
+        records = dependency_order(dest_dir, name, records)?;
+    }
+    let mut sink = RecordSink::create(dest_dir, name, &opts.output)?;
+    for doc in build_documents(&records, opts) {
+        sink.write(doc)?;
+    }
+    Ok(sink.finish()?)
+}
+
+// Reorders the files along the import graph and writes the graph sidecar.
+// The directory order breaks ties and cycles.
+fn dependency_order(
+    dest_dir: &Path,
+    name: &str,
+    records: Vec<Record>,
+) -> Result<Vec<Record>, ConcatError> {
+    let graph = build_graph(&records);
+    let order = graph.dependency_order();
+    let sidecar = graph.sidecar(&records, &order);
+
+    let graphs_dir = dest_dir.join(GRAPHS_DIR);
+    fs::create_dir_all(&graphs_dir)?;
+    let tmp_path = graphs_dir.join(format!("{name}.json.tmp"));
+    fs::write(&tmp_path, serde_json::to_string_pretty(&sidecar)?)?;
+    fs::rename(tmp_path, graphs_dir.join(format!("{name}.json")))?;
+
+    let mut records: Vec<Option<Record>> = records.into_iter().map(Some).collect();
+    Ok(order.iter().filter_map(|&i| records[i].take()).collect())
+}
+
+// One output file of repository documents per repository. `repos`
+// restricts sharded inputs, which mix repositories, like in dedupe.
+pub fn concat_repos(
+    paths: &[PathBuf],
+    repos: Option<&HashSet<String>>,
+    dest_dir: &Path,
+    opts: &ConcatOptions,
+) -> Result<usize, ConcatError> {
+    let Some(repos) = repos else {
+        // One input file per repository
+        let counts: Vec<usize> = paths
+            .par_iter()
+            .map(|path| {
+                let name = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(Format::split_name)
+                    .map(|(_, name)| name)
+                    .ok_or_else(|| ConcatError::Validation {
+                        message: format!("Unable to parse filename of path {}", path.display()),
+                    })?;
+                write_documents(dest_dir, name, read_records(path)?, opts)
+            })
+            .collect::<Result<_, _>>()?;
+        return Ok(counts.iter().sum());
+    };
+
+    // Shards mix repositories, group them back first
+    let mut by_repo: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+    for path in paths {
+        for record in read_records(path)? {
+            let name = record.repo_name.replace('/', "-");
+            if repos.contains(&name) {
+                by_repo.entry(name).or_default().push(record);
+            }
+        }
+    }
+    let counts: Vec<usize> = by_repo
+        .into_par_iter()
+        .map(|(name, records)| write_documents(dest_dir, &name, records, opts))
+        .collect::<Result<_, _>>()?;
+    Ok(counts.iter().sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use crate::chunk::{ChunkOptions, chunk_records, join_chunks};
+    use crate::concat::{
+        ConcatOptions, FileOrder, build_documents, order_files, split_file, write_documents,
+    };
+    use crate::extractor::{Record, count_tokens};
+    use crate::sink::SinkOptions;
+    use crate::tokenization::Tokenizers;
+
+    fn record(path: &str, text: &str) -> Record {
+        Record::for_test("a/x", path, "", text)
+    }
+
+    #[test]
+    fn test_order_files() {
+        let mut records: Vec<Record> = [
+            "src/main.rs",
+            "src/cli/mod.rs",
+            "Cargo.toml",
+            "build.rs",
+            "README.md",
+            "crates/core/Cargo.toml",
+            "crates/core/a.rs",
+        ]
+        .iter()
+        .map(|p| record(p, "x"))
+        .collect();
+        order_files(&mut records);
+        let paths: Vec<&str> = records.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "README.md",
+                "Cargo.toml",
+                "build.rs",
+                "crates/core/Cargo.toml",
+                "crates/core/a.rs",
+                "src/main.rs",
+                "src/cli/mod.rs"
+            ]
+        );
+
+        // Build files in their order in BUILD_FILES, not by name
+        let mut records: Vec<Record> = ["requirements.txt", "app.py", "setup.py"]
+            .iter()
+            .map(|p| record(p, "x"))
+            .collect();
+        order_files(&mut records);
+        let paths: Vec<&str> = records.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["setup.py", "requirements.txt", "app.py"]);
+    }
+
+    #[test]
+    fn test_build_documents() {
+        let opts = ConcatOptions {
+            max_tokens: 12,
+            tokenizer: None,
+            repo_token: String::from("<repo_name>"),
+            file_sep_token: String::from("<file_sep>"),
+            order: FileOrder::Directory,
+            output: SinkOptions::default(),
+        };
+        let records = vec![record("a.py", "x = 1"), record("b.py", "y = 2")];
+        let docs = build_documents(&records, &opts);
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].text, "<repo_name>a/x\n<file_sep>a.py\nx = 1\n");
+        assert_eq!((docs[1].id.as_str(), docs[1].doc_count), ("a/x:1", 2));
+
+        let opts = ConcatOptions {
+            max_tokens: 100,
+            ..opts
+        };
+        let docs = build_documents(&records, &opts);
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].paths, ["a.py", "b.py"]);
+    }
+
+    #[test]
+    fn test_budget_tokenizer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut opts = ConcatOptions {
+            max_tokens: 100,
+            tokenizer: None,
+            repo_token: String::from("<repo_name>"),
+            file_sep_token: String::from("<file_sep>"),
+            order: FileOrder::Directory,
+            output: SinkOptions::default(),
+        };
+        let mut r = record("a.py", "x = 1");
+        r.tokens = BTreeMap::from([("gpt2".to_owned(), 3), ("codellama".to_owned(), 4)]);
+        // Which of the two is ambiguous
+        let records = vec![r];
+        assert!(write_documents(tmp.path(), "a-x", records.clone(), &opts).is_err());
+
+        opts.tokenizer = Some(String::from("gpt2"));
+        assert_eq!(
+            write_documents(tmp.path(), "a-x", records, &opts).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_chunked_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opts = ConcatOptions {
+            max_tokens: 100,
+            tokenizer: None,
+            repo_token: String::from("<repo_name>"),
+            file_sep_token: String::from("<file_sep>"),
+            order: FileOrder::Directory,
+            output: SinkOptions::default(),
+        };
+        let vocab = ["def", "a", "b", "c", "():", "return", "1", "2", "3"];
+        let tokenizers = Tokenizers::for_test(&vocab);
+        let text = "def a():\n    return 1\n\ndef b():\n    return 2\n\ndef c():\n    return 3\n";
+        let mut excluded = Vec::new();
+        let whole = count_tokens(vec![record("a.py", text)], &tokenizers, &mut excluded);
+        let chunking = ChunkOptions {
+            max_tokens: 7,
+            overlap: 2,
+        };
+        let chunks = chunk_records(
+            whole.clone(),
+            &chunking,
+            str::to_owned,
+            &tokenizers,
+            &mut excluded,
+        );
+        assert_eq!(chunks.len(), 3);
+
+        // Budgeted by the window counts, not by its bytes
+        let joined = join_chunks(chunks.clone());
+        assert_eq!(joined[0].tokens, whole[0].tokens);
+        assert_eq!(
+            build_documents(&joined, &opts)[0].tokens,
+            build_documents(&whole, &opts)[0].tokens
+        );
+
+        assert_eq!(
+            write_documents(tmp.path(), "a-x", chunks, &opts).unwrap(),
+            1
+        );
+        let line = fs::read_to_string(tmp.path().join("a-x.jsonl")).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            doc["text"],
+            format!("<repo_name>a/x\n<file_sep>a.py\n{text}")
+        );
+
+        // Over the budget on its own, the file is split across documents
+        let opts = ConcatOptions {
+            max_tokens: 20,
+            ..opts
+        };
+        let docs = build_documents(&joined, &opts);
+        assert!(docs.len() > 1);
+        assert!(docs.iter().all(|d| d.tokens <= 20 && d.paths == ["a.py"]));
+        let pieces: String = docs
+            .iter()
+            .map(|d| d.text.split_once("<file_sep>a.py\n").unwrap().1)
+            .collect();
+        assert_eq!(pieces, text);
+    }
+
+    #[test]
+    fn test_split_file() {
+        // 4 lines of 10 bytes, 2 tokens each
+        let text = "x = 12345\n".repeat(4);
+        let pieces = split_file(&text, 8, 5);
+        let texts: Vec<&str> = pieces.iter().map(|p| p.0).collect();
+        assert_eq!(texts, ["x = 12345\nx = 12345\n", "x = 12345\nx = 12345\n"]);
+        assert!(pieces.iter().all(|p| p.1 == 4));
+
+        // A line longer than the budget is cut at char boundaries
+        let text = "é".repeat(10);
+        let pieces = split_file(&text, 10, 3);
+        assert!(pieces.iter().all(|p| p.1 <= 3));
+        assert_eq!(pieces.iter().map(|p| p.0).collect::<String>(), text);
+    }
+}
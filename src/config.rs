@@ -1,6 +1,12 @@
 use std::path::PathBuf;
 
 use crate::cli;
+use crate::concat::FileOrder;
+use crate::fim::SplitLevel;
+use crate::normalize::Transform;
+use crate::sink::{DEFAULT_ROW_GROUP_SIZE, Format};
+use crate::syntax::SyntaxMode;
+use crate::tokenization::TokenizerSpec;
 
 #[derive(Debug, Clone)]
 pub struct DownloadConfig {
@@ -18,6 +24,26 @@ pub struct ExtractionConfig {
     pub linguist_path: PathBuf,
     pub max_file_size: u64,
     pub languages: Option<Vec<String>>,
+    pub vendor_path: PathBuf,
+    pub documentation_path: PathBuf,
+    pub quality_config: Option<PathBuf>,
+    pub normalize: Vec<Transform>,
+    pub tokenizers: Vec<TokenizerSpec>,
+    pub offline: bool,
+    pub notebooks: bool,
+    pub notebook_markdown: bool,
+    pub chunk_tokens: Option<usize>,
+    pub chunk_overlap: usize,
+    pub syntax_check: Option<SyntaxMode>,
+    pub max_syntax_errors: usize,
+    pub link_tests: bool,
+    pub keep_docs: bool,
+    pub licenses: Option<Vec<String>>,
+    pub format: Format,
+    pub row_group_size: usize,
+    pub shard_bytes: Option<u64>,
+    pub shard_records: Option<usize>,
+    pub partition_by_language: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +52,72 @@ pub struct DedupeConfig {
     pub jsonl_dir: PathBuf,
     pub exact_dedup_dir: PathBuf,
     pub dest_dir: PathBuf,
+    pub format: Format,
+    pub row_group_size: usize,
+    pub shard_bytes: Option<u64>,
+    pub shard_records: Option<usize>,
+    pub partition_by_language: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    pub source: PathBuf,
+    pub input_dir: PathBuf,
+    pub hf_dir: PathBuf,
+    pub jsonl_dir: PathBuf,
+    pub name: String,
+    pub validation_fraction: f64,
+    pub shard_bytes: u64,
+    pub row_group_size: usize,
+    pub deduplicated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConcatConfig {
+    pub source: PathBuf,
+    pub input_dir: PathBuf,
+    pub repo_dir: PathBuf,
+    pub max_tokens: usize,
+    pub tokenizer: Option<String>,
+    pub repo_token: String,
+    pub file_sep_token: String,
+    pub order: FileOrder,
+    pub format: Format,
+    pub row_group_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FimConfig {
+    pub source: PathBuf,
+    pub input_dir: PathBuf,
+    pub fim_dir: PathBuf,
+    pub fim_rate: f64,
+    pub spm_rate: f64,
+    pub seed: u64,
+    pub split_level: SplitLevel,
+    pub tokenizers: Vec<TokenizerSpec>,
+    pub offline: bool,
+    pub prefix_token: String,
+    pub middle_token: String,
+    pub suffix_token: String,
+    pub row_group_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DefinitionsConfig {
+    pub source: PathBuf,
+    pub input_dir: PathBuf,
+    pub definitions_dir: PathBuf,
+    pub format: Format,
+    pub row_group_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct AttributionConfig {
+    pub source: PathBuf,
+    pub input_dir: PathBuf,
+    pub attribution_dir: PathBuf,
+    pub zip_dir: Option<PathBuf>,
 }
 
 impl Default for DownloadConfig {
@@ -79,6 +171,26 @@ impl Default for ExtractionConfig {
             linguist_path: PathBuf::from("./vendor/languages.yml"),
             max_file_size: 2u64.pow(17), // 128KB
             languages: None,             // None, Empty, will grab all files
+            vendor_path: PathBuf::from("./vendor/vendor.yml"),
+            documentation_path: PathBuf::from("./vendor/documentation.yml"),
+            quality_config: Some(PathBuf::from("./configs/quality.yml")),
+            normalize: Vec::new(), // Keep the original text
+            tokenizers: vec![TokenizerSpec::default()],
+            offline: false,
+            notebooks: false,
+            notebook_markdown: false,
+            chunk_tokens: None, // None, files over max_file_size are dropped
+            chunk_overlap: 128,
+            syntax_check: None, // None, files are not parsed
+            max_syntax_errors: 0,
+            link_tests: false,
+            keep_docs: false,
+            licenses: None, // None, files of every license are kept
+            format: Format::Jsonl,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            shard_bytes: None, // None, one file per repo
+            shard_records: None,
+            partition_by_language: false,
         }
     }
 }
@@ -93,6 +205,28 @@ impl ExtractionConfig {
             linguist_path,
             max_file_size,
             languages,
+            vendor_path,
+            documentation_path,
+            quality_config,
+            no_quality_filter,
+            normalize,
+            tokenizer,
+            offline,
+            no_tokenize,
+            notebooks,
+            notebook_markdown,
+            chunk_tokens,
+            chunk_overlap,
+            syntax_check,
+            max_syntax_errors,
+            link_tests,
+            keep_docs,
+            licenses,
+            format,
+            row_group_size,
+            shard_bytes,
+            shard_records,
+            partition_by_language,
         } = opts_cmd
         {
             config.source = source.to_owned();
@@ -105,10 +239,58 @@ impl ExtractionConfig {
             if let Some(l) = linguist_path {
                 config.linguist_path = l.to_owned();
             }
+            // Long files are chunked rather than dropped
+            if chunk_tokens.is_some() {
+                config.max_file_size = 2u64.pow(23); // 8MB
+            }
             if let Some(m) = max_file_size {
                 config.max_file_size = m.to_owned();
             }
             config.languages = languages.to_owned();
+            if let Some(v) = vendor_path {
+                config.vendor_path = v.to_owned();
+            }
+            if let Some(d) = documentation_path {
+                config.documentation_path = d.to_owned();
+            }
+            if let Some(q) = quality_config {
+                config.quality_config = Some(q.to_owned());
+            }
+            if *no_quality_filter {
+                config.quality_config = None;
+            }
+            if let Some(n) = normalize {
+                config.normalize = n.to_owned();
+            }
+            if let Some(t) = tokenizer {
+                config.tokenizers = t.to_owned();
+            }
+            config.offline = *offline;
+            if *no_tokenize {
+                config.tokenizers.clear();
+            }
+            config.notebooks = *notebooks || *notebook_markdown;
+            config.notebook_markdown = *notebook_markdown;
+            config.chunk_tokens = *chunk_tokens;
+            if let Some(o) = chunk_overlap {
+                config.chunk_overlap = *o;
+            }
+            config.syntax_check = *syntax_check;
+            if let Some(m) = max_syntax_errors {
+                config.max_syntax_errors = *m;
+            }
+            config.link_tests = *link_tests;
+            config.keep_docs = *keep_docs;
+            config.licenses = licenses.to_owned();
+            if let Some(f) = format {
+                config.format = *f;
+            }
+            if let Some(r) = row_group_size {
+                config.row_group_size = *r;
+            }
+            config.shard_bytes = *shard_bytes;
+            config.shard_records = *shard_records;
+            config.partition_by_language = *partition_by_language;
         }
         config
     }
@@ -121,6 +303,11 @@ impl Default for DedupeConfig {
             jsonl_dir: PathBuf::from("./jsonl"),
             exact_dedup_dir: PathBuf::from("./exact"),
             dest_dir: PathBuf::from("./dedup"),
+            format: Format::Jsonl,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            shard_bytes: None, // None, one file per repo
+            shard_records: None,
+            partition_by_language: false,
         }
     }
 }
@@ -133,6 +320,11 @@ impl DedupeConfig {
             jsonl_dir,
             exact_dedup_dir,
             dest_dir,
+            format,
+            row_group_size,
+            shard_bytes,
+            shard_records,
+            partition_by_language,
         } = opts_cmd
         {
             config.source = source.to_owned();
@@ -145,6 +337,293 @@ impl DedupeConfig {
             if let Some(d) = dest_dir {
                 config.dest_dir = d.to_owned();
             }
+            if let Some(f) = format {
+                config.format = *f;
+            }
+            if let Some(r) = row_group_size {
+                config.row_group_size = *r;
+            }
+            config.shard_bytes = *shard_bytes;
+            config.shard_records = *shard_records;
+            config.partition_by_language = *partition_by_language;
+        }
+        config
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            source: PathBuf::from("./config/example.jsonl"),
+            input_dir: PathBuf::from("./exact"),
+            hf_dir: PathBuf::from("./hf"),
+            jsonl_dir: PathBuf::from("./jsonl"),
+            name: String::from("codecurator"),
+            validation_fraction: 0.0,       // Everything in train
+            shard_bytes: 500 * 1024 * 1024, // 500MB of text per shard
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            deduplicated: false,
+        }
+    }
+}
+
+impl ExportConfig {
+    pub fn from_cli(opts_cmd: &cli::Command) -> ExportConfig {
+        let mut config = ExportConfig::default();
+        if let cli::Command::Export {
+            source,
+            input_dir,
+            hf,
+            jsonl_dir,
+            name,
+            validation_fraction,
+            shard_bytes,
+            row_group_size,
+            deduplicated,
+        } = opts_cmd
+        {
+            config.source = source.to_owned();
+            if let Some(stem) = source.file_stem() {
+                config.name = stem.to_string_lossy().into_owned();
+            }
+            if let Some(i) = input_dir {
+                config.input_dir = i.to_owned();
+            }
+            config.hf_dir = hf.to_owned();
+            if let Some(j) = jsonl_dir {
+                config.jsonl_dir = j.to_owned();
+            }
+            if let Some(n) = name {
+                config.name = n.to_owned();
+            }
+            if let Some(v) = validation_fraction {
+                config.validation_fraction = v.clamp(0.0, 1.0);
+            }
+            if let Some(b) = shard_bytes {
+                config.shard_bytes = *b;
+            }
+            if let Some(r) = row_group_size {
+                config.row_group_size = *r;
+            }
+            config.deduplicated = *deduplicated;
+        }
+        config
+    }
+}
+
+impl Default for ConcatConfig {
+    fn default() -> Self {
+        Self {
+            source: PathBuf::from("./config/example.jsonl"),
+            input_dir: PathBuf::from("./exact"),
+            repo_dir: PathBuf::from("./repo"),
+            max_tokens: 16384,
+            tokenizer: None, // None, the only tokenizer of the records
+            repo_token: String::from("<repo_name>"),
+            file_sep_token: String::from("<file_sep>"),
+            order: FileOrder::Directory,
+            format: Format::Jsonl,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+        }
+    }
+}
+
+impl ConcatConfig {
+    pub fn from_cli(opts_cmd: &cli::Command) -> ConcatConfig {
+        let mut config = ConcatConfig::default();
+        if let cli::Command::Concat {
+            source,
+            input_dir,
+            repo_dir,
+            max_tokens,
+            tokenizer,
+            repo_token,
+            file_sep_token,
+            order,
+            format,
+            row_group_size,
+        } = opts_cmd
+        {
+            config.source = source.to_owned();
+            if let Some(i) = input_dir {
+                config.input_dir = i.to_owned();
+            }
+            if let Some(r) = repo_dir {
+                config.repo_dir = r.to_owned();
+            }
+            if let Some(m) = max_tokens {
+                config.max_tokens = *m;
+            }
+            config.tokenizer = tokenizer.to_owned();
+            if let Some(t) = repo_token {
+                config.repo_token = t.to_owned();
+            }
+            if let Some(t) = file_sep_token {
+                config.file_sep_token = t.to_owned();
+            }
+            if let Some(o) = order {
+                config.order = *o;
+            }
+            if let Some(f) = format {
+                config.format = *f;
+            }
+            if let Some(r) = row_group_size {
+                config.row_group_size = *r;
+            }
+        }
+        config
+    }
+}
+
+impl Default for FimConfig {
+    fn default() -> Self {
+        Self {
+            source: PathBuf::from("./config/example.jsonl"),
+            input_dir: PathBuf::from("./exact"),
+            fim_dir: PathBuf::from("./fim"),
+            fim_rate: 0.5,
+            spm_rate: 0.5,
+            seed: 0,
+            split_level: SplitLevel::Char,
+            tokenizers: Vec::new(), // Empty, the default one for token split points
+            offline: false,
+            prefix_token: String::from("<fim_prefix>"),
+            middle_token: String::from("<fim_middle>"),
+            suffix_token: String::from("<fim_suffix>"),
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+        }
+    }
+}
+
+impl FimConfig {
+    pub fn from_cli(opts_cmd: &cli::Command) -> FimConfig {
+        let mut config = FimConfig::default();
+        if let cli::Command::Fim {
+            source,
+            input_dir,
+            fim_dir,
+            fim_rate,
+            spm_rate,
+            seed,
+            split_level,
+            tokenizer,
+            offline,
+            prefix_token,
+            middle_token,
+            suffix_token,
+            row_group_size,
+        } = opts_cmd
+        {
+            config.source = source.to_owned();
+            if let Some(i) = input_dir {
+                config.input_dir = i.to_owned();
+            }
+            if let Some(f) = fim_dir {
+                config.fim_dir = f.to_owned();
+            }
+            if let Some(r) = fim_rate {
+                config.fim_rate = r.clamp(0.0, 1.0);
+            }
+            if let Some(r) = spm_rate {
+                config.spm_rate = r.clamp(0.0, 1.0);
+            }
+            if let Some(s) = seed {
+                config.seed = *s;
+            }
+            if let Some(l) = split_level {
+                config.split_level = *l;
+            }
+            if let Some(t) = tokenizer {
+                config.tokenizers = t.to_owned();
+            }
+            config.offline = *offline;
+            if let Some(t) = prefix_token {
+                config.prefix_token = t.to_owned();
+            }
+            if let Some(t) = middle_token {
+                config.middle_token = t.to_owned();
+            }
+            if let Some(t) = suffix_token {
+                config.suffix_token = t.to_owned();
+            }
+            if let Some(r) = row_group_size {
+                config.row_group_size = *r;
+            }
+        }
+        config
+    }
+}
+
+impl Default for DefinitionsConfig {
+    fn default() -> Self {
+        Self {
+            source: PathBuf::from("./config/example.jsonl"),
+            input_dir: PathBuf::from("./exact"),
+            definitions_dir: PathBuf::from("./definitions"),
+            format: Format::Jsonl,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+        }
+    }
+}
+
+impl DefinitionsConfig {
+    pub fn from_cli(opts_cmd: &cli::Command) -> DefinitionsConfig {
+        let mut config = DefinitionsConfig::default();
+        if let cli::Command::Definitions {
+            source,
+            input_dir,
+            definitions_dir,
+            format,
+            row_group_size,
+        } = opts_cmd
+        {
+            config.source = source.to_owned();
+            if let Some(i) = input_dir {
+                config.input_dir = i.to_owned();
+            }
+            if let Some(d) = definitions_dir {
+                config.definitions_dir = d.to_owned();
+            }
+            if let Some(f) = format {
+                config.format = *f;
+            }
+            if let Some(r) = row_group_size {
+                config.row_group_size = *r;
+            }
+        }
+        config
+    }
+}
+
+impl Default for AttributionConfig {
+    fn default() -> Self {
+        Self {
+            source: PathBuf::from("./config/example.jsonl"),
+            input_dir: PathBuf::from("./exact"),
+            attribution_dir: PathBuf::from("./attribution"),
+            zip_dir: None, // None, holders only come from the files
+        }
+    }
+}
+
+impl AttributionConfig {
+    pub fn from_cli(opts_cmd: &cli::Command) -> AttributionConfig {
+        let mut config = AttributionConfig::default();
+        if let cli::Command::Attribution {
+            source,
+            input_dir,
+            attribution_dir,
+            zip_dir,
+        } = opts_cmd
+        {
+            config.source = source.to_owned();
+            if let Some(i) = input_dir {
+                config.input_dir = i.to_owned();
+            }
+            if let Some(a) = attribution_dir {
+                config.attribution_dir = a.to_owned();
+            }
+            config.zip_dir = zip_dir.to_owned();
         }
         config
     }
@@ -2,14 +2,18 @@ use std::collections::HashSet;
 use std::fs;
 use std::io::Cursor;
 use std::path::Path;
-use std::{fs::File, io::BufReader, path::PathBuf};
+use std::path::PathBuf;
 
 use polars::prelude::*;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::Serialize;
 
 use crate::error::ExactDedupError;
-use crate::extractor::{Record, write_repo_jsonl};
+use crate::extractor::Record;
+use crate::shard::{
+    ShardEntry, ShardIndex, ShardOptions, ShardedSink, load_index, remove_index, write_index,
+};
+use crate::sink::{Format, RecordSink, SinkOptions, read_records};
 
 #[derive(Serialize)]
 struct MD5Record {
@@ -19,11 +23,13 @@ struct MD5Record {
 
 impl From<Record> for MD5Record {
     fn from(doc: Record) -> Self {
-        let digest = md5::compute(&doc.text);
-        Self {
-            id: doc.id,
-            md5: format!("{:?}", digest),
-        }
+        // Records extracted before content hashes existed have an empty field
+        let md5 = if doc.content_hash.is_empty() {
+            format!("{:?}", md5::compute(&doc.text))
+        } else {
+            doc.content_hash
+        };
+        Self { id: doc.id, md5 }
     }
 }
 
@@ -44,8 +50,9 @@ impl DedupDataFrame for Vec<MD5Record> {
 
     fn get_unique_md5(&self) -> Result<DataFrame, ExactDedupError> {
         let df = self.to_dataframe()?;
+        // Keep the first occurrence so repeated runs keep the same copy
         let df_unique =
-            df.unique_stable(Some(&["md5".to_string()]), UniqueKeepStrategy::Any, None)?;
+            df.unique_stable(Some(&["md5".to_string()]), UniqueKeepStrategy::First, None)?;
         Ok(df_unique)
     }
 
@@ -62,68 +69,85 @@ impl DedupDataFrame for Vec<MD5Record> {
     }
 }
 
-fn read_records(jsonl_path: &PathBuf) -> Result<Vec<Record>, ExactDedupError> {
-    let file = File::open(jsonl_path)?;
-    let mut reader = BufReader::new(file);
-    let mut records = Vec::new();
+// Writes the unique records of one input file to the same relative path
+// under `dest_dir`, so partitions and shards of the input do not collide.
+// Returns the input's path relative to `input_dir` and the output's entry.
+fn write_records(
+    path: &Path,
+    input_dir: &Path,
+    ids: &HashSet<String>,
+    dest_dir: &Path,
+    output: &SinkOptions,
+) -> Result<(String, ShardEntry), ExactDedupError> {
+    // Keep the repo or shard name, only the directory and format change
+    let rel_path = path.strip_prefix(input_dir).unwrap_or(path);
+    let Some((_, name)) = rel_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(Format::split_name)
+    else {
+        return Err(ExactDedupError::Validation {
+            message: format!("Unable to parse filename of path {}", path.display()),
+        });
+    };
+    let rel_dir = rel_path.parent().unwrap_or(Path::new(""));
 
-    loop {
-        match jsonl::read::<_, Record>(&mut reader) {
-            Ok(record) => records.push(record),
-            Err(jsonl::ReadError::Eof) => break,
-            Err(_) => continue,
+    let mut sink = RecordSink::create(&dest_dir.join(rel_dir), name, output)?;
+    let file = rel_dir.join(format!("{name}.{}", output.format.extension()));
+    let mut entry = ShardEntry {
+        file: file.to_string_lossy().into_owned(),
+        ..Default::default()
+    };
+    for record in read_records(path)? {
+        if ids.contains(&record.id) {
+            entry.records += 1;
+            entry.bytes += record.text.len() as u64;
+            if !entry.repos.contains(&record.repo_name) {
+                entry.repos.push(record.repo_name.clone());
+            }
+            sink.write(record)?;
         }
     }
-
-    Ok(records)
+    sink.finish()?;
+    Ok((rel_path.to_string_lossy().into_owned(), entry))
 }
 
-fn write_records(
-    jsonl_path: &PathBuf,
-    ids: &HashSet<String>,
+// Index of the mirrored shards of a sharded input, with their new counts
+fn write_mirrored_index(
+    mut index: ShardIndex,
+    entries: &[(String, ShardEntry)],
     dest_dir: &Path,
+    output: &SinkOptions,
 ) -> Result<(), ExactDedupError> {
-    let file = File::open(jsonl_path)?;
-    let mut reader = BufReader::new(file);
-
-    // Replace the root dir from /jsonl/ to /<dedup dir>/
-    let name = match jsonl_path.file_stem() {
-        Some(name) => format!("{}", name.display()),
-        _ => {
-            return Err(ExactDedupError::Validation {
-                message: format!(
-                    "Unable to parse filename of jsonl path {}",
-                    jsonl_path.display()
-                ),
-            });
-        }
-    };
-
-    let mut fc: i64 = 0;
-    loop {
-        match jsonl::read::<_, Record>(&mut reader) {
-            Ok(record) => {
-                if ids.contains(&record.id) {
-                    match write_repo_jsonl(dest_dir, &name, &record, &fc) {
-                        Ok(_) => {
-                            fc += 1;
-                            continue;
-                        }
-                        Err(e) => eprintln!("{}", e),
-                    }
-                }
-            }
-            Err(jsonl::ReadError::Eof) => break,
-            Err(e) => {
-                eprintln!("{}", e);
-                continue;
-            }
+    index.format = output.format.extension().to_owned();
+    for shard in index.shards.iter_mut() {
+        if let Some((_, entry)) = entries.iter().find(|(input, _)| *input == shard.file) {
+            shard.file = entry.file.clone();
+            shard.records = entry.records;
+            shard.bytes = entry.bytes;
+            shard.repos = entry.repos.clone();
         }
     }
+    write_index(dest_dir, &index)?;
     Ok(())
 }
 
-pub fn exact_deduplication(jsonl_paths: &Vec<PathBuf>, ddest: &Path) {
+// Where and how deduplicated records are written
+pub struct DedupOutput<'a> {
+    pub dir: &'a Path,
+    pub sink: SinkOptions,
+    // None mirrors the input files and their layout
+    pub shards: Option<ShardOptions>,
+}
+
+// `repos` restricts sharded inputs, which mix repositories, to the records
+// of the given "<user>-<repo>" names
+pub fn exact_deduplication(
+    jsonl_paths: &Vec<PathBuf>,
+    repos: Option<&HashSet<String>>,
+    input_dir: &Path,
+    output: &DedupOutput,
+) {
     println!(
         "Starting Exact deduplication on {} files",
         jsonl_paths.len()
@@ -131,8 +155,19 @@ pub fn exact_deduplication(jsonl_paths: &Vec<PathBuf>, ddest: &Path) {
 
     let hashes: Vec<MD5Record> = jsonl_paths
         .par_iter()
-        .filter_map(|path| read_records(path).ok())
+        .filter_map(|path| match read_records(path) {
+            Ok(records) => Some(records),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                None
+            }
+        })
         .flatten()
+        .filter(|r| {
+            repos.is_none_or(|hs| {
+                r.repo_name.is_empty() || hs.contains(&r.repo_name.replace('/', "-"))
+            })
+        })
         .map(MD5Record::from)
         .collect();
 
@@ -143,17 +178,227 @@ pub fn exact_deduplication(jsonl_paths: &Vec<PathBuf>, ddest: &Path) {
 
     println!("Found {} unique documents", ids.len());
 
-    let destination_dir = ddest;
-    fs::create_dir_all(&destination_dir).expect("Unable to create deduplication dir");
+    let destination_dir = output.dir;
+    fs::create_dir_all(destination_dir).expect("Unable to create deduplication dir");
 
-    jsonl_paths
-        .par_iter()
-        .for_each(|path| match write_records(path, &ids_hs, destination_dir) {
-            Ok(()) => (),
-            Err(e) => {
-                eprintln!("Error processing {}: {}", path.display(), e);
+    match &output.shards {
+        None => {
+            // Per-repository inputs, the index of an earlier sharded run
+            // would hide the new files from later stages
+            let index = load_index(input_dir);
+            if index.is_none()
+                && let Err(e) = remove_index(destination_dir)
+            {
+                eprintln!(
+                    "Error removing the shard index of {}: {}",
+                    destination_dir.display(),
+                    e
+                );
             }
-        });
+            let entries: Vec<(String, ShardEntry)> = jsonl_paths
+                .par_iter()
+                .filter_map(|path| {
+                    match write_records(path, input_dir, &ids_hs, destination_dir, &output.sink) {
+                        Ok(entry) => Some(entry),
+                        Err(e) => {
+                            eprintln!("Error processing {}: {}", path.display(), e);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            // Sharded inputs mix repositories, later stages need the index
+            // to find them
+            if let Some(index) = index
+                && let Err(e) = write_mirrored_index(index, &entries, destination_dir, &output.sink)
+            {
+                eprintln!(
+                    "Error writing the shard index to {}: {}",
+                    destination_dir.display(),
+                    e
+                );
+            }
+        }
+        Some(shards) => {
+            if let Err(e) =
+                write_shards(jsonl_paths, &ids_hs, destination_dir, shards, &output.sink)
+            {
+                eprintln!(
+                    "Error writing shards to {}: {}",
+                    destination_dir.display(),
+                    e
+                );
+            }
+        }
+    }
 
     println!("Exact dedup written to {}", destination_dir.display());
 }
+
+// Inputs are read in parallel batches and written in path order
+fn write_shards(
+    paths: &[PathBuf],
+    ids: &HashSet<String>,
+    dest_dir: &Path,
+    shards: &ShardOptions,
+    output: &SinkOptions,
+) -> Result<(), ExactDedupError> {
+    let mut sink = ShardedSink::create(dest_dir, shards, output)?;
+    for batch in paths.chunks(rayon::current_num_threads() * 2) {
+        let records: Vec<Vec<Record>> = batch
+            .par_iter()
+            .map(|path| read_records(path))
+            .collect::<Result<_, _>>()?;
+        for record in records.into_iter().flatten() {
+            if ids.contains(&record.id) {
+                sink.write(record)?;
+            }
+        }
+    }
+    sink.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use crate::deduplication::{DedupOutput, exact_deduplication};
+    use crate::extractor::Record;
+    use crate::shard::{SHARD_INDEX, ShardOptions, ShardedSink, load_index, read_index};
+    use crate::sink::{Format, RecordSink, SinkOptions, read_records};
+
+    // Extract output in `dir`, written through the shard index
+    fn write_input(dir: &Path, opts: &ShardOptions, records: &[(&str, &str, &str)]) {
+        let mut sink = ShardedSink::create(dir, opts, &SinkOptions::default()).unwrap();
+        for (path, language, text) in records {
+            sink.write(Record::for_test("user/repo", path, language, text))
+                .unwrap();
+        }
+        sink.finish().unwrap();
+    }
+
+    #[test]
+    fn test_partitioned_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input_dir = tmp.path().join("jsonl");
+        let opts = ShardOptions::new(None, None, true).unwrap();
+        write_input(
+            &input_dir,
+            &opts,
+            &[
+                ("a.rs", "Rust", "fn a() {}\n"),
+                ("b.rs", "Rust", "fn a() {}\n"),
+                ("a.py", "Python", "x = 1\n"),
+                ("b.py", "Python", "y = 2\n"),
+            ],
+        );
+
+        // Without shard options every partition keeps its own file
+        let dest_dir = tmp.path().join("dedup");
+        let output = DedupOutput {
+            dir: &dest_dir,
+            sink: SinkOptions::default(),
+            shards: None,
+        };
+        let paths = read_index(&input_dir).unwrap();
+        let repos = HashSet::from([String::from("user-repo")]);
+        exact_deduplication(&paths, Some(&repos), &input_dir, &output);
+        let paths = |language: &str| {
+            read_records(&dest_dir.join(format!("language={language}/part-000.jsonl")))
+                .unwrap()
+                .into_iter()
+                .map(|r| r.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths("Rust"), ["a.rs"]);
+        assert_eq!(paths("Python"), ["a.py", "b.py"]);
+    }
+
+    #[test]
+    fn test_sharded_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input_dir = tmp.path().join("jsonl");
+        let opts = ShardOptions::new(None, Some(2), false).unwrap();
+        write_input(
+            &input_dir,
+            &opts,
+            &[
+                ("a.py", "Python", "x = 1\n"),
+                ("b.py", "Python", "x = 1\n"),
+                ("c.py", "Python", "y = 2\n"),
+            ],
+        );
+
+        // The mirrored shards get an index of their own, in the new format
+        let dest_dir = tmp.path().join("dedup");
+        let output = DedupOutput {
+            dir: &dest_dir,
+            sink: SinkOptions {
+                format: Format::Parquet,
+                ..Default::default()
+            },
+            shards: None,
+        };
+        let paths = read_index(&input_dir).unwrap();
+        let repos = HashSet::from([String::from("user-repo")]);
+        exact_deduplication(&paths, Some(&repos), &input_dir, &output);
+        let index = load_index(&dest_dir).unwrap();
+        assert_eq!(index.format, "parquet");
+        let files: Vec<&str> = index.shards.iter().map(|s| s.file.as_str()).collect();
+        assert_eq!(
+            files,
+            [
+                "data/train-00000-of-00002.parquet",
+                "data/train-00001-of-00002.parquet"
+            ]
+        );
+        assert_eq!(index.records(), 2);
+        assert_eq!(index.shards[1].repos, ["user/repo"]);
+        let records: Vec<_> = read_index(&dest_dir)
+            .unwrap()
+            .iter()
+            .flat_map(|p| read_records(p).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn test_per_repo_after_sharded_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input_dir = tmp.path().join("jsonl");
+        let mut sink =
+            RecordSink::create(&input_dir, "user-repo", &SinkOptions::default()).unwrap();
+        for (path, text) in [
+            ("a.py", "x = 1\n"),
+            ("b.py", "x = 1\n"),
+            ("c.py", "y = 2\n"),
+        ] {
+            sink.write(Record::for_test("user/repo", path, "Python", text))
+                .unwrap();
+        }
+        sink.finish().unwrap();
+        let paths = vec![input_dir.join("user-repo.jsonl")];
+
+        // One record per shard, then one file per repository
+        let dest_dir = tmp.path().join("dedup");
+        let output = DedupOutput {
+            dir: &dest_dir,
+            sink: SinkOptions::default(),
+            shards: ShardOptions::new(None, Some(1), false),
+        };
+        exact_deduplication(&paths, None, &input_dir, &output);
+        assert_eq!(read_index(&dest_dir).unwrap().len(), 2);
+
+        let output = DedupOutput {
+            shards: None,
+            ..output
+        };
+        exact_deduplication(&paths, None, &input_dir, &output);
+        assert!(!dest_dir.join(SHARD_INDEX).exists());
+        assert!(!dest_dir.join("data").exists());
+        let records = read_records(&dest_dir.join("user-repo.jsonl")).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+}
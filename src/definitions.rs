@@ -0,0 +1,674 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use polars::prelude::{Column, DataFrame, PolarsResult};
+use rayon::prelude::*;
+use serde::Serialize;
+use tree_sitter::Node;
+use uuid::Uuid;
+
+use crate::chunk::join_chunks;
+use crate::error::DefinitionError;
+use crate::extractor::Record;
+use crate::sink::{Format, RecordSink, SinkOptions, Tabular, read_records};
+use crate::syntax::parse;
+
+// Node kinds of every grammar and the kind of definition they are
+const DEFINITION_KINDS: &[(&str, &str)] = &[
+    // Python
+    ("function_definition", "function"),
+    ("class_definition", "class"),
+    // Rust
+    ("function_item", "function"),
+    ("struct_item", "struct"),
+    ("enum_item", "enum"),
+    ("trait_item", "trait"),
+    // C and C++, `function_definition` above
+    ("struct_specifier", "struct"),
+    ("enum_specifier", "enum"),
+    ("class_specifier", "class"),
+    // Go, `type_spec` is a struct or an interface
+    ("function_declaration", "function"),
+    ("method_declaration", "method"),
+    ("type_spec", "type"),
+    // Java, `method_declaration` above
+    ("constructor_declaration", "method"),
+    ("class_declaration", "class"),
+    ("record_declaration", "class"),
+    ("interface_declaration", "interface"),
+    ("enum_declaration", "enum"),
+    // JavaScript and TypeScript, `const f = () => ...` is a `variable_declarator`
+    ("generator_function_declaration", "function"),
+    ("method_definition", "method"),
+    ("variable_declarator", "function"),
+    ("abstract_class_declaration", "class"),
+];
+
+// Verilog and SystemVerilog node kinds, their grammar has no name or body
+// fields and shares some kinds with the grammars above
+const VERILOG_KINDS: &[(&str, &str)] = &[
+    ("module_declaration", "module"),
+    ("interface_declaration", "interface"),
+    ("function_declaration", "function"),
+    ("task_declaration", "task"),
+    ("class_declaration", "class"),
+];
+
+// Nodes naming a Verilog definition, a module is named inside its header
+const VERILOG_NAMES: &[&str] = &[
+    "module_header",
+    "interface_identifier",
+    "function_identifier",
+    "task_identifier",
+    "class_identifier",
+];
+
+// Nodes whose functions are methods
+const CLASS_BODIES: &[&str] = &[
+    "class_definition",
+    "impl_item",
+    "trait_item",
+    "field_declaration_list",
+    "class_body",
+    "interface_body",
+    "enum_body",
+    // SystemVerilog
+    "class_item",
+];
+
+// Nodes wrapping a definition with its decorators, templates or exports,
+// leading comments are found before them
+const WRAPPERS: &[&str] = &[
+    "decorated_definition",
+    "template_declaration",
+    "export_statement",
+    "lexical_declaration",
+    "variable_declaration",
+    "type_declaration",
+    // Verilog items nested in modules, packages and classes
+    "module_or_generate_item",
+    "interface_or_generate_item",
+    "package_or_generate_item_declaration",
+    "class_item",
+    "class_method",
+];
+
+// Leaf nodes naming a C or C++ function inside its declarator
+const DECLARATOR_NAMES: &[&str] = &[
+    "identifier",
+    "field_identifier",
+    "qualified_identifier",
+    "destructor_name",
+    "operator_name",
+];
+
+// A function, method or class of a source file
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Definition {
+    // Source of the whole definition, decorators included
+    pub text: String,
+    pub id: String,
+    // Id of the record of the enclosing file
+    pub file_id: String,
+    pub repo_name: String,
+    pub branch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    pub path: String,
+    pub language: String,
+    // function, method, class, struct, enum, trait, interface or module
+    pub kind: String,
+    pub name: String,
+    // Everything before the body: "def f(x) -> int", "pub fn f(x: u8) -> u8"
+    pub signature: String,
+    // Comment block or docstring, without its delimiters
+    pub docstring: String,
+    pub body: String,
+    // 1-based and inclusive
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+impl Tabular for Definition {
+    fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame> {
+        let str_column = |name: &str, f: fn(&Definition) -> &str| {
+            Column::new(name.into(), rows.iter().map(f).collect::<Vec<_>>())
+        };
+        let u64_column = |name: &str, f: fn(&Definition) -> usize| {
+            Column::new(
+                name.into(),
+                rows.iter().map(|d| f(d) as u64).collect::<Vec<_>>(),
+            )
+        };
+        DataFrame::new(vec![
+            str_column("text", |d| &d.text),
+            str_column("id", |d| &d.id),
+            str_column("file_id", |d| &d.file_id),
+            str_column("repo_name", |d| &d.repo_name),
+            str_column("branch", |d| &d.branch),
+            Column::new(
+                "commit_sha".into(),
+                rows.iter()
+                    .map(|d| d.commit_sha.as_deref())
+                    .collect::<Vec<_>>(),
+            ),
+            str_column("path", |d| &d.path),
+            str_column("language", |d| &d.language),
+            str_column("kind", |d| &d.kind),
+            str_column("name", |d| &d.name),
+            str_column("signature", |d| &d.signature),
+            str_column("docstring", |d| &d.docstring),
+            str_column("body", |d| &d.body),
+            u64_column("start_line", |d| d.start_line),
+            u64_column("end_line", |d| d.end_line),
+        ])
+    }
+}
+
+// Parts of a definition found in the source, before it gets the identity
+// of its file
+struct Span<'a> {
+    kind: &'a str,
+    name: &'a str,
+    // Byte ranges of the whole definition and of its body
+    range: (usize, usize),
+    body: (usize, usize),
+    signature: &'a str,
+    docstring: String,
+}
+
+// Removes comment delimiters and the common indentation of a comment block
+// or docstring
+fn clean_doc(text: &str) -> String {
+    let lines: Vec<&str> = text
+        .lines()
+        .map(|l| {
+            let l = l.trim();
+            let l = ["///", "//!", "//", "/**", "/*!", "/*"]
+                .iter()
+                .find_map(|p| l.strip_prefix(p))
+                .unwrap_or(l);
+            let l = l.strip_suffix("*/").unwrap_or(l);
+            l.strip_prefix('*').unwrap_or(l).trim_end()
+        })
+        .collect();
+    let indent = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|l| l.get(indent..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_owned()
+}
+
+// Python docstring: contents of a string literal, dedented like inspect.cleandoc
+fn clean_docstring(literal: &str) -> String {
+    let s = literal.trim_start_matches(|c: char| "rRuUbBfF".contains(c));
+    let s = ["\"\"\"", "'''", "\"", "'"]
+        .iter()
+        .find_map(|q| s.strip_prefix(q).and_then(|s| s.strip_suffix(q)))
+        .unwrap_or(s);
+    let mut lines = s.lines();
+    let first = lines.next().unwrap_or_default().trim();
+    let rest: Vec<&str> = lines.collect();
+    let indent = rest
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let mut doc = first.to_owned();
+    for l in rest {
+        doc.push('\n');
+        doc.push_str(l.get(indent..).unwrap_or("").trim_end());
+    }
+    doc.trim().to_owned()
+}
+
+// Name of a C or C++ function, nested in pointer and function declarators
+fn declarator_name<'a>(node: Node, text: &'a str) -> Option<&'a str> {
+    let mut node = node.child_by_field_name("declarator")?;
+    while !DECLARATOR_NAMES.contains(&node.kind()) {
+        node = node.child_by_field_name("declarator")?;
+    }
+    node.utf8_text(text.as_bytes()).ok()
+}
+
+// Line comments may end at the start of the next line
+fn comment_end_row(comment: Node) -> usize {
+    let end = comment.end_position();
+    if end.column == 0 {
+        end.row.saturating_sub(1)
+    } else {
+        end.row
+    }
+}
+
+// Comment nodes right above `node`, without blank lines in between
+fn leading_comments(node: Node, text: &str) -> String {
+    let mut comments = Vec::new();
+    let mut row = node.start_position().row;
+    let mut prev = node.prev_sibling();
+    while let Some(p) = prev {
+        if p.kind() == "attribute_item" {
+            // Rust attributes sit between doc comments and the item
+        } else if p.kind().contains("comment") && comment_end_row(p) + 1 >= row {
+            comments.push(&text[p.start_byte()..p.end_byte()]);
+        } else {
+            break;
+        }
+        row = p.start_position().row;
+        prev = p.prev_sibling();
+    }
+    comments.reverse();
+    clean_doc(&comments.join("\n"))
+}
+
+// The definition `node` starts, if it is one
+fn span<'a>(node: Node, text: &'a str, in_class: bool) -> Option<Span<'a>> {
+    let &(_, mut kind) = DEFINITION_KINDS.iter().find(|(k, _)| *k == node.kind())?;
+    let mut body = node.child_by_field_name("body");
+    match node.kind() {
+        "type_spec" => {
+            let ty = node.child_by_field_name("type")?;
+            kind = match ty.kind() {
+                "struct_type" => "struct",
+                "interface_type" => "interface",
+                _ => return None,
+            };
+            body = Some(ty);
+        }
+        "variable_declarator" => {
+            let value = node.child_by_field_name("value")?;
+            if !matches!(
+                value.kind(),
+                "arrow_function" | "function_expression" | "function"
+            ) {
+                return None;
+            }
+            body = value.child_by_field_name("body");
+        }
+        _ => {}
+    }
+    let body = body?;
+    if kind == "function" && in_class {
+        kind = "method";
+    }
+    let name = match node.child_by_field_name("name") {
+        Some(n) => n.utf8_text(text.as_bytes()).ok()?,
+        None => declarator_name(node, text)?,
+    };
+
+    let mut anchor = node;
+    while let Some(parent) = anchor.parent()
+        && WRAPPERS.contains(&parent.kind())
+    {
+        anchor = parent;
+    }
+    // Go types are named after the `type` keyword of their declaration
+    let signature_start = if node.kind() == "type_spec" {
+        anchor.start_byte()
+    } else {
+        node.start_byte()
+    };
+    let signature = text[signature_start..body.start_byte()].trim_end();
+    let signature = signature.strip_suffix(':').unwrap_or(signature).trim_end();
+
+    // Python docstrings are the first statement of the body, and left out of it
+    let mut body_start = body.start_byte();
+    let docstring = if body.kind() == "block"
+        && let Some(first) = body.named_child(0)
+        && first.kind() == "expression_statement"
+        && let Some(string) = first.named_child(0)
+        && string.kind() == "string"
+    {
+        body_start = first.end_byte();
+        clean_docstring(&text[string.start_byte()..string.end_byte()])
+    } else {
+        leading_comments(anchor, text)
+    };
+    Some(Span {
+        kind,
+        name,
+        range: (anchor.start_byte(), anchor.end_byte().max(node.end_byte())),
+        body: (body_start, body.end_byte()),
+        signature,
+        docstring,
+    })
+}
+
+// Definitions of a syntax tree, in source order
+fn tree_spans<'a>(root: Node, text: &'a str, verilog: bool) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    // Depth-first, children pushed in reverse to pop them in order
+    let mut stack = vec![(root, false)];
+    while let Some((node, in_class)) = stack.pop() {
+        let mut in_class = in_class;
+        let span = if verilog {
+            verilog_span(node, text, in_class)
+        } else {
+            span(node, text, in_class)
+        };
+        if let Some(s) = span {
+            // Functions nested in methods are functions again
+            in_class = false;
+            spans.push(s);
+        }
+        if CLASS_BODIES.contains(&node.kind()) {
+            in_class = true;
+        }
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node.named_children(&mut cursor).collect();
+        stack.extend(children.into_iter().rev().map(|c| (c, in_class)));
+    }
+    spans
+}
+
+// First node of the kinds in pre-order
+fn find_first<'t>(node: Node<'t>, kinds: &[&str]) -> Option<Node<'t>> {
+    let mut stack = vec![node];
+    while let Some(n) = stack.pop() {
+        if kinds.contains(&n.kind()) {
+            return Some(n);
+        }
+        let mut cursor = n.walk();
+        let children: Vec<Node> = n.children(&mut cursor).collect();
+        stack.extend(children.into_iter().rev());
+    }
+    None
+}
+
+// Start of the end keyword closing a Verilog definition, which may sit in
+// the `*_body_declaration` of functions and tasks
+fn verilog_end(node: Node) -> usize {
+    let mut node = node;
+    loop {
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node.children(&mut cursor).collect();
+        if let Some(end) = children
+            .iter()
+            .rev()
+            .find(|c| !c.is_named() && c.kind().starts_with("end"))
+        {
+            return end.start_byte();
+        }
+        match children.iter().rev().find(|c| c.is_named()) {
+            Some(last) => node = *last,
+            None => return node.end_byte(),
+        }
+    }
+}
+
+// The Verilog module, interface, function, task or class `node` starts, if
+// it is one. The header runs up to its first `;`, the body up to the end
+// keyword.
+fn verilog_span<'a>(node: Node, text: &'a str, in_class: bool) -> Option<Span<'a>> {
+    let &(_, mut kind) = VERILOG_KINDS.iter().find(|(k, _)| *k == node.kind())?;
+    if kind == "function" && in_class {
+        kind = "method";
+    }
+    let mut name = find_first(node, VERILOG_NAMES)?;
+    if name.kind() == "module_header" {
+        name = find_first(name, &["simple_identifier"])?;
+    }
+    let header_end = find_first(node, &[";"])?;
+    let body = (
+        header_end.end_byte(),
+        verilog_end(node).max(header_end.end_byte()),
+    );
+
+    let mut anchor = node;
+    while let Some(parent) = anchor.parent()
+        && WRAPPERS.contains(&parent.kind())
+    {
+        anchor = parent;
+    }
+    Some(Span {
+        kind,
+        name: name.utf8_text(text.as_bytes()).ok()?,
+        range: (anchor.start_byte(), anchor.end_byte().max(node.end_byte())),
+        body,
+        signature: text[node.start_byte()..header_end.start_byte()].trim_end(),
+        docstring: leading_comments(anchor, text),
+    })
+}
+
+fn line_of(text: &str, byte: usize) -> usize {
+    text[..byte].matches('\n').count() + 1
+}
+
+// Functions, methods and classes of a file, empty for languages without
+// a grammar
+pub fn definitions(record: &Record) -> Vec<Definition> {
+    let text = &record.text;
+    let Some(tree) = parse(&record.language, text) else {
+        return Vec::new();
+    };
+    let verilog = matches!(record.language.as_str(), "Verilog" | "SystemVerilog");
+    tree_spans(tree.root_node(), text, verilog)
+        .into_iter()
+        .map(|s| {
+            let start_line = line_of(text, s.range.0);
+            let key = format!("{}:{}:{}", record.id, start_line, s.name);
+            Definition {
+                text: text[s.range.0..s.range.1].to_owned(),
+                id: Uuid::new_v5(&Uuid::NAMESPACE_URL, key.as_bytes()).to_string(),
+                file_id: record.id.clone(),
+                repo_name: record.repo_name.clone(),
+                branch: record.branch.clone(),
+                commit_sha: record.commit_sha.clone(),
+                path: record.path.clone(),
+                language: record.language.clone(),
+                kind: s.kind.to_owned(),
+                name: s.name.to_owned(),
+                signature: s.signature.to_owned(),
+                docstring: s.docstring,
+                body: text[s.body.0..s.body.1].trim_start().to_owned(),
+                start_line,
+                end_line: line_of(text, s.range.1.saturating_sub(1).max(s.range.0)),
+            }
+        })
+        .collect()
+}
+
+fn write_definitions(
+    dest_dir: &Path,
+    name: &str,
+    records: Vec<Record>,
+    output: &SinkOptions,
+) -> Result<usize, DefinitionError> {
+    let mut sink = RecordSink::create(dest_dir, name, output)?;
+    // Windows cut definitions apart, they are parsed as the whole file
+    for record in join_chunks(records) {
+        for definition in definitions(&record) {
+            sink.write(definition)?;
+        }
+    }
+    Ok(sink.finish()?)
+}
+
+// One output file of definitions per repository. `repos` restricts sharded
+// inputs, which mix repositories, like in dedupe.
+pub fn extract_definitions(
+    paths: &[PathBuf],
+    repos: Option<&HashSet<String>>,
+    dest_dir: &Path,
+    output: &SinkOptions,
+) -> Result<usize, DefinitionError> {
+    let Some(repos) = repos else {
+        // One input file per repository
+        let counts: Vec<usize> = paths
+            .par_iter()
+            .map(|path| {
+                let name = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(Format::split_name)
+                    .map(|(_, name)| name)
+                    .ok_or_else(|| DefinitionError::Validation {
+                        message: format!("Unable to parse filename of path {}", path.display()),
+                    })?;
+                write_definitions(dest_dir, name, read_records(path)?, output)
+            })
+            .collect::<Result<_, _>>()?;
+        return Ok(counts.iter().sum());
+    };
+
+    // Shards mix repositories, group them back first
+    let mut by_repo: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+    for path in paths {
+        for record in read_records(path)? {
+            let name = record.repo_name.replace('/', "-");
+            if repos.contains(&name) {
+                by_repo.entry(name).or_default().push(record);
+            }
+        }
+    }
+    let counts: Vec<usize> = by_repo
+        .into_par_iter()
+        .map(|(name, records)| write_definitions(dest_dir, &name, records, output))
+        .collect::<Result<_, _>>()?;
+    Ok(counts.iter().sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::definitions::{definitions, write_definitions};
+    use crate::extractor::Record;
+    use crate::sink::SinkOptions;
+
+    fn record(path: &str, language: &str, text: &str) -> Record {
+        Record::for_test("", path, language, text)
+    }
+
+    #[test]
+    fn test_python_definitions() {
+        let text = "import os\n\nclass A:\n    \"\"\"An A.\n\n    Holds x.\n    \"\"\"\n\n    @property\n    def x(self) -> int:\n        '''The x.'''\n        return 1\n\ndef f(a, b):\n    return a + b\n";
+        let defs = definitions(&record("a.py", "Python", text));
+        let summary: Vec<(&str, &str, usize, usize)> = defs
+            .iter()
+            .map(|d| (d.kind.as_str(), d.name.as_str(), d.start_line, d.end_line))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("class", "A", 3, 12),
+                ("method", "x", 9, 12),
+                ("function", "f", 14, 15)
+            ]
+        );
+        assert_eq!(defs[0].docstring, "An A.\n\nHolds x.");
+        assert_eq!(defs[1].signature, "def x(self) -> int");
+        assert_eq!(defs[1].docstring, "The x.");
+        assert_eq!(defs[1].body, "return 1");
+        assert!(defs[1].text.starts_with("@property"));
+        assert_eq!(defs[2].file_id, "a.py");
+    }
+
+    #[test]
+    fn test_c_like_definitions() {
+        let text = "/// Adds one.\n#[inline]\npub fn add(x: u8) -> u8 {\n    x + 1\n}\n\nstruct P { x: u8 }\n\nimpl P {\n    fn get(&self) -> u8 { self.x }\n}\n";
+        let defs = definitions(&record("a.rs", "Rust", text));
+        let names: Vec<(&str, &str)> = defs
+            .iter()
+            .map(|d| (d.kind.as_str(), d.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [("function", "add"), ("struct", "P"), ("method", "get")]
+        );
+        assert_eq!(defs[0].docstring, "Adds one.");
+        assert_eq!(defs[0].signature, "pub fn add(x: u8) -> u8");
+
+        let text = "// Sum\nstatic int *sum(int a) { return 0; }\n";
+        let defs = definitions(&record("a.c", "C", text));
+        assert_eq!(
+            (defs[0].name.as_str(), defs[0].docstring.as_str()),
+            ("sum", "Sum")
+        );
+
+        let text = "/** Doc */\nexport const f = (a) => a + 1;\nclass K { m() { return 1; } }\n";
+        let defs = definitions(&record("a.js", "JavaScript", text));
+        let names: Vec<(&str, &str)> = defs
+            .iter()
+            .map(|d| (d.kind.as_str(), d.name.as_str()))
+            .collect();
+        assert_eq!(names, [("function", "f"), ("class", "K"), ("method", "m")]);
+        assert_eq!(defs[0].docstring, "Doc");
+    }
+
+    #[test]
+    fn test_verilog_definitions() {
+        let text = "// Counter\nmodule counter #(parameter W = 8) (input clk);\n  function automatic [7:0] inc(input [7:0] v);\n    inc = v + 1;\n  endfunction\nendmodule\n";
+        let defs = definitions(&record("c.v", "Verilog", text));
+        let summary: Vec<(&str, &str, usize, usize)> = defs
+            .iter()
+            .map(|d| (d.kind.as_str(), d.name.as_str(), d.start_line, d.end_line))
+            .collect();
+        assert_eq!(
+            summary,
+            [("module", "counter", 2, 6), ("function", "inc", 3, 5)]
+        );
+        assert_eq!(defs[0].docstring, "Counter");
+        assert_eq!(
+            defs[0].signature,
+            "module counter #(parameter W = 8) (input clk)"
+        );
+        assert_eq!(defs[1].body, "inc = v + 1;\n  ");
+
+        let text = "interface bus_if(input clk);\n  logic a;\nendinterface\n\nclass pkt;\n  // Sends it\n  task send(input int n);\n    $display(n);\n  endtask\n  function void f();\n  endfunction\nendclass\n";
+        let defs = definitions(&record("p.sv", "SystemVerilog", text));
+        let summary: Vec<(&str, &str, usize, usize)> = defs
+            .iter()
+            .map(|d| (d.kind.as_str(), d.name.as_str(), d.start_line, d.end_line))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("interface", "bus_if", 1, 3),
+                ("class", "pkt", 5, 12),
+                ("task", "send", 7, 9),
+                ("method", "f", 10, 11)
+            ]
+        );
+        assert_eq!(defs[2].docstring, "Sends it");
+        assert_eq!(defs[2].signature, "task send(input int n)");
+    }
+
+    #[test]
+    fn test_chunked_definitions() {
+        let text = "def f():\n    return 1\n\n\ndef g():\n    return 2\n";
+        // Two windows cutting f and g apart, overlapping on the blank lines
+        let cut = text.find("    return 2").unwrap();
+        let overlap = text.find("\n\n").unwrap() + 1;
+        let windows: Vec<Record> = [(0, cut), (overlap, text.len())]
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, end))| {
+                let mut r = record("a.py", "Python", &text[start..end]);
+                r.id = format!("a.py#{i}");
+                r.parent_id = Some(String::from("a.py"));
+                r.chunk_index = Some(i as u32);
+                r.chunk_count = Some(2);
+                r.chunk_offset = Some(start as u64);
+                r
+            })
+            .collect();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let count =
+            write_definitions(tmp.path(), "user-repo", windows, &SinkOptions::default()).unwrap();
+        assert_eq!(count, 2);
+        let lines = fs::read_to_string(tmp.path().join("user-repo.jsonl")).unwrap();
+        assert!(lines.lines().all(|l| l.contains(r#""file_id":"a.py""#)));
+        assert!(lines.contains(r#""body":"return 2""#));
+    }
+}
@@ -1,4 +1,8 @@
-use std::{fs, path::PathBuf, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use crate::error::DownloadError;
 use bytes::Bytes;
@@ -40,10 +44,10 @@ async fn are_equal(contents: Bytes, filepath: &str) -> Result<bool, DownloadErro
 }
 
 async fn download_repo_zip(
-    user: &String,
-    repo: &String,
+    user: &str,
+    repo: &str,
     branch: &str,
-    zip_dir: &PathBuf,
+    zip_dir: &Path,
 ) -> Result<PathBuf, DownloadError> {
     let client = reqwest::Client::new();
 
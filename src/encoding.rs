@@ -0,0 +1,117 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+
+// Same window Git uses to decide whether a blob is binary
+const BINARY_SNIFF_BYTES: usize = 8000;
+// Share of control characters above which a file is treated as binary
+const MAX_CONTROL_FRACTION: f64 = 0.1;
+
+pub enum Content {
+    Text {
+        text: String,
+        // WHATWG name of the source encoding ("UTF-8", "Shift_JIS", "UTF-16LE")
+        encoding: &'static str,
+    },
+    Binary {
+        rule: String,
+    },
+}
+
+fn is_control(b: u8) -> bool {
+    // Tab, LF, FF, CR and ESC show up in ordinary text files
+    (b < 0x20 && !matches!(b, b'\t' | b'\n' | 0x0c | b'\r' | 0x1b)) || b == 0x7f
+}
+
+fn binary_rule(bytes: &[u8]) -> Option<String> {
+    let head = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    if head.is_empty() {
+        return None;
+    }
+    if head.contains(&0) {
+        return Some(String::from("nul_byte"));
+    }
+    let control = head.iter().filter(|b| is_control(**b)).count() as f64 / head.len() as f64;
+    if control > MAX_CONTROL_FRACTION {
+        return Some(format!(
+            "control_chars>{MAX_CONTROL_FRACTION} ({control:.3})"
+        ));
+    }
+    None
+}
+
+fn decode_with(encoding: &'static Encoding, bytes: &[u8]) -> Content {
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+    if had_errors {
+        return Content::Binary {
+            rule: format!("undecodable:{}", encoding.name()),
+        };
+    }
+    Content::Text {
+        text: text.into_owned(),
+        encoding: encoding.name(),
+    }
+}
+
+// Decodes a file to UTF-8. A byte order mark is kept as U+FEFF so text
+// normalisation can decide whether to strip it.
+pub fn decode(bytes: &[u8]) -> Content {
+    // UTF-16 is full of NUL bytes, trust the BOM before sniffing for binaries
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        return match decode_with(encoding, &bytes[bom_len..]) {
+            Content::Text { text, encoding } => Content::Text {
+                text: format!("\u{feff}{text}"),
+                encoding,
+            },
+            binary => binary,
+        };
+    }
+    if let Some(rule) = binary_rule(bytes) {
+        return Content::Binary { rule };
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Content::Text {
+            text: text.to_owned(),
+            encoding: UTF_8.name(),
+        };
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    decode_with(detector.guess(None, true), bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{Content, decode};
+
+    fn decoded(bytes: &[u8]) -> (String, &'static str) {
+        match decode(bytes) {
+            Content::Text { text, encoding } => (text, encoding),
+            Content::Binary { rule } => panic!("unexpected binary: {rule}"),
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decoded(b"fn main() {}\n"),
+            ("fn main() {}\n".into(), "UTF-8")
+        );
+
+        // "// café" in Latin-1
+        let (text, encoding) = decoded(b"// caf\xe9 au lait, tr\xe8s bien\nint x;\n");
+        assert!(text.starts_with("// café"));
+        assert_eq!(encoding, "windows-1252");
+
+        // UTF-16LE with BOM
+        let utf16: Vec<u8> = [0xff, 0xfe]
+            .into_iter()
+            .chain("x = 1".encode_utf16().flat_map(|u| u.to_le_bytes()))
+            .collect();
+        assert_eq!(decoded(&utf16), ("\u{feff}x = 1".into(), "UTF-16LE"));
+
+        assert!(matches!(
+            decode(b"\x7fELF\x02\x01\x01\x00\x00"),
+            Content::Binary { .. }
+        ));
+    }
+}
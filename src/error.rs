@@ -27,12 +27,15 @@ pub enum ExtractionError {
     #[error("JSON lines error: {0}")]
     JsonlWriter(#[from] jsonl::WriteError),
 
-    #[error("Tokenizer error")]
+    #[error("Tokenizer error: {message}")]
     Tokenizer { message: String },
 
     #[error("Zip error")]
     ZipErr(#[from] zip::result::ZipError),
 
+    #[error("Regex error: {0}")]
+    Regex(#[from] regex::Error),
+
     #[error("Validation error: {message}")]
     Validation { message: String },
 }
@@ -54,3 +57,57 @@ pub enum ExactDedupError {
     #[error("Validation error: {message}")]
     Validation { message: String },
 }
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("Filesystem error {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unable to build DataFrame: {0}")]
+    Polars(#[from] polars::prelude::PolarsError),
+
+    #[error("Validation error: {message}")]
+    Validation { message: String },
+}
+
+#[derive(Debug, Error)]
+pub enum ConcatError {
+    #[error("Filesystem error {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Unable to write import graph: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+
+    #[error("Validation error: {message}")]
+    Validation { message: String },
+}
+
+#[derive(Debug, Error)]
+pub enum DefinitionError {
+    #[error("Filesystem error {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Validation error: {message}")]
+    Validation { message: String },
+}
+
+#[derive(Debug, Error)]
+pub enum AttributionError {
+    #[error("Filesystem error {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Archive(#[from] ExtractionError),
+}
+
+#[derive(Debug, Error)]
+pub enum FimError {
+    #[error("Filesystem error {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Tokenizer(#[from] ExtractionError),
+
+    #[error("Validation error: {message}")]
+    Validation { message: String },
+}
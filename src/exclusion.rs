@@ -0,0 +1,382 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use colored::Colorize;
+use polars::prelude::{Column, DataFrame, PolarsResult};
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use yaml_rust::YamlLoader;
+
+use crate::error::ExtractionError;
+use crate::gitattributes::Overrides;
+use crate::sink::{RecordSink, SinkOptions, Tabular};
+
+// Path patterns Linguist's generated.rb treats as generated code
+const GENERATED_PATHS: &[&str] = &[
+    r"\.(js|css)\.map$",
+    r"_pb2(_grpc)?\.py$",
+    r"\.pb\.(go|cc|h|swift)$",
+    r"(_grpc)?_pb\.(js|d\.ts)$",
+    r"\.pb\.gw\.go$",
+    r"\.designer\.(cs|vb)$",
+    r"(^|/)(package-lock\.json|npm-shrinkwrap\.json|yarn\.lock|pnpm-lock\.yaml)$",
+    r"(^|/)(Cargo\.lock|Gemfile\.lock|composer\.lock|poetry\.lock|Pipfile\.lock|go\.sum)$",
+    r"(^|/)\.pnp\.c?js$",
+    r"(^|/)[Gg]enerated/",
+];
+
+// Header markers searched in the first lines of a file. Only generator
+// headers, a bare "do not edit" is common in hand-written comments.
+const GENERATED_MARKERS: &[&str] = &[
+    "@generated",
+    "Generated by the protocol buffer compiler",
+    "This file was automatically generated",
+    "Autogenerated by Thrift",
+];
+// Tools of "Generated by <tool>" headers
+const GENERATOR_TOOLS: &[&str] = &[
+    "protoc",
+    "the gRPC",
+    "Thrift",
+    "SWIG",
+    "Cython",
+    "bindgen",
+    "cbindgen",
+    "flatc",
+    "ANTLR",
+    "Bison",
+    "GNU Bison",
+    "Django",
+    "OpenAPI Generator",
+    "Swagger Codegen",
+];
+// Go's convention, also used by generators of other languages
+static CODE_GENERATED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"Code generated .* DO NOT EDIT\.").unwrap());
+const GENERATED_HEADER_LINES: usize = 5;
+
+// Minified js/css: mean line length above this (same cut as Linguist)
+const MINIFIED_MEAN_LINE_LENGTH: usize = 110;
+// Any single line above this is treated as machine generated. Well above
+// the quality filter's max_line_length, which handles long hand-written lines.
+const GENERATED_MAX_LINE_LENGTH: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExclusionReason {
+    Vendored,
+    Documentation,
+    Generated,
+    Binary,
+    Unreadable,
+    Quality,
+    Syntax,
+    License,
+    Tokenizer,
+}
+
+impl ExclusionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExclusionReason::Vendored => "vendored",
+            ExclusionReason::Documentation => "documentation",
+            ExclusionReason::Generated => "generated",
+            ExclusionReason::Binary => "binary",
+            ExclusionReason::Unreadable => "unreadable",
+            ExclusionReason::Quality => "quality",
+            ExclusionReason::Syntax => "syntax",
+            ExclusionReason::License => "license",
+            ExclusionReason::Tokenizer => "tokenizer",
+        }
+    }
+}
+
+// One line of the audit log: which file was dropped and which rule matched
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Exclusion {
+    pub path: String,
+    pub reason: ExclusionReason,
+    pub rule: String,
+}
+
+impl Tabular for Exclusion {
+    fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame> {
+        DataFrame::new(vec![
+            Column::new(
+                "path".into(),
+                rows.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "reason".into(),
+                rows.iter().map(|e| e.reason.as_str()).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "rule".into(),
+                rows.iter().map(|e| e.rule.as_str()).collect::<Vec<_>>(),
+            ),
+        ])
+    }
+}
+
+struct PathRules {
+    patterns: Vec<String>,
+    set: RegexSet,
+}
+
+impl PathRules {
+    fn new(patterns: Vec<String>) -> Result<Self, ExtractionError> {
+        let set = RegexSet::new(&patterns)?;
+        Ok(Self { patterns, set })
+    }
+
+    fn from_linguist_yml(path: &Path) -> Result<Self, ExtractionError> {
+        let fc = fs::read_to_string(path)?;
+        let docs = YamlLoader::load_from_str(&fc)?;
+        let mut patterns = Vec::new();
+        if let Some(list) = docs.first().and_then(|d| d.as_vec()) {
+            for p in list.iter().filter_map(|p| p.as_str()) {
+                // Linguist regexes are Ruby flavoured, skip those we cannot compile
+                if let Err(e) = regex::Regex::new(p) {
+                    eprintln!(
+                        "{} Skipping rule {:?} from {}: {}",
+                        "[WARNING]".truecolor(214, 143, 0),
+                        p,
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+                patterns.push(p.to_owned());
+            }
+        }
+        Self::new(patterns)
+    }
+
+    fn first_match(&self, path: &str) -> Option<&str> {
+        let idx = self.set.matches(path).into_iter().next()?;
+        Some(&self.patterns[idx])
+    }
+}
+
+pub struct ExclusionRules {
+    vendor: PathRules,
+    documentation: PathRules,
+    generated: PathRules,
+}
+
+impl ExclusionRules {
+    pub fn load(vendor_path: &Path, documentation_path: &Path) -> Result<Self, ExtractionError> {
+        Ok(Self {
+            vendor: PathRules::from_linguist_yml(vendor_path)?,
+            documentation: PathRules::from_linguist_yml(documentation_path)?,
+            generated: PathRules::new(GENERATED_PATHS.iter().map(|p| p.to_string()).collect())?,
+        })
+    }
+
+    // Path based rules, checked before the file is read. Explicit
+    // .gitattributes overrides take precedence over Linguist's lists.
+    // `path` is relative to the repo root, as Linguist rules expect.
+    pub fn match_path(&self, path: &str, overrides: &Overrides) -> Option<Exclusion> {
+        let checks = [
+            (ExclusionReason::Vendored, overrides.vendored, &self.vendor),
+            (
+                ExclusionReason::Documentation,
+                overrides.documentation,
+                &self.documentation,
+            ),
+            (
+                ExclusionReason::Generated,
+                overrides.generated,
+                &self.generated,
+            ),
+        ];
+        for (reason, attr, rules) in checks {
+            let rule = match attr {
+                Some(true) => format!("gitattributes:linguist-{}", reason.as_str()),
+                Some(false) => continue,
+                None => match rules.first_match(path) {
+                    Some(rule) => rule.to_owned(),
+                    None => continue,
+                },
+            };
+            return Some(Exclusion {
+                path: path.to_owned(),
+                reason,
+                rule,
+            });
+        }
+        None
+    }
+
+    // Content based generated code detection
+    pub fn match_content(
+        &self,
+        path: &str,
+        text: &str,
+        overrides: &Overrides,
+    ) -> Option<Exclusion> {
+        if overrides.generated == Some(false) {
+            return None;
+        }
+        let rule = generated_content_rule(path, text)?;
+        Some(Exclusion {
+            path: path.to_owned(),
+            reason: ExclusionReason::Generated,
+            rule,
+        })
+    }
+}
+
+fn generated_content_rule(path: &str, text: &str) -> Option<String> {
+    for line in text.lines().take(GENERATED_HEADER_LINES) {
+        if let Some(marker) = GENERATED_MARKERS.iter().find(|m| line.contains(*m)) {
+            return Some(format!("header:{marker}"));
+        }
+        if CODE_GENERATED.is_match(line) {
+            return Some(String::from("header:Code generated DO NOT EDIT"));
+        }
+        if let Some(tool) = GENERATOR_TOOLS
+            .iter()
+            .find(|t| line.contains(&format!("Generated by {t}")))
+        {
+            return Some(format!("header:Generated by {tool}"));
+        }
+    }
+
+    let lengths: Vec<usize> = text.lines().map(|l| l.chars().count()).collect();
+    if lengths.is_empty() {
+        return None;
+    }
+    if path.ends_with(".js") || path.ends_with(".css") {
+        let mean = lengths.iter().sum::<usize>() / lengths.len();
+        if mean > MINIFIED_MEAN_LINE_LENGTH {
+            return Some(format!(
+                "minified:mean_line_length>{MINIFIED_MEAN_LINE_LENGTH}"
+            ));
+        }
+    }
+    if lengths.iter().any(|l| *l > GENERATED_MAX_LINE_LENGTH) {
+        return Some(format!("long_line>{GENERATED_MAX_LINE_LENGTH}"));
+    }
+    None
+}
+
+pub fn write_exclusions(
+    dest_dir: &Path,
+    file_name: &str,
+    exclusions: &[Exclusion],
+) -> Result<(), ExtractionError> {
+    // Audit logs stay JSONL whatever the output format
+    let excluded_dir = dest_dir.join("excluded");
+    // No log for repos without exclusions, nor a stale one from an earlier run
+    if exclusions.is_empty() {
+        return match fs::remove_file(excluded_dir.join(format!("{file_name}.jsonl"))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let mut sink = RecordSink::create(&excluded_dir, file_name, &SinkOptions::default())?;
+    for e in exclusions {
+        sink.write(e.clone())?;
+    }
+    sink.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::exclusion::{ExclusionReason, ExclusionRules};
+    use crate::gitattributes::Overrides;
+
+    fn rules() -> ExclusionRules {
+        let vendor = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("vendor");
+        ExclusionRules::load(
+            &vendor.join("vendor.yml"),
+            &vendor.join("documentation.yml"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_match_path() {
+        let rules = rules();
+        let reason = |p: &str| rules.match_path(p, &Overrides::default()).map(|e| e.reason);
+
+        assert_eq!(
+            reason("node_modules/lodash/index.js"),
+            Some(ExclusionReason::Vendored)
+        );
+        assert_eq!(
+            reason("third_party/zlib/inflate.c"),
+            Some(ExclusionReason::Vendored)
+        );
+        assert_eq!(reason("static/app.min.js"), Some(ExclusionReason::Vendored));
+        assert_eq!(reason("docs/conf.py"), Some(ExclusionReason::Documentation));
+        assert_eq!(
+            reason("proto/user_pb2.py"),
+            Some(ExclusionReason::Generated)
+        );
+        assert_eq!(reason("src/docs.rs"), None);
+        assert_eq!(reason("src/main.rs"), None);
+
+        let keep = Overrides {
+            vendored: Some(false),
+            ..Default::default()
+        };
+        let vendored = Overrides {
+            vendored: Some(true),
+            ..Default::default()
+        };
+        assert!(rules.match_path("vendor/lib.js", &keep).is_none());
+        assert_eq!(
+            rules.match_path("src/lib.js", &vendored).unwrap().rule,
+            "gitattributes:linguist-vendored"
+        );
+    }
+
+    #[test]
+    fn test_match_content() {
+        let rules = rules();
+        let header = "// Code generated by protoc-gen-go. DO NOT EDIT.\npackage pb\n";
+        let minified = format!("var a={};\n", "b".repeat(200));
+
+        let rule = |p: &str, t: &str| {
+            rules
+                .match_content(p, t, &Overrides::default())
+                .map(|e| e.rule)
+        };
+        assert_eq!(
+            rule("api.go", header).as_deref(),
+            Some("header:Code generated DO NOT EDIT")
+        );
+        assert_eq!(
+            rule("lexer.c", "/* Generated by GNU Bison 3.8. */\nint x;\n").as_deref(),
+            Some("header:Generated by GNU Bison")
+        );
+        // Hand-written warnings are not generator headers
+        let warning = "# Do not edit this constant without updating the docs\nLIMIT = 10\n";
+        assert_eq!(rule("limits.py", warning), None);
+        assert_eq!(
+            rule("main.go", "// DO NOT EDIT by hand\npackage main\n"),
+            None
+        );
+        assert_eq!(rule("app.py", "# Generated by hand\nx = 1\n"), None);
+        assert!(
+            rule("bundle.js", &minified)
+                .unwrap()
+                .starts_with("minified")
+        );
+        assert_eq!(rule("main.go", "package main\n\nfunc main() {}\n"), None);
+        // Long lines are left to the quality filter up to the generated cut
+        let table = |n: usize| format!("data = [{}]\n", "1,".repeat(n));
+        assert_eq!(rule("table.py", &table(1000)), None);
+        assert_eq!(
+            rule("table.py", &table(10_000)).as_deref(),
+            Some("long_line>10000")
+        );
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use polars::prelude::{DataType, Field};
+use rayon::prelude::*;
+use spdx::LicenseItem;
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlEmitter};
+
+use crate::error::ExportError;
+use crate::exclusion::Exclusion;
+use crate::extractor::{FilterSettings, Record};
+use crate::shard::{SHARDS_DIR, ShardIndex, ShardOptions, ShardedSink};
+use crate::sink::{Format, SinkOptions, Tabular, read_records};
+
+const CARD_FILE: &str = "README.md";
+const TRAIN_SPLIT: &str = "train";
+const VALIDATION_SPLIT: &str = "validation";
+
+// Hugging Face license ids of the SPDX licenses detected at extraction,
+// GNU ids lose their "-only"/"-or-later" suffix
+const HF_LICENSES: &[(&str, &str)] = &[
+    ("AGPL-3.0", "agpl-3.0"),
+    ("Apache-2.0", "apache-2.0"),
+    ("Artistic-2.0", "artistic-2.0"),
+    ("BSD-2-Clause", "bsd-2-clause"),
+    ("BSD-3-Clause", "bsd-3-clause"),
+    ("BSL-1.0", "bsl-1.0"),
+    ("CC-BY-4.0", "cc-by-4.0"),
+    ("CC-BY-SA-4.0", "cc-by-sa-4.0"),
+    ("CC0-1.0", "cc0-1.0"),
+    ("EPL-1.0", "epl-1.0"),
+    ("EPL-2.0", "epl-2.0"),
+    ("GPL-2.0", "gpl-2.0"),
+    ("GPL-3.0", "gpl-3.0"),
+    ("ISC", "isc"),
+    ("LGPL-2.1", "lgpl-2.1"),
+    ("LGPL-3.0", "lgpl-3.0"),
+    ("MIT", "mit"),
+    ("MPL-2.0", "mpl-2.0"),
+    ("MS-PL", "ms-pl"),
+    ("NCSA", "ncsa"),
+    ("OFL-1.1", "ofl-1.1"),
+    ("PostgreSQL", "postgresql"),
+    ("Unlicense", "unlicense"),
+    ("WTFPL", "wtfpl"),
+    ("Zlib", "zlib"),
+];
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub name: String,
+    // Share of repositories held out as the validation split
+    pub validation_fraction: f64,
+    pub shard_bytes: u64,
+    pub row_group_size: usize,
+    // Extraction output holding the exclusion logs
+    pub jsonl_dir: PathBuf,
+    // The input went through exact deduplication
+    pub deduplicated: bool,
+}
+
+// Whole repositories go to one split, so files of a repository never
+// leak from train into validation. Stable across runs and machines.
+fn split_of(repo_name: &str, validation_fraction: f64) -> &'static str {
+    let digest = md5::compute(repo_name.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    let position = u64::from_be_bytes(bytes) as f64 / u64::MAX as f64;
+    if position < validation_fraction {
+        VALIDATION_SPLIT
+    } else {
+        TRAIN_SPLIT
+    }
+}
+
+#[derive(Default)]
+struct LanguageStats {
+    files: usize,
+    bytes: u64,
+    tokens: BTreeMap<String, usize>,
+}
+
+#[derive(Default)]
+struct RepoStats {
+    branch: String,
+    commit_sha: Option<String>,
+    split: &'static str,
+    files: usize,
+}
+
+// Everything the dataset card reports, gathered while writing the shards
+#[derive(Default)]
+struct ExportStats {
+    languages: BTreeMap<String, LanguageStats>,
+    repos: BTreeMap<String, RepoStats>,
+    normalizations: BTreeMap<String, usize>,
+    tokenizers: Vec<String>,
+    // Files per license expression, and files without a license
+    licenses: BTreeMap<String, usize>,
+    unlicensed: usize,
+}
+
+impl ExportStats {
+    fn add(&mut self, record: &Record, split: &'static str) {
+        let language = self.languages.entry(record.language.clone()).or_default();
+        language.files += 1;
+        language.bytes += record.text.len() as u64;
+        for (name, count) in &record.tokens {
+            *language.tokens.entry(name.clone()).or_default() += count;
+            if !self.tokenizers.contains(name) {
+                self.tokenizers.push(name.clone());
+            }
+        }
+        let repo = self.repos.entry(record.repo_name.clone()).or_default();
+        if repo.files == 0 {
+            repo.branch = record.branch.clone();
+            repo.commit_sha = record.commit_sha.clone();
+            repo.split = split;
+        }
+        repo.files += 1;
+        for n in &record.normalizations {
+            *self.normalizations.entry(n.clone()).or_default() += 1;
+        }
+        match &record.license {
+            Some(l) => *self.licenses.entry(l.clone()).or_default() += 1,
+            None => self.unlicensed += 1,
+        }
+    }
+
+    // Hugging Face ids of every license in the dataset, None when a file
+    // has no license or one without a Hugging Face id
+    fn hf_licenses(&self) -> Option<BTreeSet<&'static str>> {
+        if self.unlicensed > 0 || self.licenses.is_empty() {
+            return None;
+        }
+        let mut ids = BTreeSet::new();
+        for license in self.licenses.keys() {
+            let expr = spdx::Expression::parse(license).ok()?;
+            for req in expr.requirements() {
+                let LicenseItem::Spdx { id, .. } = req.req.license else {
+                    return None;
+                };
+                let name = id
+                    .name
+                    .trim_end_matches("-only")
+                    .trim_end_matches("-or-later");
+                let (_, hf) = HF_LICENSES.iter().find(|(spdx, _)| *spdx == name)?;
+                ids.insert(*hf);
+            }
+        }
+        Some(ids)
+    }
+}
+
+// Writes `paths` as a Hugging Face dataset: Parquet shards under "data/"
+// and a README.md dataset card whose front matter declares the configs,
+// features and splits `datasets.load_dataset` reads
+pub fn export_hf(
+    paths: &[PathBuf],
+    repos: Option<&HashSet<String>>,
+    dest_dir: &Path,
+    opts: &ExportOptions,
+) -> Result<usize, ExportError> {
+    let shards = ShardOptions::new(Some(opts.shard_bytes), None, false).ok_or_else(|| {
+        ExportError::Validation {
+            message: String::from("Shard size is required"),
+        }
+    })?;
+    let output = SinkOptions {
+        format: Format::Parquet,
+        row_group_size: opts.row_group_size,
+    };
+    let mut sink = ShardedSink::create(dest_dir, &shards, &output)?;
+    let mut stats = ExportStats::default();
+
+    // Inputs are read in parallel batches and written in path order
+    for batch in paths.chunks(rayon::current_num_threads() * 2) {
+        let records: Vec<Vec<Record>> = batch
+            .par_iter()
+            .map(|path| read_records(path))
+            .collect::<Result<_, _>>()?;
+        for record in records.into_iter().flatten() {
+            if let Some(hs) = repos
+                && !record.repo_name.is_empty()
+                && !hs.contains(&record.repo_name.replace('/', "-"))
+            {
+                continue;
+            }
+            let split = split_of(&record.repo_name, opts.validation_fraction);
+            stats.add(&record, split);
+            sink.write_split(split, record)?;
+        }
+    }
+    let index = sink.finish()?;
+
+    let exclusions = read_exclusions(&opts.jsonl_dir.join("excluded"), &stats.repos);
+    let filters = FilterSettings::load(&opts.jsonl_dir);
+    let card = dataset_card(
+        dest_dir,
+        opts,
+        &index,
+        &stats,
+        &exclusions,
+        filters.as_ref(),
+    )?;
+    let tmp_path = dest_dir.join(format!("{CARD_FILE}.tmp"));
+    fs::write(&tmp_path, card)?;
+    fs::rename(tmp_path, dest_dir.join(CARD_FILE))?;
+    Ok(index.records())
+}
+
+// Exclusion counts per reason from the audit logs of the exported repos
+fn read_exclusions(
+    excluded_dir: &Path,
+    repos: &BTreeMap<String, RepoStats>,
+) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for repo_name in repos.keys() {
+        let path = excluded_dir.join(format!("{}.jsonl", repo_name.replace('/', "-")));
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        let mut reader = BufReader::new(file);
+        while let Ok(e) = jsonl::read::<_, Exclusion>(&mut reader) {
+            *counts.entry(e.reason.as_str().to_owned()).or_default() += 1;
+        }
+    }
+    counts
+}
+
+// Hugging Face feature of a Parquet column type
+fn feature_type(dtype: &DataType) -> (&'static str, Yaml) {
+    match dtype {
+        DataType::List(inner) => match inner.as_ref() {
+            DataType::Struct(fields) => ("list", struct_features(fields)),
+            inner => ("sequence", feature_type(inner).1),
+        },
+        DataType::Struct(fields) => ("struct", struct_features(fields)),
+        DataType::UInt64 => ("dtype", Yaml::from_str("uint64")),
+        DataType::UInt32 => ("dtype", Yaml::from_str("uint32")),
+        DataType::Int64 => ("dtype", Yaml::from_str("int64")),
+        DataType::Int32 => ("dtype", Yaml::from_str("int32")),
+        DataType::Float64 => ("dtype", Yaml::from_str("float64")),
+        DataType::Boolean => ("dtype", Yaml::from_str("bool")),
+        _ => ("dtype", Yaml::String(String::from("string"))),
+    }
+}
+
+fn struct_features(fields: &[Field]) -> Yaml {
+    Yaml::Array(
+        fields
+            .iter()
+            .map(|f| feature(f.name().as_str(), f.dtype()))
+            .collect(),
+    )
+}
+
+fn feature(name: &str, dtype: &DataType) -> Yaml {
+    let (key, value) = feature_type(dtype);
+    yaml_hash(vec![("name", Yaml::String(name.to_owned())), (key, value)])
+}
+
+fn yaml_hash(entries: Vec<(&str, Yaml)>) -> Yaml {
+    let mut hash = Hash::new();
+    for (k, v) in entries {
+        hash.insert(Yaml::String(k.to_owned()), v);
+    }
+    Yaml::Hash(hash)
+}
+
+fn size_category(records: usize) -> &'static str {
+    match records {
+        0..1_000 => "n<1K",
+        1_000..10_000 => "1K<n<10K",
+        10_000..100_000 => "10K<n<100K",
+        100_000..1_000_000 => "100K<n<1M",
+        1_000_000..10_000_000 => "1M<n<10M",
+        10_000_000..100_000_000 => "10M<n<100M",
+        100_000_000..1_000_000_000 => "100M<n<1B",
+        _ => "n>1B",
+    }
+}
+
+// YAML front matter of the card, read by the hub and `load_dataset`
+fn front_matter(
+    dest_dir: &Path,
+    opts: &ExportOptions,
+    index: &ShardIndex,
+    stats: &ExportStats,
+) -> Result<String, ExportError> {
+    let schema = Record::to_dataframe(&[])?.schema().clone();
+    let features: Vec<Yaml> = schema.iter().map(|(n, d)| feature(n, d)).collect();
+
+    let mut splits: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut download_size = 0;
+    for shard in &index.shards {
+        *splits.entry(shard.split.as_str()).or_default() += shard.records;
+        download_size += fs::metadata(dest_dir.join(&shard.file))?.len();
+    }
+    let data_files = splits
+        .keys()
+        .map(|split| {
+            yaml_hash(vec![
+                ("split", Yaml::from_str(split)),
+                ("path", Yaml::String(format!("{SHARDS_DIR}/{split}-*"))),
+            ])
+        })
+        .collect();
+    let split_infos = splits
+        .iter()
+        .map(|(split, records)| {
+            yaml_hash(vec![
+                ("name", Yaml::from_str(split)),
+                ("num_examples", Yaml::Integer(*records as i64)),
+            ])
+        })
+        .collect();
+
+    let doc = yaml_hash(vec![
+        ("pretty_name", Yaml::String(opts.name.clone())),
+        (
+            "license",
+            match stats.hf_licenses() {
+                Some(ids) => Yaml::Array(ids.into_iter().map(Yaml::from_str).collect()),
+                None => Yaml::from_str("other"),
+            },
+        ),
+        ("tags", Yaml::Array(vec![Yaml::from_str("code")])),
+        (
+            "size_categories",
+            Yaml::Array(vec![Yaml::String(
+                size_category(index.records()).to_owned(),
+            )]),
+        ),
+        (
+            "configs",
+            Yaml::Array(vec![yaml_hash(vec![
+                ("config_name", Yaml::from_str("default")),
+                ("data_files", Yaml::Array(data_files)),
+            ])]),
+        ),
+        (
+            "dataset_info",
+            yaml_hash(vec![
+                ("features", Yaml::Array(features)),
+                ("splits", Yaml::Array(split_infos)),
+                ("download_size", Yaml::Integer(download_size as i64)),
+            ]),
+        ),
+    ]);
+    let mut out = String::new();
+    YamlEmitter::new(&mut out)
+        .dump(&doc)
+        .map_err(|e| ExportError::Validation {
+            message: format!("Unable to write the card front matter: {e:?}"),
+        })?;
+    Ok(out)
+}
+
+// Extraction settings, as the list the "Filters applied" section opens with
+fn write_filters(card: &mut String, f: &FilterSettings) {
+    let _ = writeln!(card, "- Files over {} bytes were skipped.", f.max_file_size);
+    match &f.quality {
+        Some(sections) => {
+            card.push_str("- Quality thresholds:\n");
+            for (section, thresholds) in sections {
+                let _ = writeln!(card, "  - {section}: {thresholds}");
+            }
+        }
+        None => card.push_str("- The quality filter was disabled.\n"),
+    }
+    match &f.licenses {
+        Some(ids) => {
+            let _ = writeln!(card, "- Licenses allowed: {}.", ids.join(", "));
+        }
+        None => card.push_str("- Files of every license were kept.\n"),
+    }
+    if !f.normalize.is_empty() {
+        let _ = writeln!(card, "- Normalizations: {}.", f.normalize.join(", "));
+    }
+    if let Some(syntax) = &f.syntax {
+        let _ = writeln!(card, "- Syntax check: {syntax}.");
+    }
+    if f.keep_docs {
+        card.push_str("- Documentation and example files were kept and tagged.\n");
+    }
+}
+
+// The README.md of the exported directory: front matter and the card body
+fn dataset_card(
+    dest_dir: &Path,
+    opts: &ExportOptions,
+    index: &ShardIndex,
+    stats: &ExportStats,
+    exclusions: &BTreeMap<String, usize>,
+    filters: Option<&FilterSettings>,
+) -> Result<String, ExportError> {
+    let mut card = front_matter(dest_dir, opts, index, stats)?;
+    card.push_str("\n---\n\n");
+    let _ = writeln!(card, "# {}\n", opts.name);
+    let _ = writeln!(
+        card,
+        "Source code files of {} repositories, curated with codecurator. \
+         {} files in {} Parquet shards.\n",
+        stats.repos.len(),
+        index.records(),
+        index.shards.len()
+    );
+    let _ = writeln!(
+        card,
+        "```python\nfrom datasets import load_dataset\n\nds = load_dataset(\"path/to/this/dataset\")\n```\n"
+    );
+
+    card.push_str("## Splits\n\n| Split | Files | Shards |\n|---|---:|---:|\n");
+    let mut splits: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for shard in &index.shards {
+        let s = splits.entry(shard.split.as_str()).or_default();
+        s.0 += shard.records;
+        s.1 += 1;
+    }
+    for (split, (records, shards)) in &splits {
+        let _ = writeln!(card, "| {split} | {records} | {shards} |");
+    }
+    if opts.validation_fraction > 0.0 {
+        let _ = writeln!(
+            card,
+            "\nRepositories are assigned whole to a split, {:.1}% of them to validation.",
+            opts.validation_fraction * 100.0
+        );
+    }
+
+    card.push_str("\n## Languages\n\n| Language | Files | Bytes |");
+    for name in &stats.tokenizers {
+        let _ = write!(card, " Tokens ({name}) |");
+    }
+    card.push_str("\n|---|---:|---:|");
+    card.push_str(&"---:|".repeat(stats.tokenizers.len()));
+    card.push('\n');
+    for (language, s) in &stats.languages {
+        let _ = write!(card, "| {language} | {} | {} |", s.files, s.bytes);
+        for name in &stats.tokenizers {
+            let _ = write!(card, " {} |", s.tokens.get(name).unwrap_or(&0));
+        }
+        card.push('\n');
+    }
+
+    card.push_str("\n## Source repositories\n\n| Repository | Branch | Commit | Split | Files |\n|---|---|---|---|---:|\n");
+    for (repo_name, s) in &stats.repos {
+        let _ = writeln!(
+            card,
+            "| [{repo_name}](https://github.com/{repo_name}) | {} | {} | {} | {} |",
+            s.branch,
+            s.commit_sha.as_deref().unwrap_or("-"),
+            s.split,
+            s.files
+        );
+    }
+
+    card.push_str("\n## Filters applied\n\n");
+    match filters {
+        Some(f) => write_filters(&mut card, f),
+        None => card.push_str("The extraction settings were not recorded.\n"),
+    }
+    card.push('\n');
+    if exclusions.is_empty() {
+        card.push_str("No exclusion logs were found for these repositories.\n");
+    } else {
+        card.push_str("Files excluded during extraction:\n\n| Reason | Files |\n|---|---:|\n");
+        for (reason, count) in exclusions {
+            let _ = writeln!(card, "| {reason} | {count} |");
+        }
+    }
+    if !stats.normalizations.is_empty() {
+        card.push_str("\nText normalizations, by files they changed:\n\n| Normalization | Files |\n|---|---:|\n");
+        for (n, count) in &stats.normalizations {
+            let _ = writeln!(card, "| {n} | {count} |");
+        }
+    }
+    if opts.deduplicated {
+        card.push_str(
+            "\nExact duplicates were removed across all repositories by the MD5 hash \
+             of their text, keeping the first copy.\n",
+        );
+    } else {
+        card.push_str("\nDuplicates were not removed.\n");
+    }
+
+    card.push_str("\n## Licenses\n\n");
+    if stats.licenses.is_empty() {
+        card.push_str(
+            "File licenses were not detected. Each file keeps the license of \
+             its source repository, check them before redistributing.\n",
+        );
+        return Ok(card);
+    }
+    card.push_str(
+        "File licenses come from their SPDX-License-Identifier header, else from the \
+         license files of their repository. NOASSERTION marks license files that match \
+         no known license. Check the terms before redistributing.\n\n\
+         | License | Files |\n|---|---:|\n",
+    );
+    for (license, count) in &stats.licenses {
+        let _ = writeln!(card, "| {license} | {count} |");
+    }
+    if stats.unlicensed > 0 {
+        let _ = writeln!(card, "| none | {} |", stats.unlicensed);
+    }
+    Ok(card)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use polars::prelude::{DataType, Field};
+    use yaml_rust::{YamlEmitter, YamlLoader};
+
+    use crate::exclusion::{Exclusion, ExclusionReason, write_exclusions};
+    use crate::export::{ExportOptions, export_hf, feature, split_of};
+    use crate::extractor::{FilterSettings, Record};
+    use crate::quality::QualityFilter;
+    use crate::shard::read_index;
+    use crate::sink::{RecordSink, SinkOptions, read_records};
+
+    #[test]
+    fn test_export_hf() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (input_dir, jsonl_dir, hf_dir) = (
+            tmp.path().join("exact"),
+            tmp.path().join("jsonl"),
+            tmp.path().join("hf"),
+        );
+        let mut paths = Vec::new();
+        for repo in ["user/a", "user/b"] {
+            let name = repo.replace('/', "-");
+            let mut sink = RecordSink::create(&input_dir, &name, &SinkOptions::default()).unwrap();
+            for path in ["main.py", "util.py"] {
+                let mut r = Record::for_test(repo, path, "Python", "x = 1\n");
+                r.license = Some(String::from("MIT"));
+                sink.write(r).unwrap();
+            }
+            sink.finish().unwrap();
+            paths.push(input_dir.join(format!("{name}.jsonl")));
+        }
+        let exclusion = Exclusion {
+            path: String::from("data.py"),
+            reason: ExclusionReason::Quality,
+            rule: String::from("max_line_length>1000 (5000)"),
+        };
+        write_exclusions(&jsonl_dir, "user-a", &[exclusion]).unwrap();
+        let filters = FilterSettings {
+            max_file_size: 131072,
+            quality: Some(QualityFilter::builtin().sections()),
+            licenses: Some(vec![String::from("Apache-2.0"), String::from("MIT")]),
+            ..Default::default()
+        };
+        filters.write(&jsonl_dir).unwrap();
+
+        let opts = ExportOptions {
+            name: String::from("test-code"),
+            validation_fraction: 0.0,
+            shard_bytes: 1024,
+            row_group_size: 10,
+            jsonl_dir,
+            deduplicated: true,
+        };
+        assert_eq!(export_hf(&paths, None, &hf_dir, &opts).unwrap(), 4);
+        let shards = read_index(&hf_dir).unwrap();
+        let records: usize = shards.iter().map(|p| read_records(p).unwrap().len()).sum();
+        assert_eq!(records, 4);
+
+        let card = fs::read_to_string(hf_dir.join("README.md")).unwrap();
+        let (front_matter, body) = card
+            .strip_prefix("---\n")
+            .unwrap()
+            .split_once("\n---\n")
+            .unwrap();
+        let doc = &YamlLoader::load_from_str(front_matter).unwrap()[0];
+        assert_eq!(doc["pretty_name"].as_str(), Some("test-code"));
+        assert_eq!(doc["license"][0].as_str(), Some("mit"));
+        let split = &doc["dataset_info"]["splits"][0];
+        assert_eq!(split["name"].as_str(), Some("train"));
+        assert_eq!(split["num_examples"].as_i64(), Some(4));
+        assert_eq!(
+            doc["configs"][0]["data_files"][0]["path"].as_str(),
+            Some("data/train-*")
+        );
+
+        assert!(body.contains("| train | 4 | 1 |"));
+        let filters = &body[body.find("## Filters applied").unwrap()..];
+        assert!(filters.contains("- Files over 131072 bytes were skipped."));
+        assert!(filters.contains("  - default: max_line_length=1000, max_mean_line_length=100"));
+        assert!(filters.contains("- Licenses allowed: Apache-2.0, MIT."));
+        assert!(filters.contains("| quality | 1 |"));
+        assert!(filters.contains("Exact duplicates were removed"));
+    }
+
+    #[test]
+    fn test_split_of() {
+        assert_eq!(split_of("user/repo", 0.0), "train");
+        assert_eq!(split_of("user/repo", 1.0), "validation");
+        let held_out = (0..1000)
+            .filter(|i| split_of(&format!("user/repo{i}"), 0.1) == "validation")
+            .count();
+        assert!((50..150).contains(&held_out));
+    }
+
+    #[test]
+    fn test_feature() {
+        let dtype = DataType::List(Box::new(DataType::Struct(vec![
+            Field::new("tokenizer".into(), DataType::String),
+            Field::new("count".into(), DataType::UInt64),
+        ])));
+        let mut out = String::new();
+        YamlEmitter::new(&mut out)
+            .dump(&feature("tokens", &dtype))
+            .unwrap();
+        assert_eq!(
+            out,
+            "---\nname: tokens\nlist:\n  - name: tokenizer\n    dtype: string\n  - name: count\n    dtype: uint64"
+        );
+    }
+}
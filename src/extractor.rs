@@ -1,40 +1,622 @@
+use crate::chunk::{ChunkOptions, chunk_records};
+use crate::encoding::{Content, decode};
 use crate::error::ExtractionError;
+use crate::exclusion::{Exclusion, ExclusionReason, ExclusionRules, write_exclusions};
+use crate::file_class::{FileClass, classify, link_tests};
+use crate::gitattributes::{GitAttributes, Overrides};
+use crate::license::{NOASSERTION, file_license, is_allowed, license_files, repo_license};
+use crate::metrics::Metrics;
+use crate::normalize::Normalizer;
+use crate::notebook::{
+    MAX_NOTEBOOK_SIZE, NOTEBOOK_EXTENSION, NOTEBOOK_LANGUAGE, NotebookOptions, to_script,
+};
+use crate::quality::QualityFilter;
+use crate::shard::{ShardOptions, ShardedSink, remove_index};
+use crate::sink::{RecordSink, SinkOptions, Tabular};
+use crate::syntax::{SyntaxCheck, SyntaxMode, syntax_errors};
+use crate::tokenization::Tokenizers;
 
 use colored::Colorize;
+use polars::prelude::{
+    Column, DataFrame, DataType, Field, IntoSeries, NamedFrom, PolarsResult, Series, StructChunked,
+};
 use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use std::io::{BufReader, Write};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::io::BufReader;
 use std::{
-    collections::HashMap,
-    fs::{self, File, OpenOptions},
-    io::{BufWriter, Read},
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
+    io::Read,
     path::{Path, PathBuf},
     sync::Arc,
 };
-use tokenizers::Tokenizer;
 use uuid::Uuid;
 use zip::ZipArchive;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Settings of an extraction run, written next to its output
+pub const FILTERS_FILE: &str = "filters.json";
+// Zip entries read per rayon task, so a single huge repo uses the whole pool
+const ENTRIES_PER_TASK: usize = 256;
+// Files per `encode_batch` call
+const TOKENIZE_BATCH_SIZE: usize = 512;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Record {
     pub text: String,
     pub id: String,
+    #[serde(default)]
+    pub content_hash: String,
+    // "<user>/<repo>"
+    #[serde(default)]
+    pub repo_name: String,
+    #[serde(default)]
+    pub branch: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit_sha: Option<String>,
+    // SPDX expression of the file's SPDX-License-Identifier header, else of
+    // the repository license files, NOASSERTION when they match no known
+    // license. None without either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
     file_extension: String,
     category: String,
+    // source, test, example, benchmark, docs or build
+    #[serde(default)]
+    pub file_class: String,
+    // Source file a test exercises, with --link-tests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_subject: Option<String>,
     pub path: String,
     size_in_bytes: u64,
     file_name: String,
-    tokens: usize,
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    encoding: String,
+    // Text transforms that changed the stored text
+    #[serde(default)]
+    pub normalizations: Vec<String>,
+    // Token count per tokenizer name, {"gpt2": 812, "llama3": 640}.
+    // Omitted when extracting with --no-tokenize.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "deserialize_tokens"
+    )]
+    pub tokens: BTreeMap<String, usize>,
+    // Files longer than --chunk-tokens are split into windows sharing the
+    // id of the whole file as parent id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_count: Option<u32>,
+    // Byte offset of the window in the whole file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_offset: Option<u64>,
+    // Parse errors found by --syntax-check, None without a grammar
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syntax_errors: Option<u32>,
+    // Code metrics of the stored text, see `Metrics`
+    #[serde(default)]
+    pub lines: u64,
+    #[serde(default)]
+    pub code_lines: u64,
+    #[serde(default)]
+    pub comment_lines: u64,
+    #[serde(default)]
+    pub blank_lines: u64,
+    #[serde(default)]
+    pub max_line_length: u64,
+    #[serde(default)]
+    pub avg_line_length: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complexity: Option<u64>,
+}
+
+// Records written before multiple tokenizers carry a single GPT-2 count
+fn deserialize_tokens<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TokenCounts {
+        PerTokenizer(BTreeMap<String, usize>),
+        Gpt2(usize),
+    }
+    Ok(match TokenCounts::deserialize(deserializer)? {
+        TokenCounts::PerTokenizer(counts) => counts,
+        TokenCounts::Gpt2(n) => BTreeMap::from([(String::from("gpt2"), n)]),
+    })
+}
+
+// Parquet column type of `tokens`, a list of {tokenizer, count} pairs
+fn tokens_dtype() -> DataType {
+    DataType::List(Box::new(DataType::Struct(vec![
+        Field::new("tokenizer".into(), DataType::String),
+        Field::new("count".into(), DataType::UInt64),
+    ])))
+}
+
+// Stable Parquet schema: one column per field, in declaration order
+impl Tabular for Record {
+    fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame> {
+        let str_column = |name: &str, f: fn(&Record) -> &str| {
+            Column::new(name.into(), rows.iter().map(f).collect::<Vec<_>>())
+        };
+        let u64_column = |name: &str, f: fn(&Record) -> u64| {
+            Column::new(name.into(), rows.iter().map(f).collect::<Vec<_>>())
+        };
+        let normalizations: Vec<Series> = rows
+            .iter()
+            .map(|r| Series::new("".into(), &r.normalizations))
+            .collect();
+        let tokens = rows
+            .iter()
+            .map(|r| {
+                let names = Series::new(
+                    "tokenizer".into(),
+                    r.tokens.keys().map(String::as_str).collect::<Vec<_>>(),
+                );
+                let counts: Vec<u64> = r.tokens.values().map(|c| *c as u64).collect();
+                let counts = Series::new("count".into(), counts);
+                let fields = [names.cast(&DataType::String)?, counts];
+                Ok(
+                    StructChunked::from_series("".into(), r.tokens.len(), fields.iter())?
+                        .into_series(),
+                )
+            })
+            .collect::<PolarsResult<Vec<Series>>>()?;
+
+        DataFrame::new(vec![
+            str_column("text", |r| &r.text),
+            str_column("id", |r| &r.id),
+            str_column("content_hash", |r| &r.content_hash),
+            str_column("repo_name", |r| &r.repo_name),
+            str_column("branch", |r| &r.branch),
+            Column::new(
+                "commit_sha".into(),
+                rows.iter()
+                    .map(|r| r.commit_sha.as_deref())
+                    .collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "license".into(),
+                rows.iter()
+                    .map(|r| r.license.as_deref())
+                    .collect::<Vec<_>>(),
+            )
+            .cast(&DataType::String)?,
+            str_column("file_extension", |r| &r.file_extension),
+            str_column("category", |r| &r.category),
+            str_column("file_class", |r| &r.file_class),
+            Column::new(
+                "test_subject".into(),
+                rows.iter()
+                    .map(|r| r.test_subject.as_deref())
+                    .collect::<Vec<_>>(),
+            )
+            .cast(&DataType::String)?,
+            str_column("path", |r| &r.path),
+            Column::new(
+                "size_in_bytes".into(),
+                rows.iter().map(|r| r.size_in_bytes).collect::<Vec<_>>(),
+            ),
+            str_column("file_name", |r| &r.file_name),
+            str_column("language", |r| &r.language),
+            str_column("encoding", |r| &r.encoding),
+            Column::new("normalizations".into(), normalizations)
+                .cast(&DataType::List(Box::new(DataType::String)))?,
+            Column::new("tokens".into(), tokens).cast(&tokens_dtype())?,
+            Column::new(
+                "parent_id".into(),
+                rows.iter()
+                    .map(|r| r.parent_id.as_deref())
+                    .collect::<Vec<_>>(),
+            )
+            .cast(&DataType::String)?,
+            Column::new(
+                "chunk_index".into(),
+                rows.iter().map(|r| r.chunk_index).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "chunk_count".into(),
+                rows.iter().map(|r| r.chunk_count).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "chunk_offset".into(),
+                rows.iter().map(|r| r.chunk_offset).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "syntax_errors".into(),
+                rows.iter().map(|r| r.syntax_errors).collect::<Vec<_>>(),
+            ),
+            u64_column("lines", |r| r.lines),
+            u64_column("code_lines", |r| r.code_lines),
+            u64_column("comment_lines", |r| r.comment_lines),
+            u64_column("blank_lines", |r| r.blank_lines),
+            u64_column("max_line_length", |r| r.max_line_length),
+            Column::new(
+                "avg_line_length".into(),
+                rows.iter().map(|r| r.avg_line_length).collect::<Vec<_>>(),
+            ),
+            Column::new(
+                "complexity".into(),
+                rows.iter().map(|r| r.complexity).collect::<Vec<_>>(),
+            ),
+        ])
+    }
+}
+
+impl Record {
+    // Inverse of `Tabular::to_dataframe`, used to read Parquet outputs back
+    pub fn from_dataframe(df: &DataFrame) -> PolarsResult<Vec<Self>> {
+        let str_column = |name: &str| -> PolarsResult<Vec<String>> {
+            Ok(df
+                .column(name)?
+                .str()?
+                .into_iter()
+                .map(|v| v.unwrap_or_default().to_owned())
+                .collect())
+        };
+        let mut text = str_column("text")?.into_iter();
+        let mut id = str_column("id")?.into_iter();
+        let mut content_hash = str_column("content_hash")?.into_iter();
+        let mut repo_name = str_column("repo_name")?.into_iter();
+        let mut branch = str_column("branch")?.into_iter();
+        let mut file_extension = str_column("file_extension")?.into_iter();
+        let mut category = str_column("category")?.into_iter();
+        let mut path = str_column("path")?.into_iter();
+        let mut file_name = str_column("file_name")?.into_iter();
+        let mut language = str_column("language")?.into_iter();
+        let mut encoding = str_column("encoding")?.into_iter();
+        let commit_sha = df.column("commit_sha")?.str()?;
+        let size_in_bytes = df.column("size_in_bytes")?.u64()?;
+        let normalizations = df.column("normalizations")?.list()?;
+        let tokens = df.column("tokens")?.list()?;
+        // Columns added after the first releases may be missing
+        let license = df.column("license").ok().map(|c| c.str()).transpose()?;
+        let file_class = df.column("file_class").ok().map(|c| c.str()).transpose()?;
+        let test_subject = df
+            .column("test_subject")
+            .ok()
+            .map(|c| c.str())
+            .transpose()?;
+        let parent_id = df.column("parent_id").ok().map(|c| c.str()).transpose()?;
+        let chunk_index = df.column("chunk_index").ok().map(|c| c.u32()).transpose()?;
+        let chunk_count = df.column("chunk_count").ok().map(|c| c.u32()).transpose()?;
+        let chunk_offset = df
+            .column("chunk_offset")
+            .ok()
+            .map(|c| c.u64())
+            .transpose()?;
+        let syntax_errors = df
+            .column("syntax_errors")
+            .ok()
+            .map(|c| c.u32())
+            .transpose()?;
+        // So are metric columns before metrics
+        let u64_column = |name: &str| df.column(name).ok().map(|c| c.u64()).transpose();
+        let lines = u64_column("lines")?;
+        let code_lines = u64_column("code_lines")?;
+        let comment_lines = u64_column("comment_lines")?;
+        let blank_lines = u64_column("blank_lines")?;
+        let max_line_length = u64_column("max_line_length")?;
+        let complexity = u64_column("complexity")?;
+        let avg_line_length = df
+            .column("avg_line_length")
+            .ok()
+            .map(|c| c.f64())
+            .transpose()?;
+
+        let mut records = Vec::with_capacity(df.height());
+        for i in 0..df.height() {
+            let normalizations = match normalizations.get_as_series(i) {
+                Some(s) => s.str()?.into_iter().flatten().map(str::to_owned).collect(),
+                None => Vec::new(),
+            };
+            let mut token_counts = BTreeMap::new();
+            if let Some(s) = tokens.get_as_series(i) {
+                let fields = s.struct_()?.fields_as_series();
+                for (name, count) in fields[0].str()?.into_iter().zip(fields[1].u64()?) {
+                    if let (Some(name), Some(count)) = (name, count) {
+                        token_counts.insert(name.to_owned(), count as usize);
+                    }
+                }
+            }
+            records.push(Record {
+                text: text.next().unwrap_or_default(),
+                id: id.next().unwrap_or_default(),
+                content_hash: content_hash.next().unwrap_or_default(),
+                repo_name: repo_name.next().unwrap_or_default(),
+                branch: branch.next().unwrap_or_default(),
+                commit_sha: commit_sha.get(i).map(str::to_owned),
+                license: license.and_then(|c| c.get(i)).map(str::to_owned),
+                file_extension: file_extension.next().unwrap_or_default(),
+                category: category.next().unwrap_or_default(),
+                file_class: file_class
+                    .and_then(|c| c.get(i))
+                    .unwrap_or_default()
+                    .to_owned(),
+                test_subject: test_subject.and_then(|c| c.get(i)).map(str::to_owned),
+                path: path.next().unwrap_or_default(),
+                size_in_bytes: size_in_bytes.get(i).unwrap_or_default(),
+                file_name: file_name.next().unwrap_or_default(),
+                language: language.next().unwrap_or_default(),
+                encoding: encoding.next().unwrap_or_default(),
+                normalizations,
+                tokens: token_counts,
+                parent_id: parent_id.and_then(|c| c.get(i)).map(str::to_owned),
+                chunk_index: chunk_index.and_then(|c| c.get(i)),
+                chunk_count: chunk_count.and_then(|c| c.get(i)),
+                chunk_offset: chunk_offset.and_then(|c| c.get(i)),
+                syntax_errors: syntax_errors.and_then(|c| c.get(i)),
+                lines: lines.and_then(|c| c.get(i)).unwrap_or_default(),
+                code_lines: code_lines.and_then(|c| c.get(i)).unwrap_or_default(),
+                comment_lines: comment_lines.and_then(|c| c.get(i)).unwrap_or_default(),
+                blank_lines: blank_lines.and_then(|c| c.get(i)).unwrap_or_default(),
+                max_line_length: max_line_length.and_then(|c| c.get(i)).unwrap_or_default(),
+                avg_line_length: avg_line_length.and_then(|c| c.get(i)).unwrap_or_default(),
+                complexity: complexity.and_then(|c| c.get(i)),
+            });
+        }
+        Ok(records)
+    }
+
+    // Record of the file at `path` of `repo_name`, for the tests of modules
+    // that take records as input
+    #[cfg(test)]
+    pub fn for_test(repo_name: &str, path: &str, language: &str, text: &str) -> Self {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        let mut record = Record {
+            text: text.to_owned(),
+            id: path.to_owned(),
+            content_hash: format!("{:x}", md5::compute(text)),
+            repo_name: repo_name.to_owned(),
+            branch: String::from("main"),
+            commit_sha: None,
+            license: None,
+            file_extension: file_name
+                .rsplit_once('.')
+                .map(|(_, e)| format!(".{e}"))
+                .unwrap_or_default(),
+            category: String::from("programming"),
+            file_class: classify(path).as_str().to_owned(),
+            test_subject: None,
+            path: path.to_owned(),
+            size_in_bytes: text.len() as u64,
+            file_name: file_name.to_owned(),
+            language: language.to_owned(),
+            encoding: String::from("UTF-8"),
+            normalizations: Vec::new(),
+            tokens: BTreeMap::new(),
+            parent_id: None,
+            chunk_index: None,
+            chunk_count: None,
+            chunk_offset: None,
+            syntax_errors: None,
+            lines: 0,
+            code_lines: 0,
+            comment_lines: 0,
+            blank_lines: 0,
+            max_line_length: 0,
+            avg_line_length: 0.0,
+            complexity: None,
+        };
+        record.set_metrics();
+        record
+    }
+
+    // Size, hash and metrics of a rewritten text. Token counts are dropped,
+    // they are only valid for the text they were counted on.
+    pub fn text_changed(&mut self) {
+        self.size_in_bytes = self.text.len() as u64;
+        self.content_hash = format!("{:x}", md5::compute(&self.text));
+        self.tokens.clear();
+        self.set_metrics();
+    }
+
+    // Recomputed whenever the text changes
+    fn set_metrics(&mut self) {
+        let m = Metrics::compute(&self.language, &self.text);
+        self.lines = m.lines;
+        self.code_lines = m.code_lines;
+        self.comment_lines = m.comment_lines;
+        self.blank_lines = m.blank_lines;
+        self.max_line_length = m.max_line_length;
+        self.avg_line_length = m.avg_line_length;
+        self.complexity = m.complexity;
+    }
+}
+
+// Identity of the archive being extracted
+struct RepoInfo {
+    // Zip name, "<user>-<repo>"
+    name: String,
+    // "<user>/<repo>"
+    repo_name: String,
+    branch: String,
+    // GitHub stores the commit SHA as the archive comment
+    commit: Option<String>,
+    // From the root license files, see `repo_license`
+    license: Option<String>,
+}
+
+impl RepoInfo {
+    fn from_zip(zip: &ZipArchive<BufReader<File>>, name: &str, repo_name: Option<&str>) -> Self {
+        let comment = String::from_utf8_lossy(zip.comment()).trim().to_string();
+        let is_sha = comment.len() == 40 && comment.chars().all(|c| c.is_ascii_hexdigit());
+
+        // The top-level folder is "<repo>-<branch>"
+        let repo_name = repo_name.unwrap_or(name).to_owned();
+        let top = zip
+            .file_names()
+            .next()
+            .and_then(|n| n.split_once('/'))
+            .map(|(top, _)| top)
+            .unwrap_or_default();
+        let repo = repo_name.rsplit('/').next().unwrap_or_default();
+        let branch = top
+            .strip_prefix(&format!("{repo}-"))
+            .unwrap_or(top)
+            .to_owned();
+
+        Self {
+            name: name.to_owned(),
+            repo_name,
+            branch,
+            commit: is_sha.then_some(comment),
+            license: None,
+        }
+    }
+
+    // Stable across runs: the same file at the same commit keeps its id
+    fn record_id(&self, path: &str) -> String {
+        let key = format!(
+            "{}:{}:{}",
+            self.name,
+            self.commit.as_deref().unwrap_or_default(),
+            path
+        );
+        Uuid::new_v5(&Uuid::NAMESPACE_URL, key.as_bytes()).to_string()
+    }
+}
+
+// GitHub archives nest everything under a "<repo>-<branch>/" folder
+pub fn repo_relative(path: &str) -> &str {
+    path.split_once('/').map(|(_, rest)| rest).unwrap_or(path)
+}
+
+// What extract_zip learned about an entry before turning it into a record
+struct FileInfo {
+    extension: String,
+    language: String,
+    encoding: &'static str,
+    normalizations: Vec<String>,
+}
+
+// Read-only settings shared by every zip of an extraction run
+pub struct ExtractOptions {
+    // Zip name "<user>-<repo>" -> "<user>/<repo>"
+    pub repo_names: HashMap<String, String>,
+    pub languages: Languages,
+    pub exclusion_rules: ExclusionRules,
+    pub quality: Option<QualityFilter>,
+    pub normalizer: Normalizer,
+    pub max_file_size: u64,
+    // Notebooks are extracted as scripts, None skips them unless
+    // "Jupyter Notebook" is a selected language
+    pub notebooks: Option<NotebookOptions>,
+    // None keeps files whole
+    pub chunking: Option<ChunkOptions>,
+    // None skips parsing
+    pub syntax: Option<SyntaxCheck>,
+    // Set `test_subject` of test files
+    pub link_tests: bool,
+    // Tag documentation and example files instead of dropping them
+    pub keep_docs: bool,
+    // SPDX ids files must be licensed under, None keeps every file
+    pub licenses: Option<HashSet<&'static str>>,
+    pub output: SinkOptions,
+    // None writes one file per repository
+    pub shards: Option<ShardOptions>,
+}
+
+// Filters of an extraction run, kept in its output directory so the
+// dataset card of `export` can report them
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FilterSettings {
+    pub max_file_size: u64,
+    // Thresholds per quality config section, None without the quality filter
+    pub quality: Option<Vec<(String, String)>>,
+    // --licenses allowlist, None keeps every license
+    pub licenses: Option<Vec<String>>,
+    pub normalize: Vec<String>,
+    // None skips parsing
+    pub syntax: Option<String>,
+    pub keep_docs: bool,
+}
+
+impl FilterSettings {
+    fn from_options(opts: &ExtractOptions) -> Self {
+        Self {
+            max_file_size: opts.max_file_size,
+            quality: opts.quality.as_ref().map(QualityFilter::sections),
+            licenses: opts.licenses.as_ref().map(|allowed| {
+                let mut ids: Vec<String> = allowed.iter().map(|id| id.to_string()).collect();
+                ids.sort();
+                ids
+            }),
+            normalize: opts.normalizer.names(),
+            syntax: opts.syntax.map(|check| match check.mode {
+                SyntaxMode::Reject => format!("reject over {} errors", check.max_errors),
+                SyntaxMode::Tag => String::from("tag"),
+            }),
+            keep_docs: opts.keep_docs,
+        }
+    }
+
+    pub fn load(dir: &Path) -> Option<Self> {
+        let content = fs::read_to_string(dir.join(FILTERS_FILE)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn write(&self, dir: &Path) -> std::io::Result<()> {
+        let tmp_path = dir.join(format!("{FILTERS_FILE}.tmp"));
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, dir.join(FILTERS_FILE))
+    }
+}
+
+// Linguist languages selected for extraction
+pub struct Languages {
+    // ".rs" -> "Rust"
+    pub extensions: HashMap<String, String>,
+    // "CMakeLists.txt" -> "CMake"
+    pub filenames: HashMap<String, String>,
+    // Lowercased names and aliases -> language
+    pub aliases: HashMap<String, String>,
+}
+
+impl Languages {
+    // Extension and language of a selected file, a `linguist-language`
+    // override from .gitattributes wins over the file name, which wins over
+    // the extension.
+    fn detect(&self, name: &str, language_override: Option<&str>) -> Option<(String, String)> {
+        let ext = parse_ext(name, &self.extensions).unwrap_or_default();
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        let language = match language_override {
+            Some(l) => self.aliases.get(&l.to_lowercase())?,
+            None => self
+                .filenames
+                .get(file_name)
+                .or_else(|| self.extensions.get(&ext))?,
+        };
+        Some((ext, language.to_owned()))
+    }
+
+    // Whether notebooks of a kernel language are extracted: when it is
+    // selected, or when notebooks are the only selected language
+    fn keeps_kernel(&self, language: &str) -> bool {
+        self.aliases.contains_key(&language.to_lowercase())
+            || self.extensions.values().all(|l| l == NOTEBOOK_LANGUAGE)
+    }
 }
 
-fn parse_ext(file: &zip::read::ZipFile<'_, BufReader<fs::File>>) -> Option<String> {
-    let split_ext: Vec<&str> = file.name().splitn(2, ".").collect();
-    if split_ext.len() == 2 {
-        let mut ext = split_ext.last()?.to_string();
-        ext.insert(0, '.');
-        return Some(ext);
+// Longest known extension of the file name (".d.ts" before ".ts"),
+// falling back to the last one when none is known.
+fn parse_ext(name: &str, file_types: &HashMap<String, String>) -> Option<String> {
+    let file_name = name.rsplit('/').next()?;
+    let mut last = None;
+    for (i, _) in file_name.match_indices('.').filter(|(i, _)| *i > 0) {
+        let ext = &file_name[i..];
+        if file_types.contains_key(ext) {
+            return Some(ext.to_string());
+        }
+        last = Some(i);
     }
-    None
+    last.map(|i| file_name[i..].to_string())
 }
 
 fn get_zip_name(zip_path: &Path) -> Option<&str> {
@@ -43,35 +625,16 @@ fn get_zip_name(zip_path: &Path) -> Option<&str> {
     Some(base_name)
 }
 
-pub fn write_repo_jsonl(
-    dest_dir: &Path,
-    file_name: &str,
-    r: &Record,
-    fc: &i64,
-) -> Result<(), ExtractionError> {
-    let mut jsonl_name = file_name.to_owned();
-    jsonl_name.push_str(".jsonl");
-    let jsonl_path = dest_dir.join(jsonl_name);
-
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(*fc == 0)
-        .append(*fc != 0)
-        .open(&jsonl_path)?;
-
-    let mut writer = BufWriter::new(file);
-    jsonl::write(&mut writer, r)?;
-    writer.flush()?;
-    Ok(())
-}
-
 fn extract_path_metadata(
     file: &mut zip::read::ZipFile<'_, BufReader<fs::File>>,
 ) -> Option<(String, String)> {
     let path: PathBuf = file.enclosed_name()?;
     let filename = path.file_name()?;
-    Some((path.display().to_string(), filename.display().to_string()))
+    let path = path.display().to_string();
+    Some((
+        repo_relative(&path).to_owned(),
+        filename.display().to_string(),
+    ))
 }
 
 //
@@ -79,16 +642,11 @@ fn extract_path_metadata(
 //
 fn process_valid_file(
     file: &mut zip::read::ZipFile<'_, BufReader<fs::File>>,
-    tokenizer: &Tokenizer,
-    extension: String,
+    text: String,
+    repo: &RepoInfo,
+    info: FileInfo,
 ) -> Result<Record, ExtractionError> {
-    // Read file contents
-    let mut text = String::new();
-    file.read_to_string(&mut text)?;
-    let text = text.trim().to_string();
-
     // Secondary fields: id, path
-    let id = Uuid::new_v4().to_string();
     let Some((file_path, file_name)): Option<(String, String)> = extract_path_metadata(file) else {
         return Err(ExtractionError::Validation {
             message: format!(
@@ -97,106 +655,450 @@ fn process_valid_file(
             ),
         });
     };
+    let id = repo.record_id(&file_path);
+    let content_hash = format!("{:x}", md5::compute(&text));
+    // Of the stored text, after transcoding and notebook conversion
+    let size_in_bytes = text.len() as u64;
+    let license = file_license(&text).or_else(|| repo.license.clone());
 
-    // Metadata: file_type & tokenize
+    // Metadata: file_type, tokens are counted per repo in batches
     let file_type = String::from("programming");
-    let Ok(encoding) = tokenizer.encode(text.clone(), false) else {
-        return Err(ExtractionError::Tokenizer {
-            message: format!("Unable to tokenize {}", file.name()),
-        });
-    };
-    let n_tokens = encoding.len();
 
-    Ok(Record {
+    let mut record = Record {
         text,
         id,
+        content_hash,
+        repo_name: repo.repo_name.clone(),
+        branch: repo.branch.clone(),
+        commit_sha: repo.commit.clone(),
+        license,
         category: file_type,
+        file_class: classify(&file_path).as_str().to_owned(),
+        test_subject: None,
         path: file_path,
         file_name,
-        file_extension: extension,
-        size_in_bytes: file.size(),
-        tokens: n_tokens,
-    })
+        file_extension: info.extension,
+        size_in_bytes,
+        language: info.language,
+        encoding: info.encoding.to_owned(),
+        normalizations: info.normalizations,
+        tokens: BTreeMap::new(),
+        parent_id: None,
+        chunk_index: None,
+        chunk_count: None,
+        chunk_offset: None,
+        syntax_errors: None,
+        lines: 0,
+        code_lines: 0,
+        comment_lines: 0,
+        blank_lines: 0,
+        max_line_length: 0,
+        avg_line_length: 0.0,
+        complexity: None,
+    };
+    record.set_metrics();
+    Ok(record)
 }
 
-fn extract_zip(
+// Zip entry that passed the language and path filters
+struct Entry {
+    index: usize,
+    rel_path: String,
+    extension: String,
+    language: String,
+    overrides: Overrides,
+}
+
+pub fn open_zip(zip_path: &Path) -> Result<ZipArchive<BufReader<File>>, ExtractionError> {
+    let reader = BufReader::new(File::open(zip_path)?);
+    Ok(ZipArchive::new(reader)?)
+}
+
+// Reads, decodes and filters the contents of `entries`
+fn read_entries(
     zip: &mut ZipArchive<BufReader<File>>,
+    entries: &[Entry],
+    opts: &ExtractOptions,
+    repo: &RepoInfo,
+) -> Result<(Vec<Record>, Vec<Exclusion>), ExtractionError> {
+    let mut records: Vec<Record> = Vec::new();
+    let mut excluded: Vec<Exclusion> = Vec::new();
+    for entry in entries {
+        let mut file = zip.by_index(entry.index)?;
+
+        // Read file contents, skipping binaries and transcoding legacy encodings
+        let mut bytes = Vec::new();
+        if let Err(e) = file.read_to_end(&mut bytes) {
+            excluded.push(Exclusion {
+                path: entry.rel_path.clone(),
+                reason: ExclusionReason::Unreadable,
+                rule: format!("read:{e}"),
+            });
+            continue;
+        }
+        let (text, encoding) = match decode(&bytes) {
+            Content::Text { text, encoding } => (text, encoding),
+            Content::Binary { rule } => {
+                excluded.push(Exclusion {
+                    path: entry.rel_path.clone(),
+                    reason: ExclusionReason::Binary,
+                    rule,
+                });
+                continue;
+            }
+        };
+
+        // Notebooks are kept as the script of their code cells, in the
+        // kernel language, before base64 outputs trip the content rules
+        let mut language = entry.language.clone();
+        let mut notebook = false;
+        let text = if language == NOTEBOOK_LANGUAGE {
+            match to_script(&text, &opts.notebooks.unwrap_or_default()) {
+                // Kernels of languages that were not selected
+                Some(script) if !opts.languages.keeps_kernel(&script.language) => {
+                    excluded.push(Exclusion {
+                        path: entry.rel_path.clone(),
+                        reason: ExclusionReason::Quality,
+                        rule: format!("notebook:kernel:{}", script.language),
+                    });
+                    continue;
+                }
+                // --max-file-size holds for the script, outputs excluded
+                Some(script) if script.text.len() as u64 > opts.max_file_size => {
+                    excluded.push(Exclusion {
+                        path: entry.rel_path.clone(),
+                        reason: ExclusionReason::Quality,
+                        rule: format!("notebook:max_file_size>{}", opts.max_file_size),
+                    });
+                    continue;
+                }
+                Some(script) if !script.text.trim().is_empty() => {
+                    language = script.language;
+                    notebook = true;
+                    script.text
+                }
+                script => {
+                    let rule = if script.is_some() {
+                        "notebook:no_code"
+                    } else {
+                        "notebook:invalid"
+                    };
+                    excluded.push(Exclusion {
+                        path: entry.rel_path.clone(),
+                        reason: ExclusionReason::Quality,
+                        rule: rule.to_owned(),
+                    });
+                    continue;
+                }
+            }
+        } else {
+            text
+        };
+        if let Some(e) =
+            opts.exclusion_rules
+                .match_content(&entry.rel_path, &text, &entry.overrides)
+        {
+            excluded.push(e);
+            continue;
+        }
+
+        let (text, mut normalizations) = opts.normalizer.apply(text);
+        if notebook {
+            normalizations.insert(0, String::from("notebook-script"));
+        }
+        let info = FileInfo {
+            extension: entry.extension.clone(),
+            language,
+            encoding,
+            normalizations,
+        };
+
+        // Parse file
+        let mut r = match process_valid_file(&mut file, text, repo, info) {
+            Ok(r) => r,
+            Err(e) => {
+                excluded.push(Exclusion {
+                    path: entry.rel_path.clone(),
+                    reason: ExclusionReason::Unreadable,
+                    rule: format!("validation:{e}"),
+                });
+                continue;
+            }
+        };
+        // Files of allowed repositories whose SPDX header names a license
+        // outside --licenses
+        if let Some(allowed) = &opts.licenses
+            && let Some(license) = r.license.as_deref()
+            && r.license != repo.license
+            && !is_allowed(license, allowed)
+        {
+            excluded.push(Exclusion {
+                path: r.path,
+                reason: ExclusionReason::License,
+                rule: format!("license:{license}"),
+            });
+            continue;
+        }
+        // Content quality heuristics
+        if let Some(quality) = &opts.quality
+            && let Some(rule) = quality.check(&r.language, &r.text)
+        {
+            excluded.push(Exclusion {
+                path: r.path,
+                reason: ExclusionReason::Quality,
+                rule,
+            });
+            continue;
+        }
+        // Truncated, templated or conflicted files do not parse
+        if let Some(check) = &opts.syntax
+            && let Some(errors) = syntax_errors(&r.language, &r.text)
+        {
+            if errors > check.max_errors && check.mode == SyntaxMode::Reject {
+                excluded.push(Exclusion {
+                    path: r.path,
+                    reason: ExclusionReason::Syntax,
+                    rule: format!("max_syntax_errors>{} ({errors})", check.max_errors),
+                });
+                continue;
+            }
+            r.syntax_errors = Some(errors as u32);
+        }
+        records.push(r);
+    }
+    Ok((records, excluded))
+}
+
+fn extract_zip(
+    zip_path: &Path,
     name: &str,
-    file_types: &HashMap<String, String>,
+    opts: &ExtractOptions,
     dest_dir: &Path,
-    tokenizer: &Tokenizer,
-) -> Result<i64, ExtractionError> {
-    let mut file_count = 0;
+    tokenizers: &Tokenizers,
+) -> Result<Vec<Record>, ExtractionError> {
+    let mut zip = open_zip(zip_path)?;
+    let mut excluded: Vec<Exclusion> = Vec::new();
+    let mut repo = RepoInfo::from_zip(&zip, name, opts.repo_names.get(name).map(String::as_str));
+    repo.license = repo_license(&license_files(&mut zip)?);
+    // Repositories under licenses outside --licenses, including unlicensed
+    // ones, are logged as one exclusion without reading their files
+    if let Some(allowed) = &opts.licenses {
+        let license = repo.license.as_deref().unwrap_or(NOASSERTION);
+        if !is_allowed(license, allowed) {
+            let exclusion = Exclusion {
+                path: String::new(),
+                reason: ExclusionReason::License,
+                rule: format!("license:{license}"),
+            };
+            write_exclusions(dest_dir, name, &[exclusion])?;
+            return Ok(Vec::new());
+        }
+    }
+    let gitattributes = GitAttributes::from_zip(&mut zip)?;
+
+    // Path based filters only need the central directory
+    let mut entries: Vec<Entry> = Vec::new();
     for i in 0..zip.len() {
-        let mut file = zip.by_index(i)?;
-        // If we are in a file + it has extension
-        let Some(ext) = parse_ext(&file) else {
+        let file = zip.by_index_raw(i)?;
+        if !file.is_file() {
+            continue;
+        }
+        let rel_path = repo_relative(file.name()).to_owned();
+        let notebook = opts.notebooks.is_some() && file.name().ends_with(NOTEBOOK_EXTENSION);
+        if notebook && file.size() > opts.max_file_size.max(MAX_NOTEBOOK_SIZE) {
+            excluded.push(Exclusion {
+                path: rel_path,
+                reason: ExclusionReason::Quality,
+                rule: format!("notebook:max_size>{MAX_NOTEBOOK_SIZE}"),
+            });
+            continue;
+        } else if !notebook && file.size() > opts.max_file_size {
+            continue;
+        }
+        // .gitattributes overrides apply before the language and vendor filters
+        let overrides = gitattributes.overrides(&rel_path);
+        let Some((extension, language)) = opts
+            .languages
+            .detect(file.name(), overrides.language.as_deref())
+            .or_else(|| {
+                notebook.then(|| (NOTEBOOK_EXTENSION.to_owned(), NOTEBOOK_LANGUAGE.to_owned()))
+            })
+        else {
             continue;
         };
-        if file.is_file()
-                && file.size() <= 2u64.pow(17) // 128KB
-                // && file_types.contains_key(&ext)
+        // Vendored, documentation and generated paths. With --keep-docs,
+        // documentation.yml matches classified as docs or examples are kept.
+        if let Some(e) = opts.exclusion_rules.match_path(&rel_path, &overrides)
+            && !(opts.keep_docs
+                && e.reason == ExclusionReason::Documentation
+                && matches!(classify(&rel_path), FileClass::Docs | FileClass::Example))
         {
-            // Parse file
-            let r = match process_valid_file(&mut file, tokenizer, ext) {
-                Ok(r) => r,
-                Err(_) => {
-                    continue;
+            excluded.push(e);
+            continue;
+        }
+        entries.push(Entry {
+            index: i,
+            rel_path,
+            extension,
+            language,
+            overrides,
+        });
+    }
+
+    // Large archives are split across the rayon pool, every chunk reading
+    // through its own handle of the zip
+    let chunks: Vec<(Vec<Record>, Vec<Exclusion>)> = if entries.len() <= ENTRIES_PER_TASK {
+        vec![read_entries(&mut zip, &entries, opts, &repo)?]
+    } else {
+        entries
+            .par_chunks(ENTRIES_PER_TASK)
+            .map(|chunk| read_entries(&mut open_zip(zip_path)?, chunk, opts, &repo))
+            .collect::<Result<_, _>>()?
+    };
+    let mut records: Vec<Record> = Vec::new();
+    for (r, e) in chunks {
+        records.extend(r);
+        excluded.extend(e);
+    }
+
+    // Zip entry order is not guaranteed, sort for reproducible output
+    records.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if opts.link_tests {
+        link_tests(&mut records);
+    }
+    let mut records = count_tokens(records, tokenizers, &mut excluded);
+    if let Some(chunking) = &opts.chunking {
+        let record_id = |path: &str| repo.record_id(path);
+        records = chunk_records(records, chunking, record_id, tokenizers, &mut excluded);
+    }
+    excluded.sort_by(|a, b| a.path.cmp(&b.path));
+
+    write_exclusions(dest_dir, name, &excluded)?;
+    Ok(records)
+}
+
+// Sets the token counts of `records` a batch at once. When a batch fails,
+// its files are counted one by one and those that still fail are excluded.
+pub fn count_tokens(
+    mut records: Vec<Record>,
+    tokenizers: &Tokenizers,
+    excluded: &mut Vec<Exclusion>,
+) -> Vec<Record> {
+    let mut failed = vec![false; records.len()];
+    for (batch, failed) in records
+        .chunks_mut(TOKENIZE_BATCH_SIZE)
+        .zip(failed.chunks_mut(TOKENIZE_BATCH_SIZE))
+    {
+        let texts: Vec<&str> = batch.iter().map(|r| r.text.as_str()).collect();
+        if let Ok(counts) = tokenizers.count_batch(&texts) {
+            for (r, tokens) in batch.iter_mut().zip(counts) {
+                r.tokens = tokens;
+            }
+            continue;
+        }
+        for (r, failed) in batch.iter_mut().zip(failed.iter_mut()) {
+            match tokenizers.count_batch(&[r.text.as_str()]) {
+                Ok(mut counts) => r.tokens = counts.remove(0),
+                Err(e) => {
+                    eprintln!(
+                        "{} Unable to tokenize {} of {}: {}",
+                        "[WARNING]".truecolor(214, 143, 0),
+                        r.path,
+                        r.repo_name,
+                        e
+                    );
+                    excluded.push(Exclusion {
+                        path: r.path.clone(),
+                        reason: ExclusionReason::Tokenizer,
+                        rule: e.to_string(),
+                    });
+                    *failed = true;
                 }
-            };
-            // Write to JSONL
-            let Ok(_) = write_repo_jsonl(dest_dir, name, &r, &file_count) else {
-                continue;
-            };
-            file_count += 1;
+            }
         }
     }
-    Ok(file_count)
+    let mut failed = failed.into_iter();
+    records.retain(|_| !failed.next().unwrap_or_default());
+    records
+}
+
+// One file per repository, written in the requested output format
+fn write_repo(
+    dest_dir: &Path,
+    name: &str,
+    records: Vec<Record>,
+    output: &SinkOptions,
+) -> Result<i64, ExtractionError> {
+    let mut sink = RecordSink::create(dest_dir, name, output)?;
+    for r in records {
+        sink.write(r)?;
+    }
+    Ok(sink.finish()? as i64)
 }
 
 pub fn extract_text(
     jsonl_dir: &PathBuf,
     zip_paths: Vec<PathBuf>,
-    file_types: HashMap<String, String>,
-    tokenizer: Tokenizer,
+    opts: ExtractOptions,
+    tokenizers: Tokenizers,
 ) -> Result<(), ExtractionError> {
     let destination_dir = jsonl_dir;
-    fs::create_dir_all(&destination_dir)?;
+    fs::create_dir_all(destination_dir)?;
+    FilterSettings::from_options(&opts).write(destination_dir)?;
 
     // Arc types for read-only on async
-    let file_types = Arc::new(file_types);
-    let tokenizer = Arc::new(tokenizer);
+    let opts = Arc::new(opts);
+    let tokenizers = Arc::new(tokenizers);
     let dest_dir = Arc::new(destination_dir);
 
-    let total_files: i64 = zip_paths
-        .par_iter()
-        .map(|zip_path| {
-            let ft = Arc::new(&file_types);
-            let tok = Arc::new(&tokenizer);
-            let ddir = Arc::clone(&dest_dir);
+    let extract = |zip_path: &PathBuf| -> Option<(String, Vec<Record>)> {
+        let zip_name = get_zip_name(zip_path)?;
+        match extract_zip(zip_path, zip_name, &opts, &dest_dir, &tokenizers) {
+            Ok(records) => {
+                println!("\t{}:  {}", "Extracted".green(), zip_name);
+                Some((zip_name.to_owned(), records))
+            }
+            Err(e) => {
+                eprintln!("Unable to extract zip {}: {}", zip_path.display(), e);
+                None
+            }
+        }
+    };
 
-            let f = match fs::File::open(zip_path) {
-                Ok(f) => f,
-                Err(e) => {
-                    eprintln!("Unable to open zip {}: {}", zip_path.display(), e);
-                    return 0;
+    let total_files: i64 = match &opts.shards {
+        None => {
+            remove_index(destination_dir)?;
+            zip_paths
+                .par_iter()
+                .filter_map(extract)
+                .map(|(zip_name, records)| {
+                    match write_repo(&dest_dir, &zip_name, records, &opts.output) {
+                        Ok(count) => count,
+                        Err(e) => {
+                            eprintln!("Unable to write {}: {}", zip_name, e);
+                            0
+                        }
+                    }
+                })
+                .sum()
+        }
+        Some(shards) => {
+            // Repos are extracted in parallel batches but written in path
+            // order, so the same input always produces the same shards
+            let mut sink = ShardedSink::create(destination_dir, shards, &opts.output)?;
+            for batch in zip_paths.chunks(rayon::current_num_threads() * 2) {
+                let extracted: Vec<(String, Vec<Record>)> =
+                    batch.par_iter().filter_map(extract).collect();
+                for (_, records) in extracted {
+                    for r in records {
+                        sink.write(r)?;
+                    }
                 }
-            };
-
-            let Some(zip_name) = get_zip_name(zip_path) else {
-                return 0;
-            };
-
-            let reader = BufReader::new(f);
-            let mut zip = zip::ZipArchive::new(reader).unwrap();
-
-            if let Ok(count) = extract_zip(&mut zip, zip_name, &ft, &ddir, &tok) {
-                println!("\t{}:  {}", "Extracted".green(), zip_name);
-                return count;
             }
-            0
-        })
-        .sum();
+            sink.finish()?.records() as i64
+        }
+    };
 
     println!("Total files processed = {}", total_files);
     // TODO: Make this error if and only if the original length is not 0
@@ -210,14 +1112,82 @@ pub fn extract_text(
 
 #[cfg(test)]
 mod tests {
-    use crate::extractor::Record;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
 
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    use crate::concat::{ConcatOptions, FileOrder, concat_repos};
+    use crate::exclusion::{Exclusion, ExclusionReason, ExclusionRules};
+    use crate::extractor::{
+        ExtractOptions, Languages, Record, TOKENIZE_BATCH_SIZE, count_tokens, extract_text,
+        extract_zip, parse_ext, write_repo,
+    };
+    use crate::normalize::Normalizer;
+    use crate::notebook::NotebookOptions;
+    use crate::shard::{SHARD_INDEX, ShardOptions, read_index};
+    use crate::sink::{SinkOptions, Tabular};
+    use crate::tokenization::Tokenizers;
+
+    // GitHub-style archive of user/repo, with the commit SHA as comment
+    fn write_zip(dir: &Path, commit: &str, files: &[(&str, &str)]) -> PathBuf {
+        let path = dir.join("user-repo.zip");
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for (name, text) in files {
+            zip.start_file(format!("repo-main/{name}"), SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+        }
+        zip.set_comment(commit);
+        zip.finish().unwrap();
+        path
+    }
+
+    // Python files only, without the optional stages
+    fn options() -> ExtractOptions {
+        ExtractOptions {
+            repo_names: HashMap::from([("user-repo".to_owned(), "user/repo".to_owned())]),
+            languages: Languages {
+                extensions: HashMap::from([(".py".to_owned(), "Python".to_owned())]),
+                filenames: HashMap::new(),
+                aliases: HashMap::from([("python".to_owned(), "Python".to_owned())]),
+            },
+            exclusion_rules: ExclusionRules::load(
+                Path::new("./vendor/vendor.yml"),
+                Path::new("./vendor/documentation.yml"),
+            )
+            .unwrap(),
+            quality: None,
+            normalizer: Normalizer::default(),
+            max_file_size: 2u64.pow(17),
+            notebooks: None,
+            chunking: None,
+            syntax: None,
+            link_tests: false,
+            keep_docs: false,
+            licenses: None,
+            output: SinkOptions::default(),
+            shards: None,
+        }
+    }
 
     #[test]
     fn test_parse_ext() {
-        // Create a mock ZipFile somehow, or test the logic
-        // This might be tricky with ZipFile, so consider refactoring
-        // to extract the logic into a testable function
+        let file_types: HashMap<String, String> = [".ts", ".d.ts", ".py"]
+            .iter()
+            .map(|e| (e.to_string(), "programming".to_string()))
+            .collect();
+
+        let ext = |name: &str| parse_ext(name, &file_types);
+        assert_eq!(ext("repo-main/src/app.ts").as_deref(), Some(".ts"));
+        assert_eq!(ext("repo-main/types/index.d.ts").as_deref(), Some(".d.ts"));
+        assert_eq!(ext("repo-1.0/tests/test.utils.py").as_deref(), Some(".py"));
+        assert_eq!(ext("repo-main/archive.tar.gz").as_deref(), Some(".gz"));
+        assert_eq!(ext("repo-main/.gitignore"), None);
+        assert_eq!(ext("repo-main/Makefile"), None);
     }
 
     #[test]
@@ -225,15 +1195,383 @@ mod tests {
         let record = Record {
             text: "fn main() {}".to_string(),
             id: "test-id".to_string(),
+            content_hash: "test-hash".to_string(),
+            repo_name: "user/repo".to_string(),
+            branch: "main".to_string(),
+            commit_sha: None,
+            license: None,
             file_extension: ".rs".to_string(),
             category: "programming".to_string(),
+            file_class: "source".to_string(),
+            test_subject: None,
             path: "test.rs".to_string(),
             size_in_bytes: 13,
             file_name: "test.rs".to_string(),
-            tokens: 5,
+            language: "Rust".to_string(),
+            encoding: "UTF-8".to_string(),
+            normalizations: vec![],
+            tokens: BTreeMap::from([("gpt2".to_string(), 5)]),
+            parent_id: None,
+            chunk_index: None,
+            chunk_count: None,
+            chunk_offset: None,
+            syntax_errors: None,
+            lines: 1,
+            code_lines: 1,
+            comment_lines: 0,
+            blank_lines: 0,
+            max_line_length: 12,
+            avg_line_length: 12.0,
+            complexity: Some(1),
         };
 
-        assert_eq!(record.tokens, 5);
+        assert_eq!(record.tokens["gpt2"], 5);
         assert_eq!(record.file_extension, ".rs");
     }
+
+    #[test]
+    fn test_record_dataframe_roundtrip() {
+        let line = r#"{"text":"x = 1\n","id":"a","content_hash":"h","repo_name":"user/repo","branch":"main","file_extension":".py","category":"programming","file_class":"source","path":"a.py","size_in_bytes":6,"file_name":"a.py","language":"Python","encoding":"UTF-8","normalizations":["crlf"],"tokens":{"gpt2":4,"llama3":3},"lines":1,"code_lines":1,"comment_lines":0,"blank_lines":0,"max_line_length":5,"avg_line_length":5.0,"complexity":1}"#;
+        let record: Record = serde_json::from_str(line).unwrap();
+        let df = Record::to_dataframe(&[record]).unwrap();
+        let records = Record::from_dataframe(&df).unwrap();
+        assert_eq!(serde_json::to_string(&records[0]).unwrap(), line);
+
+        // Empty outputs still carry the full schema
+        let df = Record::to_dataframe(&[]).unwrap();
+        assert_eq!(
+            df.schema(),
+            Record::to_dataframe(&records).unwrap().schema()
+        );
+    }
+
+    #[test]
+    fn test_reproducible_extraction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let files = [
+            ("b.py", "y = 2\n"),
+            ("a.py", "x = 1\n"),
+            ("src/c.py", "z = 3\n"),
+        ];
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let zip_path = write_zip(tmp.path(), sha, &files);
+        let opts = options();
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+
+        // Two runs over the same archive
+        let mut outputs = Vec::new();
+        let mut ids = Vec::new();
+        for run in ["1", "2"] {
+            let dest_dir = tmp.path().join(run);
+            let records =
+                extract_zip(&zip_path, "user-repo", &opts, &dest_dir, &tokenizers).unwrap();
+            ids.push(records.iter().map(|r| r.id.clone()).collect::<Vec<_>>());
+            write_repo(&dest_dir, "user-repo", records, &opts.output).unwrap();
+            outputs.push(fs::read(dest_dir.join("user-repo.jsonl")).unwrap());
+        }
+        assert_eq!(ids[0].len(), 3);
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(outputs[0], outputs[1]);
+
+        // The same file at another commit is another record
+        let zip_path = write_zip(tmp.path(), &sha.replace('0', "f"), &files);
+        let records = extract_zip(&zip_path, "user-repo", &opts, tmp.path(), &tokenizers).unwrap();
+        let paths: Vec<&str> = records.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["a.py", "b.py", "src/c.py"]);
+        assert!(records.iter().zip(&ids[0]).all(|(r, id)| r.id != *id));
+    }
+
+    #[test]
+    fn test_per_repo_after_sharded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let zip_path = write_zip(tmp.path(), "", &[("a.py", "x = 1\n"), ("b.py", "y = 2\n")]);
+        let dest_dir = tmp.path().join("jsonl");
+
+        // One record per shard, then one file per repository
+        let mut opts = options();
+        opts.shards = ShardOptions::new(None, Some(1), false);
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        extract_text(&dest_dir, vec![zip_path.clone()], opts, tokenizers).unwrap();
+        assert_eq!(read_index(&dest_dir).unwrap().len(), 2);
+
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        extract_text(&dest_dir, vec![zip_path], options(), tokenizers).unwrap();
+        assert!(read_index(&dest_dir).is_none());
+        assert!(!dest_dir.join(SHARD_INDEX).exists());
+        assert!(!dest_dir.join("data").exists());
+        assert!(dest_dir.join("user-repo.jsonl").exists());
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        let tokenizers = Tokenizers::for_test(&["x", "=", "1", "y", "2"]);
+        // Two batches, the second one with a file the tokenizer fails on
+        let mut records: Vec<Record> = (0..TOKENIZE_BATCH_SIZE + 2)
+            .map(|i| Record::for_test("user/repo", &format!("{i}.py"), "Python", "x = 1"))
+            .collect();
+        records[1].text = String::from("y = 2\ny");
+        records[TOKENIZE_BATCH_SIZE].text = String::from("z = 3");
+
+        let mut excluded = Vec::new();
+        let records = count_tokens(records, &tokenizers, &mut excluded);
+        assert_eq!(records.len(), TOKENIZE_BATCH_SIZE + 1);
+        assert_eq!(records[0].tokens, BTreeMap::from([("test".to_owned(), 3)]));
+        assert_eq!(records[1].tokens["test"], 4);
+        assert!(records.iter().all(|r| r.tokens.contains_key("test")));
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].path, format!("{TOKENIZE_BATCH_SIZE}.py"));
+        assert_eq!(excluded[0].reason, ExclusionReason::Tokenizer);
+    }
+
+    #[test]
+    fn test_unreadable_and_transcoded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("user-repo.zip");
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        // "café" in Latin-1, 4 bytes on disk and 5 once transcoded
+        zip.start_file("repo-main/a.py", stored).unwrap();
+        zip.write_all(b"s = 'caf\xe9'\n").unwrap();
+        zip.start_file("repo-main/b.py", stored).unwrap();
+        zip.write_all(b"y = 2\n").unwrap();
+        // A path escaping the archive
+        zip.start_file("repo-main/../../c.py", stored).unwrap();
+        zip.write_all(b"z = 3\n").unwrap();
+        zip.finish().unwrap();
+        // Corrupt the stored data of b.py so its checksum fails
+        let mut bytes = fs::read(&path).unwrap();
+        let at = bytes.windows(5).position(|w| w == b"y = 2").unwrap();
+        bytes[at] = b'z';
+        fs::write(&path, bytes).unwrap();
+
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let records = extract_zip(&path, "user-repo", &options(), tmp.path(), &tokenizers).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].encoding, "windows-1252");
+        assert_eq!(records[0].size_in_bytes, records[0].text.len() as u64);
+        let log = fs::read_to_string(tmp.path().join("excluded/user-repo.jsonl")).unwrap();
+        let mut exclusions: Vec<Exclusion> = log
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        exclusions.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(exclusions.len(), 2);
+        assert_eq!(exclusions[0].path, "../../c.py");
+        assert_eq!(exclusions[0].reason, ExclusionReason::Unreadable);
+        assert!(exclusions[0].rule.starts_with("validation:"));
+        assert_eq!(exclusions[1].path, "b.py");
+        assert_eq!(exclusions[1].reason, ExclusionReason::Unreadable);
+        assert!(exclusions[1].rule.starts_with("read:"));
+    }
+
+    #[test]
+    fn test_keep_docs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let files = [
+            ("src/a.py", "x = 1\n"),
+            ("examples/basic.py", "y = 2\n"),
+            ("docs/conf.py", "z = 3\n"),
+        ];
+        let zip_path = write_zip(tmp.path(), "", &files);
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let classes = |opts: &ExtractOptions| {
+            extract_zip(&zip_path, "user-repo", opts, tmp.path(), &tokenizers)
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.path, r.file_class))
+                .collect::<Vec<_>>()
+        };
+
+        // documentation.yml drops both directories by default
+        let mut opts = options();
+        assert_eq!(
+            classes(&opts),
+            [("src/a.py".to_owned(), "source".to_owned())]
+        );
+        opts.keep_docs = true;
+        let expected = [
+            ("docs/conf.py", "docs"),
+            ("examples/basic.py", "example"),
+            ("src/a.py", "source"),
+        ]
+        .map(|(p, c)| (p.to_owned(), c.to_owned()));
+        assert_eq!(classes(&opts), expected);
+    }
+
+    #[test]
+    fn test_concat_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let files = [
+            ("pkg/a.py", "x = 1\n"),
+            ("setup.py", "y = 2\n"),
+            ("Makefile", "all:\n\tpython setup.py\n"),
+            ("README.md", "# repo\n"),
+            ("pkg/README.md", "# pkg\n"),
+        ];
+        let zip_path = write_zip(tmp.path(), "", &files);
+        // READMEs need Markdown and --keep-docs, Makefile its Linguist file name
+        let mut opts = options();
+        let languages = &mut opts.languages;
+        languages
+            .extensions
+            .insert(".md".to_owned(), "Markdown".to_owned());
+        languages
+            .filenames
+            .insert("Makefile".to_owned(), "Makefile".to_owned());
+        opts.keep_docs = true;
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let jsonl_dir = tmp.path().join("jsonl");
+        let records = extract_zip(&zip_path, "user-repo", &opts, &jsonl_dir, &tokenizers).unwrap();
+        write_repo(&jsonl_dir, "user-repo", records, &opts.output).unwrap();
+
+        let repo_dir = tmp.path().join("repo");
+        let concat = ConcatOptions {
+            max_tokens: 1000,
+            tokenizer: None,
+            repo_token: String::from("<repo_name>"),
+            file_sep_token: String::from("<file_sep>"),
+            order: FileOrder::Directory,
+            output: SinkOptions::default(),
+        };
+        let paths = [jsonl_dir.join("user-repo.jsonl")];
+        assert_eq!(concat_repos(&paths, None, &repo_dir, &concat).unwrap(), 1);
+        let doc: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(repo_dir.join("user-repo.jsonl")).unwrap())
+                .unwrap();
+        assert_eq!(
+            doc["paths"],
+            serde_json::json!([
+                "README.md",
+                "Makefile",
+                "setup.py",
+                "pkg/README.md",
+                "pkg/a.py"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_notebook_kernels() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notebook = |language: &str, code: &str| {
+            serde_json::json!({
+                "metadata": {"kernelspec": {"language": language}},
+                "cells": [{"cell_type": "code", "source": code, "outputs": []}],
+            })
+            .to_string()
+        };
+        let (python, r) = (notebook("python", "x = 1\n"), notebook("R", "x <- 1\n"));
+        let zip_path = write_zip(tmp.path(), "", &[("a.ipynb", &python), ("b.ipynb", &r)]);
+        // Python only, with --notebooks
+        let mut opts = options();
+        opts.notebooks = Some(NotebookOptions::default());
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let records = extract_zip(&zip_path, "user-repo", &opts, tmp.path(), &tokenizers).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].path, "a.ipynb");
+        assert_eq!(records[0].language, "Python");
+        let log = fs::read_to_string(tmp.path().join("excluded/user-repo.jsonl")).unwrap();
+        let exclusion: Exclusion = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(exclusion.path, "b.ipynb");
+        assert_eq!(exclusion.rule, "notebook:kernel:R");
+    }
+
+    #[test]
+    fn test_notebook_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notebook = |code: &str, output: &str| {
+            serde_json::json!({
+                "metadata": {"kernelspec": {"language": "python"}},
+                "cells": [{
+                    "cell_type": "code",
+                    "source": code,
+                    "outputs": [{"data": {"image/png": output}}],
+                }],
+            })
+            .to_string()
+        };
+        // Over 128KB because of an image output, and because of its code
+        let image = notebook("x = 1\n", &"A".repeat(200_000));
+        let code = notebook(&"y = 2\n".repeat(30_000), "");
+        let zip_path = write_zip(tmp.path(), "", &[("a.ipynb", &image), ("b.ipynb", &code)]);
+        let mut opts = options();
+        opts.notebooks = Some(NotebookOptions::default());
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let records = extract_zip(&zip_path, "user-repo", &opts, tmp.path(), &tokenizers).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].path, "a.ipynb");
+        assert_eq!(records[0].text, "x = 1\n");
+        let log = fs::read_to_string(tmp.path().join("excluded/user-repo.jsonl")).unwrap();
+        let exclusion: Exclusion = serde_json::from_str(log.trim()).unwrap();
+        assert_eq!(exclusion.path, "b.ipynb");
+        assert_eq!(exclusion.rule, "notebook:max_file_size>131072");
+    }
+
+    #[test]
+    fn test_no_tokenize() {
+        let tmp = tempfile::tempdir().unwrap();
+        let zip_path = write_zip(tmp.path(), "", &[("a.py", "x = 1\n")]);
+        let opts = options();
+        // --no-tokenize loads no tokenizer
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let records = extract_zip(&zip_path, "user-repo", &opts, tmp.path(), &tokenizers).unwrap();
+        assert!(records[0].tokens.is_empty());
+        let line = serde_json::to_string(&records[0]).unwrap();
+        assert!(!line.contains("\"tokens\""));
+    }
+
+    #[test]
+    fn test_license_allowlist() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut opts = options();
+        opts.licenses = Some(HashSet::from(["MIT"]));
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let mit = spdx::license_id("MIT").unwrap().text();
+        let gpl = spdx::license_id("GPL-3.0-only").unwrap().text();
+        let excluded = |dest_dir: &Path| {
+            fs::read_to_string(dest_dir.join("excluded/user-repo.jsonl"))
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect::<Vec<Exclusion>>()
+        };
+
+        // Files of an allowed repository are checked against their own header
+        let files = [
+            ("LICENSE", mit),
+            ("a.py", "x = 1\n"),
+            ("b.py", "# SPDX-License-Identifier: GPL-3.0-only\ny = 2\n"),
+        ];
+        let zip_path = write_zip(tmp.path(), "", &files);
+        let dest_dir = tmp.path().join("mit");
+        let records = extract_zip(&zip_path, "user-repo", &opts, &dest_dir, &tokenizers).unwrap();
+        let paths: Vec<&str> = records.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["a.py"]);
+        let exclusions = excluded(&dest_dir);
+        assert_eq!(exclusions.len(), 1);
+        assert_eq!(exclusions[0].path, "b.py");
+
+        // A repository outside the allowlist is one exclusion
+        let files = [
+            ("LICENSE", gpl),
+            ("a.py", "# SPDX-License-Identifier: MIT\nx = 1\n"),
+            ("b.py", "y = 2\n"),
+        ];
+        let zip_path = write_zip(tmp.path(), "", &files);
+        let dest_dir = tmp.path().join("gpl");
+        let records = extract_zip(&zip_path, "user-repo", &opts, &dest_dir, &tokenizers).unwrap();
+        assert!(records.is_empty());
+        let exclusions = excluded(&dest_dir);
+        assert_eq!(exclusions.len(), 1);
+        assert_eq!(exclusions[0].path, "");
+        assert_eq!(exclusions[0].reason, ExclusionReason::License);
+        assert_eq!(exclusions[0].rule, "license:GPL-3.0-only");
+    }
+
+    #[test]
+    fn test_legacy_token_count() {
+        let line = r#"{"text":"x","id":"a","file_extension":".py","category":"programming","path":"a.py","size_in_bytes":1,"file_name":"a.py","tokens":3}"#;
+        let record: Record = serde_json::from_str(line).unwrap();
+        assert_eq!(record.tokens, BTreeMap::from([("gpt2".to_string(), 3)]));
+    }
 }
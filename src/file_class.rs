@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::extractor::Record;
+
+// Build scripts and manifests, most important first. Concat puts the build
+// files of a directory in this order.
+pub const BUILD_FILES: &[&str] = &[
+    "Cargo.toml",
+    "CMakeLists.txt",
+    "Makefile",
+    "GNUmakefile",
+    "meson.build",
+    "BUILD",
+    "BUILD.bazel",
+    "WORKSPACE",
+    "configure.ac",
+    "package.json",
+    "tsconfig.json",
+    "pyproject.toml",
+    "setup.py",
+    "setup.cfg",
+    "requirements.txt",
+    "go.mod",
+    "pom.xml",
+    "build.gradle",
+    "build.gradle.kts",
+    "settings.gradle",
+    "build.sbt",
+    "Gemfile",
+    "composer.json",
+    "Package.swift",
+    "mix.exs",
+    "dune-project",
+    "stack.yaml",
+    "Dockerfile",
+];
+
+// Extensions of build scripts whose names vary
+const BUILD_EXTENSIONS: &[&str] = &["cmake", "mk", "mak", "bzl", "gradle"];
+
+// Directory names that mark every file below them
+const TEST_DIRS: &[&str] = &["test", "tests", "__tests__", "testing", "spec", "specs"];
+const BENCHMARK_DIRS: &[&str] = &["bench", "benches", "benchmark", "benchmarks"];
+const EXAMPLE_DIRS: &[&str] = &["example", "examples", "demo", "demos", "sample", "samples"];
+const DOCS_DIRS: &[&str] = &["doc", "docs", "documentation"];
+
+// Markers of test and benchmark file stems, "test_x.py", "x_test.go",
+// "XTest.java", "x.spec.ts", "x_tb.v". Capitalized markers only match
+// CamelCase names, so "latest.py" is not a test.
+const TEST_PREFIXES: &[&str] = &["test_", "tb_"];
+const TEST_SUFFIXES: &[&str] = &[
+    "_test", "_tests", ".test", "_spec", ".spec", "_tb", "Test", "Tests",
+];
+const BENCHMARK_PREFIXES: &[&str] = &["bench_", "benchmark_"];
+const BENCHMARK_SUFFIXES: &[&str] = &["_bench", "_benchmark", "Benchmark"];
+
+// Role of a file in its repository, from path conventions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileClass {
+    Source,
+    Test,
+    Example,
+    Benchmark,
+    Docs,
+    Build,
+}
+
+impl FileClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileClass::Source => "source",
+            FileClass::Test => "test",
+            FileClass::Example => "example",
+            FileClass::Benchmark => "benchmark",
+            FileClass::Docs => "docs",
+            FileClass::Build => "build",
+        }
+    }
+}
+
+// "tests/test_io.py" -> "io", "pkg/io_test.go" -> "io", "IoTest.java" -> "io",
+// None when the name has no test marker
+fn strip_marker(stem: &str, prefixes: &[&str], suffixes: &[&str]) -> Option<String> {
+    let lower = stem.to_lowercase();
+    let stripped = prefixes
+        .iter()
+        .find_map(|p| lower.strip_prefix(p).map(str::to_owned))
+        .or_else(|| {
+            suffixes.iter().find_map(|s| {
+                if s.starts_with(char::is_uppercase) {
+                    stem.strip_suffix(s).map(str::to_lowercase)
+                } else {
+                    lower.strip_suffix(s).map(str::to_owned)
+                }
+            })
+        })?;
+    let stripped = stripped.trim_end_matches(['_', '.', '-']);
+    (!stripped.is_empty()).then(|| stripped.to_owned())
+}
+
+// File name without its extensions, "x.spec.ts" keeps "x.spec"
+fn stem(name: &str) -> &str {
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+// Class of a repository-relative path. Build files win over test names,
+// which win over the directories a file is in.
+pub fn classify(path: &str) -> FileClass {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    if BUILD_FILES.contains(&name) || BUILD_EXTENSIONS.contains(&extension) {
+        return FileClass::Build;
+    }
+    if name == "conftest.py" || strip_marker(stem(name), TEST_PREFIXES, TEST_SUFFIXES).is_some() {
+        return FileClass::Test;
+    }
+    if strip_marker(stem(name), BENCHMARK_PREFIXES, BENCHMARK_SUFFIXES).is_some() {
+        return FileClass::Benchmark;
+    }
+    let dirs: Vec<String> = path
+        .split('/')
+        .rev()
+        .skip(1)
+        .map(str::to_lowercase)
+        .collect();
+    let in_dir = |names: &[&str]| dirs.iter().any(|d| names.contains(&d.as_str()));
+    if in_dir(TEST_DIRS) {
+        FileClass::Test
+    } else if in_dir(BENCHMARK_DIRS) {
+        FileClass::Benchmark
+    } else if in_dir(EXAMPLE_DIRS) {
+        FileClass::Example
+    } else if in_dir(DOCS_DIRS) || matches!(extension, "md" | "rst" | "adoc") {
+        FileClass::Docs
+    } else {
+        FileClass::Source
+    }
+}
+
+// Leading path components two paths share
+fn common_dirs(a: &str, b: &str) -> usize {
+    a.split('/')
+        .zip(b.split('/'))
+        .take_while(|(x, y)| x == y)
+        .count()
+}
+
+// Links every test file to the source file of the same language named like
+// the test without its marker, "tests/test_io.py" -> "pkg/io.py". Among
+// several candidates the closest in the tree wins.
+pub fn link_tests(records: &mut [Record]) {
+    let mut sources: HashMap<(String, String), Vec<String>> = HashMap::new();
+    for r in records.iter().filter(|r| r.file_class == "source") {
+        let name = r.path.rsplit('/').next().unwrap_or(&r.path);
+        let key = (r.language.clone(), stem(name).to_lowercase());
+        sources.entry(key).or_default().push(r.path.clone());
+    }
+    for r in records.iter_mut().filter(|r| r.file_class == "test") {
+        let name = r.path.rsplit('/').next().unwrap_or(&r.path);
+        let stem = stem(name);
+        let subject =
+            strip_marker(stem, TEST_PREFIXES, TEST_SUFFIXES).unwrap_or_else(|| stem.to_lowercase());
+        r.test_subject = sources
+            .get(&(r.language.clone(), subject))
+            .and_then(|paths| {
+                // Ties go to the first path, records are sorted by path
+                paths.iter().rev().max_by_key(|p| common_dirs(p, &r.path))
+            })
+            .cloned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extractor::Record;
+    use crate::file_class::{FileClass, classify, link_tests};
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            ("src/io.py", FileClass::Source),
+            ("tests/test_io.py", FileClass::Test),
+            ("pkg/io_test.go", FileClass::Test),
+            ("src/test/java/IoTest.java", FileClass::Test),
+            ("web/io.spec.ts", FileClass::Test),
+            ("rtl/fifo_tb.v", FileClass::Test),
+            ("conftest.py", FileClass::Test),
+            ("benches/parse.rs", FileClass::Benchmark),
+            ("examples/basic.rs", FileClass::Example),
+            ("docs/conf.py", FileClass::Docs),
+            ("CMakeLists.txt", FileClass::Build),
+            ("cmake/FindFoo.cmake", FileClass::Build),
+            ("src/latest.py", FileClass::Source),
+        ];
+        for (path, class) in cases {
+            assert_eq!(classify(path), class, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_link_tests() {
+        let mut records: Vec<Record> = [
+            ("lib/io.py", "source"),
+            ("pkg/io.py", "source"),
+            ("pkg/tests/test_io.py", "test"),
+            ("tests/test_net.py", "test"),
+        ]
+        .iter()
+        .map(|(path, class)| {
+            let mut r = Record::for_test("", path, "Python", "");
+            r.file_class = class.to_string();
+            r
+        })
+        .collect();
+        link_tests(&mut records);
+        assert_eq!(records[2].test_subject.as_deref(), Some("pkg/io.py"));
+        assert_eq!(records[3].test_subject, None);
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+use crate::error::FimError;
+use crate::extractor::Record;
+use crate::shard::{ShardEntry, load_index, remove_index, write_index};
+use crate::sink::{Format, RecordSink, SinkOptions, read_records};
+use crate::tokenization::Tokenizers;
+
+// Where the text of a record may be split
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SplitLevel {
+    #[default]
+    Char,
+    // Token boundaries of the configured tokenizer
+    Token,
+}
+
+impl FromStr for SplitLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "char" => Ok(SplitLevel::Char),
+            "token" => Ok(SplitLevel::Token),
+            _ => Err(format!(
+                "unknown split level {s:?}, expected one of: char, token"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FimOptions {
+    // Share of records rewritten, the rest is copied unchanged
+    pub rate: f64,
+    // Share of the rewritten records in SPM rather than PSM order
+    pub spm_rate: f64,
+    pub seed: u64,
+    pub level: SplitLevel,
+    pub prefix_token: String,
+    pub middle_token: String,
+    pub suffix_token: String,
+    pub row_group_size: usize,
+}
+
+// SplitMix64, seeded per record so the output does not depend on the
+// order records are processed in
+struct Rng(u64);
+
+impl Rng {
+    fn for_record(seed: u64, id: &str) -> Self {
+        let digest = md5::compute(format!("{seed}:{id}"));
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        Rng(u64::from_le_bytes(bytes))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+// Rewrites the text of a record into fill-in-the-middle form, split at two
+// of the given byte offsets:
+//   PSM: <prefix_token>prefix<suffix_token>suffix<middle_token>middle
+//   SPM: <suffix_token>suffix<prefix_token>prefix<middle_token>middle
+fn rewrite(record: &mut Record, boundaries: &[usize], spm: bool, rng: &mut Rng, opts: &FimOptions) {
+    let mut a = boundaries[rng.below(boundaries.len())];
+    let mut b = boundaries[rng.below(boundaries.len())];
+    if a > b {
+        std::mem::swap(&mut a, &mut b);
+    }
+    let text = &record.text;
+    let (prefix, middle, suffix) = (&text[..a], &text[a..b], &text[b..]);
+    let (p, m, s) = (&opts.prefix_token, &opts.middle_token, &opts.suffix_token);
+    let (text, transform) = if spm {
+        (format!("{s}{suffix}{p}{prefix}{m}{middle}"), "fim-spm")
+    } else {
+        (format!("{p}{prefix}{s}{suffix}{m}{middle}"), "fim-psm")
+    };
+    record.text = text;
+    record.text_changed();
+    record.normalizations.push(transform.to_owned());
+}
+
+// Rewrites the record with probability `rate`, recorded in its normalizations
+pub fn apply_fim(
+    record: &mut Record,
+    opts: &FimOptions,
+    tokenizers: &Tokenizers,
+) -> Result<bool, FimError> {
+    let mut rng = Rng::for_record(opts.seed, &record.id);
+    if rng.next_f64() >= opts.rate || record.text.is_empty() {
+        return Ok(false);
+    }
+    let spm = rng.next_f64() < opts.spm_rate;
+    let mut boundaries: Vec<usize> = match opts.level {
+        SplitLevel::Char => record.text.char_indices().map(|(i, _)| i).collect(),
+        SplitLevel::Token => tokenizers.token_starts(&record.text)?,
+    };
+    if boundaries.first() != Some(&0) {
+        boundaries.insert(0, 0);
+    }
+    boundaries.push(record.text.len());
+    rewrite(record, &boundaries, spm, &mut rng, opts);
+    // Recounted with the --tokenizer ones, without any the counts are dropped
+    if let Some(tokens) = tokenizers.count_batch(&[record.text.as_str()])?.pop() {
+        record.tokens = tokens;
+    }
+    Ok(true)
+}
+
+// Transforms one input file into the same relative path under `dest_dir`,
+// returns its shard entry
+fn fim_file(
+    path: &Path,
+    input_dir: &Path,
+    dest_dir: &Path,
+    repos: Option<&HashSet<String>>,
+    opts: &FimOptions,
+    tokenizers: &Tokenizers,
+) -> Result<ShardEntry, FimError> {
+    let rel_path = path.strip_prefix(input_dir).unwrap_or(path);
+    let Some((format, name)) = rel_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(Format::split_name)
+    else {
+        return Err(FimError::Validation {
+            message: format!("Unable to parse filename of path {}", path.display()),
+        });
+    };
+    let dir = dest_dir.join(rel_path.parent().unwrap_or(Path::new("")));
+    let output = SinkOptions {
+        format,
+        row_group_size: opts.row_group_size,
+    };
+
+    let mut sink = RecordSink::create(&dir, name, &output)?;
+    let mut entry = ShardEntry {
+        file: rel_path.to_string_lossy().into_owned(),
+        ..Default::default()
+    };
+    for mut record in read_records(path)? {
+        if let Some(hs) = repos
+            && !record.repo_name.is_empty()
+            && !hs.contains(&record.repo_name.replace('/', "-"))
+        {
+            continue;
+        }
+        apply_fim(&mut record, opts, tokenizers)?;
+        entry.records += 1;
+        entry.bytes += record.text.len() as u64;
+        if !entry.repos.contains(&record.repo_name) {
+            entry.repos.push(record.repo_name.clone());
+        }
+        sink.write(record)?;
+    }
+    sink.finish()?;
+    Ok(entry)
+}
+
+// Writes every input file with a fraction of its records in FIM form,
+// keeping the layout, formats and shard index of the input
+pub fn fim_transform(
+    paths: &[PathBuf],
+    repos: Option<&HashSet<String>>,
+    input_dir: &Path,
+    dest_dir: &Path,
+    opts: &FimOptions,
+    tokenizers: &Tokenizers,
+) -> Result<usize, FimError> {
+    let entries: Vec<ShardEntry> = paths
+        .par_iter()
+        .map(|path| fim_file(path, input_dir, dest_dir, repos, opts, tokenizers))
+        .collect::<Result<_, _>>()?;
+
+    if let Some(mut index) = load_index(input_dir) {
+        for shard in index.shards.iter_mut() {
+            if let Some(entry) = entries.iter().find(|e| e.file == shard.file) {
+                shard.records = entry.records;
+                shard.bytes = entry.bytes;
+                shard.repos = entry.repos.clone();
+            }
+        }
+        write_index(dest_dir, &index)?;
+    } else {
+        // Per-repository inputs, drop the index of an earlier sharded run
+        remove_index(dest_dir)?;
+    }
+    Ok(entries.iter().map(|e| e.records).sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extractor::Record;
+    use crate::fim::{FimOptions, SplitLevel, apply_fim};
+    use crate::tokenization::Tokenizers;
+
+    fn record(id: &str, text: &str) -> Record {
+        let mut r = Record::for_test("", "a.py", "Python", text);
+        r.id = id.to_owned();
+        r
+    }
+
+    #[test]
+    fn test_apply_fim() {
+        let opts = FimOptions {
+            rate: 1.0,
+            spm_rate: 0.0,
+            seed: 42,
+            level: SplitLevel::Char,
+            prefix_token: String::from("<P>"),
+            middle_token: String::from("<M>"),
+            suffix_token: String::from("<S>"),
+            row_group_size: 1,
+        };
+        let tokenizers = Tokenizers::load(&[], true).unwrap();
+        let text = "def añadir(a, b):\n    return a + b\n";
+
+        let mut r = record("1", text);
+        assert!(apply_fim(&mut r, &opts, &tokenizers).unwrap());
+        assert_eq!(r.normalizations, ["fim-psm"]);
+        // Prefix, suffix and middle put back together give the original
+        let (prefix, rest) = r
+            .text
+            .strip_prefix("<P>")
+            .unwrap()
+            .split_once("<S>")
+            .unwrap();
+        let (suffix, middle) = rest.split_once("<M>").unwrap();
+        assert_eq!(format!("{prefix}{middle}{suffix}"), text);
+        // Fields derived from the text follow it
+        assert_eq!(r.content_hash, format!("{:x}", md5::compute(&r.text)));
+        assert_eq!(
+            r.max_line_length,
+            r.text.lines().map(|l| l.chars().count()).max().unwrap() as u64
+        );
+        assert!(r.tokens.is_empty());
+
+        // Same seed and id, same split
+        let mut again = record("1", text);
+        apply_fim(&mut again, &opts, &tokenizers).unwrap();
+        assert_eq!(again.text, r.text);
+
+        // Recounted when a tokenizer is given
+        let counting = Tokenizers::for_test(&["[UNK]", "def", "return"]);
+        let mut again = record("1", text);
+        apply_fim(&mut again, &opts, &counting).unwrap();
+        assert_eq!(again.text, r.text);
+        assert_eq!(
+            again.tokens["test"],
+            counting.count_batch(&[&again.text]).unwrap()[0]["test"]
+        );
+
+        let opts = FimOptions {
+            spm_rate: 1.0,
+            ..opts
+        };
+        let mut r = record("2", text);
+        apply_fim(&mut r, &opts, &tokenizers).unwrap();
+        assert!(r.text.starts_with("<S>"));
+
+        let opts = FimOptions { rate: 0.0, ..opts };
+        let mut r = record("3", text);
+        assert!(!apply_fim(&mut r, &opts, &tokenizers).unwrap());
+        assert_eq!(r.text, text);
+    }
+
+    #[test]
+    fn test_token_split() {
+        let opts = FimOptions {
+            rate: 1.0,
+            spm_rate: 0.0,
+            seed: 7,
+            level: SplitLevel::Token,
+            // Sentinels the test tokenizer splits off as words
+            prefix_token: String::from(" P "),
+            middle_token: String::from(" M "),
+            suffix_token: String::from(" S "),
+            row_group_size: 1,
+        };
+        let vocab = ["total", "=", "count", "+", "1", "P", "M", "S"];
+        let tokenizers = Tokenizers::for_test(&vocab);
+        let text = "total = count + 1\ncount = total + 1\n";
+        let starts = tokenizers.token_starts(text).unwrap();
+
+        for id in 0..20 {
+            let mut r = record(&id.to_string(), text);
+            apply_fim(&mut r, &opts, &tokenizers).unwrap();
+            let (prefix, rest) = r
+                .text
+                .strip_prefix(" P ")
+                .unwrap()
+                .split_once(" S ")
+                .unwrap();
+            let (_, middle) = rest.split_once(" M ").unwrap();
+            // Both split points are token starts or the ends of the text
+            for split in [prefix.len(), prefix.len() + middle.len()] {
+                assert!(split == 0 || split == text.len() || starts.contains(&split));
+            }
+            assert_eq!(
+                r.tokens["test"],
+                tokenizers.count_batch(&[&r.text]).unwrap()[0]["test"]
+            );
+        }
+    }
+}
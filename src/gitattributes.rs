@@ -0,0 +1,315 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use zip::ZipArchive;
+
+use crate::error::ExtractionError;
+use crate::extractor::repo_relative;
+
+const GITATTRIBUTES: &str = ".gitattributes";
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttrValue {
+    Set,         // attr
+    Unset,       // -attr
+    Unspecified, // !attr
+    Value(String),
+}
+
+impl AttrValue {
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            AttrValue::Set => Some(true),
+            AttrValue::Unset => Some(false),
+            AttrValue::Unspecified => None,
+            AttrValue::Value(v) => match v.as_str() {
+                "true" => Some(true),
+                "false" => Some(false),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    // Directory of the .gitattributes file relative to the repo root, "" or "a/b/"
+    base: String,
+    pattern: Vec<char>,
+    // Patterns without a slash match the basename at any depth below `base`
+    basename_only: bool,
+    attrs: Vec<(String, AttrValue)>,
+}
+
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        let Some(rel) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        let target = if self.basename_only {
+            rel.rsplit('/').next().unwrap_or(rel)
+        } else {
+            rel
+        };
+        let target: Vec<char> = target.chars().collect();
+        wildmatch(&self.pattern, &target)
+    }
+}
+
+// Linguist attributes resolved for a single path. `None` means the attribute
+// is not specified and Linguist's own rules apply.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Overrides {
+    pub vendored: Option<bool>,
+    pub generated: Option<bool>,
+    pub documentation: Option<bool>,
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct GitAttributes {
+    // Ordered from lowest to highest precedence
+    rules: Vec<Rule>,
+}
+
+impl GitAttributes {
+    pub fn from_zip(zip: &mut ZipArchive<BufReader<File>>) -> Result<Self, ExtractionError> {
+        let mut files: Vec<(String, String)> = Vec::new();
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            let rel = repo_relative(file.name());
+            if !file.is_file() || !(rel == GITATTRIBUTES || rel.ends_with("/.gitattributes")) {
+                continue;
+            }
+            let base = rel
+                .strip_suffix(GITATTRIBUTES)
+                .unwrap_or_default()
+                .to_owned();
+            let mut content = String::new();
+            if file.read_to_string(&mut content).is_ok() {
+                files.push((base, content));
+            }
+        }
+        Ok(Self::from_files(files))
+    }
+
+    // Deeper .gitattributes files take precedence over shallower ones,
+    // and within a file later lines take precedence over earlier ones.
+    pub fn from_files(mut files: Vec<(String, String)>) -> Self {
+        files.sort_by_key(|(base, _)| base.matches('/').count());
+        let rules = files
+            .iter()
+            .flat_map(|(base, content)| parse(base, content))
+            .collect();
+        Self { rules }
+    }
+
+    // `path` is relative to the repo root
+    pub fn overrides(&self, path: &str) -> Overrides {
+        let mut o = Overrides::default();
+        for rule in self.rules.iter().filter(|r| r.matches(path)) {
+            for (name, value) in &rule.attrs {
+                match name.as_str() {
+                    "linguist-vendored" => o.vendored = value.as_bool(),
+                    "linguist-generated" => o.generated = value.as_bool(),
+                    "linguist-documentation" => o.documentation = value.as_bool(),
+                    "linguist-language" => {
+                        o.language = match value {
+                            AttrValue::Value(v) => Some(v.to_owned()),
+                            _ => None,
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        o
+    }
+}
+
+fn parse(base: &str, content: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        // Comments and macro definitions
+        if line.is_empty() || line.starts_with('#') || line.starts_with("[attr]") {
+            continue;
+        }
+        let Some((pattern, rest)) = split_pattern(line) else {
+            continue;
+        };
+        // Negative patterns are forbidden and directory patterns never match files
+        if pattern.starts_with('!') || pattern.ends_with('/') {
+            continue;
+        }
+        let attrs: Vec<(String, AttrValue)> = rest
+            .split_whitespace()
+            .filter(|a| a.contains("linguist-"))
+            .map(parse_attr)
+            .collect();
+        if attrs.is_empty() {
+            continue;
+        }
+        let anchored = pattern.trim_start_matches('/');
+        rules.push(Rule {
+            base: base.to_owned(),
+            pattern: anchored.chars().collect(),
+            basename_only: !pattern.contains('/'),
+            attrs,
+        });
+    }
+    rules
+}
+
+fn split_pattern(line: &str) -> Option<(String, &str)> {
+    // Quoted patterns may contain spaces
+    if let Some(quoted) = line.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        let pattern = quoted[..end].replace("\\\"", "\"").replace("\\\\", "\\");
+        return Some((pattern, &quoted[end + 1..]));
+    }
+    match line.split_once(char::is_whitespace) {
+        Some((p, rest)) => Some((p.to_owned(), rest)),
+        None => Some((line.to_owned(), "")),
+    }
+}
+
+fn parse_attr(attr: &str) -> (String, AttrValue) {
+    if let Some(name) = attr.strip_prefix('-') {
+        (name.to_owned(), AttrValue::Unset)
+    } else if let Some(name) = attr.strip_prefix('!') {
+        (name.to_owned(), AttrValue::Unspecified)
+    } else if let Some((name, value)) = attr.split_once('=') {
+        (name.to_owned(), AttrValue::Value(value.to_owned()))
+    } else {
+        (attr.to_owned(), AttrValue::Set)
+    }
+}
+
+// Git's wildmatch with WM_PATHNAME: `*`, `?` and `[...]` never match '/',
+// `**/`, `/**/` and `/**` match across directories.
+fn wildmatch(p: &[char], t: &[char]) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        Some('*') if p.get(1) == Some(&'*') => {
+            let rest = &p[2..];
+            if let Some(rest) = rest.strip_prefix(&['/']) {
+                // Zero or more leading directories
+                wildmatch(rest, t)
+                    || (0..t.len()).any(|i| t[i] == '/' && wildmatch(rest, &t[i + 1..]))
+            } else {
+                (0..=t.len()).any(|i| wildmatch(rest, &t[i..]))
+            }
+        }
+        Some('*') => {
+            let rest = &p[1..];
+            for i in 0..=t.len() {
+                if wildmatch(rest, &t[i..]) {
+                    return true;
+                }
+                if i < t.len() && t[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => t.first().is_some_and(|c| *c != '/') && wildmatch(&p[1..], &t[1..]),
+        Some('[') => match (t.first(), match_class(&p[1..], t.first().copied())) {
+            (Some(_), Some((true, consumed))) => wildmatch(&p[1 + consumed..], &t[1..]),
+            // Unterminated class, treat '[' literally
+            (Some('['), None) => wildmatch(&p[1..], &t[1..]),
+            _ => false,
+        },
+        Some('\\') if p.len() > 1 => t.first() == Some(&p[1]) && wildmatch(&p[2..], &t[1..]),
+        Some(c) => t.first() == Some(c) && wildmatch(&p[1..], &t[1..]),
+    }
+}
+
+// Matches `c` against a bracket expression starting right after '['.
+// Returns whether it matched and how many pattern chars were consumed.
+fn match_class(p: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let c = c?;
+    let mut i = 0;
+    let negate = matches!(p.first(), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let cur = *p.get(i)?;
+        if cur == ']' && !first {
+            break;
+        }
+        first = false;
+        if p.get(i + 1) == Some(&'-') && p.get(i + 2).is_some_and(|e| *e != ']') {
+            if (cur..=p[i + 2]).contains(&c) {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if cur == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    Some((matched != negate && c != '/', i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gitattributes::{GitAttributes, wildmatch};
+
+    fn wm(p: &str, t: &str) -> bool {
+        let p: Vec<char> = p.chars().collect();
+        let t: Vec<char> = t.chars().collect();
+        wildmatch(&p, &t)
+    }
+
+    #[test]
+    fn test_wildmatch() {
+        assert!(wm("*.js", "app.js"));
+        assert!(!wm("*.js", "lib/app.js"));
+        assert!(wm("lib/**", "lib/a/b/c.js"));
+        assert!(wm("**/gen/*.go", "gen/a.go"));
+        assert!(wm("**/gen/*.go", "x/y/gen/a.go"));
+        assert!(wm("a/**/b.c", "a/b.c"));
+        assert!(wm("a/**/b.c", "a/x/y/b.c"));
+        assert!(wm("file?.[ch]", "file1.h"));
+        assert!(!wm("file?.[!ch]", "file1.h"));
+        assert!(wm("[a-c]*.v", "bus.v"));
+    }
+
+    #[test]
+    fn test_overrides_precedence() {
+        let attrs = GitAttributes::from_files(vec![
+            (
+                "rtl/".to_string(),
+                "ip/** -linguist-vendored\n*.vh linguist-language=Verilog\n".to_string(),
+            ),
+            (
+                "".to_string(),
+                "rtl/ip/** linguist-vendored\n*.vh linguist-language=SystemVerilog\n\
+                 docs/** linguist-documentation\ndocs/api.py !linguist-documentation\n"
+                    .to_string(),
+            ),
+        ]);
+
+        // Deeper file wins over the root one
+        let ip = attrs.overrides("rtl/ip/fifo.v");
+        assert_eq!(ip.vendored, Some(false));
+        assert_eq!(
+            attrs.overrides("rtl/defs.vh").language.as_deref(),
+            Some("Verilog")
+        );
+        assert_eq!(
+            attrs.overrides("tb/defs.vh").language.as_deref(),
+            Some("SystemVerilog")
+        );
+        // Later line wins within the same file
+        assert_eq!(attrs.overrides("docs/conf.py").documentation, Some(true));
+        assert_eq!(attrs.overrides("docs/api.py").documentation, None);
+        assert_eq!(attrs.overrides("src/main.rs"), Default::default());
+    }
+}
@@ -0,0 +1,515 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::extractor::Record;
+
+static C_INCLUDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?m)^\s*#\s*include\s*[<"]([^>"]+)[>"]"#).unwrap());
+static VERILOG_INCLUDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"`include\s+"([^"]+)""#).unwrap());
+static VERILOG_MODULE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*(?:macro)?module\s+([A-Za-z_]\w*)").unwrap());
+// `counter #(.W(8)) u_counter (` or `counter u_counter(`
+static VERILOG_INSTANCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*([A-Za-z_]\w*)\s*(?:#\s*\((?s:.*?)\)\s*)?\s([A-Za-z_]\w*)\s*\(").unwrap()
+});
+static PY_IMPORT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*import\s+([^#\n]+)").unwrap());
+static PY_FROM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*from\s+(\.*)([\w.]*)\s+import\s+\(?([^#\n)]+)").unwrap());
+static RUST_MOD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?mod\s+([A-Za-z_]\w*)\s*;").unwrap()
+});
+static RUST_USE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?use\s+((?:crate|super|self)(?:::\w+)+)").unwrap()
+});
+static JS_IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?:\bfrom\s*|\bimport\s*\(?\s*|\brequire\s*\(\s*)['"](\.{1,2}/[^'"]*)['"]"#)
+        .unwrap()
+});
+static GO_IMPORT_BLOCK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*import\s*\(([^)]*)\)").unwrap());
+static GO_IMPORT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?m)^\s*import\s+(?:[\w.]+\s+)?"([^"]+)""#).unwrap());
+static GO_QUOTED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#""([^"]+)""#).unwrap());
+static JAVA_IMPORT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*import\s+(?:static\s+)?([\w.]+?)(\.\*)?\s*;").unwrap());
+
+const JS_EXTENSIONS: &[&str] = &[".ts", ".tsx", ".js", ".jsx", ".mjs", ".cjs"];
+
+// Files of a repository and the files each of them imports or includes,
+// both as indices into the records the graph was built from
+pub struct ImportGraph {
+    pub deps: Vec<BTreeSet<usize>>,
+}
+
+#[derive(Serialize)]
+pub struct ImportEdge {
+    pub from: String,
+    pub to: String,
+}
+
+// Sidecar written next to the repository documents
+#[derive(Serialize)]
+pub struct ImportSidecar {
+    pub repo_name: String,
+    // Dependency order, imported files before the files that use them
+    pub files: Vec<String>,
+    // `from` imports, includes or instantiates `to`
+    pub edges: Vec<ImportEdge>,
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+fn join(dir: &str, rel: &str) -> String {
+    if dir.is_empty() {
+        rel.to_owned()
+    } else {
+        format!("{dir}/{rel}")
+    }
+}
+
+// Resolves "." and ".." segments, None when leaving the repository
+fn normalize(path: &str) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            p => parts.push(p),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+// Repository paths, with lookups by full path, by suffix and by directory
+struct PathIndex<'a> {
+    by_path: HashMap<&'a str, usize>,
+    by_name: HashMap<&'a str, Vec<usize>>,
+    by_dir: HashMap<&'a str, Vec<usize>>,
+    paths: Vec<&'a str>,
+}
+
+impl<'a> PathIndex<'a> {
+    fn new(records: &'a [Record]) -> Self {
+        let mut index = Self {
+            by_path: HashMap::new(),
+            by_name: HashMap::new(),
+            by_dir: HashMap::new(),
+            paths: Vec::new(),
+        };
+        for (i, r) in records.iter().enumerate() {
+            let path = r.path.as_str();
+            let name = path.rsplit('/').next().unwrap_or(path);
+            index.by_path.insert(path, i);
+            index.by_name.entry(name).or_default().push(i);
+            index.by_dir.entry(parent(path)).or_default().push(i);
+            index.paths.push(path);
+        }
+        index
+    }
+
+    fn exact(&self, path: &str) -> Option<usize> {
+        self.by_path.get(path).copied()
+    }
+
+    // First file whose path is `suffix` or ends with "/<suffix>"
+    fn suffix(&self, suffix: &str) -> Option<usize> {
+        let name = suffix.rsplit('/').next().unwrap_or(suffix);
+        self.by_name.get(name)?.iter().copied().find(|&i| {
+            let path = self.paths[i];
+            path == suffix || path.strip_suffix(suffix).is_some_and(|p| p.ends_with('/'))
+        })
+    }
+
+    // Files of the first directory that is `suffix` or ends with "/<suffix>"
+    fn dir_suffix(&self, suffix: &str, extension: &str) -> Vec<usize> {
+        let dir = self
+            .by_dir
+            .keys()
+            .filter(|d| **d == suffix || d.strip_suffix(suffix).is_some_and(|p| p.ends_with('/')))
+            .min();
+        dir.map(|d| {
+            self.by_dir[d]
+                .iter()
+                .copied()
+                .filter(|&i| self.paths[i].ends_with(extension))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+}
+
+fn include_deps(path: &str, included: &str, index: &PathIndex) -> Option<usize> {
+    normalize(&join(parent(path), included))
+        .and_then(|p| index.exact(&p))
+        .or_else(|| index.suffix(included.trim_start_matches("./")))
+}
+
+fn python_deps(path: &str, text: &str, index: &PathIndex, deps: &mut BTreeSet<usize>) {
+    let module = |dir: &str, name: &str| {
+        let rel = name.replace('.', "/");
+        let candidates = [format!("{rel}.py"), format!("{rel}/__init__.py")];
+        candidates.iter().find_map(|c| {
+            if dir.is_empty() {
+                index.suffix(c)
+            } else {
+                index.exact(&join(dir, c))
+            }
+        })
+    };
+    for cap in PY_IMPORT.captures_iter(text) {
+        for name in cap[1].split(',') {
+            if let Some(name) = name.split_whitespace().next() {
+                deps.extend(module("", name));
+            }
+        }
+    }
+    for cap in PY_FROM.captures_iter(text) {
+        let dots = cap[1].len();
+        // "from . import x" is relative to the package of the file
+        let mut base = parent(path).to_owned();
+        for _ in 1..dots {
+            base = parent(&base).to_owned();
+        }
+        let base = if dots == 0 { "" } else { base.as_str() };
+        let package = &cap[2];
+        let mut found = !package.is_empty() && {
+            let dep = module(base, package);
+            deps.extend(dep);
+            dep.is_some()
+        };
+        // Imported names may be submodules of the package
+        for name in cap[3].split(',') {
+            if let Some(name) = name.split_whitespace().next()
+                && name != "*"
+            {
+                let full = if package.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{package}.{name}")
+                };
+                if let Some(dep) = module(base, &full) {
+                    deps.insert(dep);
+                    found = true;
+                }
+            }
+        }
+        if !found && dots > 0 && package.is_empty() {
+            deps.extend(index.exact(&join(base, "__init__.py")));
+        }
+    }
+}
+
+// Directory holding the submodules of a Rust module file
+fn rust_module_dir(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    if matches!(name, "lib.rs" | "main.rs" | "mod.rs") {
+        parent(path).to_owned()
+    } else {
+        path.trim_end_matches(".rs").to_owned()
+    }
+}
+
+fn rust_module(dir: &str, segments: &[&str], index: &PathIndex) -> Option<usize> {
+    // "crate::a::b::Item" may name a module a/b.rs or an item of a.rs
+    (1..=segments.len()).rev().find_map(|n| {
+        let rel = join(dir, &segments[..n].join("/"));
+        index
+            .exact(&format!("{rel}.rs"))
+            .or_else(|| index.exact(&format!("{rel}/mod.rs")))
+    })
+}
+
+fn rust_deps(path: &str, text: &str, index: &PathIndex, deps: &mut BTreeSet<usize>) {
+    let module_dir = rust_module_dir(path);
+    for cap in RUST_MOD.captures_iter(text) {
+        deps.extend(rust_module(&module_dir, &[&cap[1]], index));
+    }
+    // Crate root: the "src" directory of the file, or its own directory
+    let crate_root = match path.rfind("src/") {
+        Some(i) if i == 0 || path[..i].ends_with('/') => &path[..i + 3],
+        _ => parent(path),
+    };
+    for cap in RUST_USE.captures_iter(text) {
+        let segments: Vec<&str> = cap[1].split("::").collect();
+        let (mut dir, mut rest) = match segments[0] {
+            "crate" => (crate_root.to_owned(), &segments[1..]),
+            "self" => (module_dir.clone(), &segments[1..]),
+            _ => (parent(&module_dir).to_owned(), &segments[1..]),
+        };
+        while rest.first() == Some(&"super") {
+            dir = parent(&dir).to_owned();
+            rest = &rest[1..];
+        }
+        if !rest.is_empty() {
+            deps.extend(rust_module(&dir, rest, index));
+        }
+    }
+}
+
+fn js_deps(path: &str, text: &str, index: &PathIndex, deps: &mut BTreeSet<usize>) {
+    for cap in JS_IMPORT.captures_iter(text) {
+        let Some(target) = normalize(&join(parent(path), &cap[1])) else {
+            continue;
+        };
+        let dep = index.exact(&target).or_else(|| {
+            JS_EXTENSIONS.iter().find_map(|ext| {
+                index
+                    .exact(&format!("{target}{ext}"))
+                    .or_else(|| index.exact(&format!("{target}/index{ext}")))
+            })
+        });
+        deps.extend(dep);
+    }
+}
+
+fn go_deps(text: &str, index: &PathIndex, deps: &mut BTreeSet<usize>) {
+    let mut imports: Vec<&str> = GO_IMPORT
+        .captures_iter(text)
+        .filter_map(|c| c.get(1))
+        .map(|m| m.as_str())
+        .collect();
+    for block in GO_IMPORT_BLOCK.captures_iter(text) {
+        let block = block.get(1).map_or("", |m| m.as_str());
+        imports.extend(
+            GO_QUOTED
+                .captures_iter(block)
+                .filter_map(|c| c.get(1))
+                .map(|m| m.as_str()),
+        );
+    }
+    // "github.com/user/repo/pkg/util" is the package in "pkg/util/"
+    for import in imports {
+        let segments: Vec<&str> = import.split('/').collect();
+        for n in (1..=segments.len()).rev() {
+            let files = index.dir_suffix(&segments[segments.len() - n..].join("/"), ".go");
+            if !files.is_empty() {
+                deps.extend(files);
+                break;
+            }
+        }
+    }
+}
+
+fn java_deps(text: &str, index: &PathIndex, deps: &mut BTreeSet<usize>) {
+    for cap in JAVA_IMPORT.captures_iter(text) {
+        let rel = cap[1].replace('.', "/");
+        if cap.get(2).is_some() {
+            deps.extend(index.dir_suffix(&rel, ".java"));
+        } else {
+            // Static imports name a member of the class
+            let dep = index
+                .suffix(&format!("{rel}.java"))
+                .or_else(|| index.suffix(&format!("{}.java", parent(&rel))));
+            deps.extend(dep);
+        }
+    }
+}
+
+// Import graph of the files of one repository, from import, use, include
+// and Verilog module instantiation statements resolved to repository files
+pub fn build_graph(records: &[Record]) -> ImportGraph {
+    let index = PathIndex::new(records);
+
+    // Verilog modules are instantiated by name, whatever file defines them
+    let mut modules: HashMap<&str, usize> = HashMap::new();
+    for (i, r) in records.iter().enumerate() {
+        if matches!(r.language.as_str(), "Verilog" | "SystemVerilog") {
+            for cap in VERILOG_MODULE.captures_iter(&r.text) {
+                if let Some(name) = cap.get(1) {
+                    modules.entry(name.as_str()).or_insert(i);
+                }
+            }
+        }
+    }
+
+    let deps = records
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let mut deps = BTreeSet::new();
+            let (path, text) = (r.path.as_str(), r.text.as_str());
+            match r.language.as_str() {
+                "C" | "C++" | "Objective-C" | "Objective-C++" | "Cuda" => {
+                    for cap in C_INCLUDE.captures_iter(text) {
+                        deps.extend(include_deps(path, &cap[1], &index));
+                    }
+                }
+                "Verilog" | "SystemVerilog" => {
+                    for cap in VERILOG_INCLUDE.captures_iter(text) {
+                        deps.extend(include_deps(path, &cap[1], &index));
+                    }
+                    for cap in VERILOG_INSTANCE.captures_iter(text) {
+                        deps.extend(modules.get(&cap[1]));
+                    }
+                }
+                "Python" => python_deps(path, text, &index, &mut deps),
+                "Rust" => rust_deps(path, text, &index, &mut deps),
+                "JavaScript" | "TypeScript" | "TSX" => js_deps(path, text, &index, &mut deps),
+                "Go" => go_deps(text, &index, &mut deps),
+                "Java" => java_deps(text, &index, &mut deps),
+                _ => {}
+            }
+            deps.remove(&i);
+            deps
+        })
+        .collect();
+    ImportGraph { deps }
+}
+
+impl ImportGraph {
+    // Topological order, every file after the files it depends on. Cycles
+    // are broken at the first file reached, ties keep the original order.
+    pub fn dependency_order(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.deps.len());
+        let mut visited = vec![false; self.deps.len()];
+        for root in 0..self.deps.len() {
+            if visited[root] {
+                continue;
+            }
+            visited[root] = true;
+            // Iterative post-order, deep include chains would overflow the stack
+            let mut stack = vec![(root, self.deps[root].iter())];
+            while let Some((node, deps)) = stack.last_mut() {
+                match deps.find(|&&d| !visited[d]) {
+                    Some(&dep) => {
+                        visited[dep] = true;
+                        stack.push((dep, self.deps[dep].iter()));
+                    }
+                    None => {
+                        order.push(*node);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        order
+    }
+
+    pub fn sidecar(&self, records: &[Record], order: &[usize]) -> ImportSidecar {
+        let edges = self
+            .deps
+            .iter()
+            .enumerate()
+            .flat_map(|(from, deps)| {
+                deps.iter().map(move |&to| ImportEdge {
+                    from: records[from].path.clone(),
+                    to: records[to].path.clone(),
+                })
+            })
+            .collect();
+        ImportSidecar {
+            repo_name: records
+                .first()
+                .map(|r| r.repo_name.clone())
+                .unwrap_or_default(),
+            files: order.iter().map(|&i| records[i].path.clone()).collect(),
+            edges,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extractor::Record;
+    use crate::imports::build_graph;
+
+    fn record(path: &str, language: &str, text: &str) -> Record {
+        Record::for_test("a/x", path, language, text)
+    }
+
+    fn order(records: &[Record]) -> Vec<&str> {
+        let graph = build_graph(records);
+        graph
+            .dependency_order()
+            .iter()
+            .map(|&i| records[i].path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_dependency_order() {
+        let records = [
+            record("src/main.rs", "Rust", "mod cli;\nuse crate::util::parse;\n"),
+            record("src/cli.rs", "Rust", "use super::util;\n"),
+            record("src/util/mod.rs", "Rust", ""),
+            record(
+                "pkg/app.py",
+                "Python",
+                "from .models import User\nimport pkg.db\n",
+            ),
+            record("pkg/models.py", "Python", "from . import db\n"),
+            record("pkg/db.py", "Python", ""),
+            record(
+                "web/index.ts",
+                "TypeScript",
+                "import { a } from './lib/a';\n",
+            ),
+            record("web/lib/a.ts", "TypeScript", "const b = require('../b')\n"),
+            record("web/b.js", "JavaScript", ""),
+            record("c/main.c", "C", "#include \"util.h\"\n#include <stdio.h>\n"),
+            record("c/include/util.h", "C", ""),
+        ];
+        assert_eq!(
+            order(&records),
+            [
+                "src/util/mod.rs",
+                "src/cli.rs",
+                "src/main.rs",
+                "pkg/db.py",
+                "pkg/models.py",
+                "pkg/app.py",
+                "web/b.js",
+                "web/lib/a.ts",
+                "web/index.ts",
+                "c/include/util.h",
+                "c/main.c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verilog_graph() {
+        let records = [
+            record(
+                "rtl/top.sv",
+                "SystemVerilog",
+                "`include \"defs.svh\"\nmodule top;\n  counter #(.W(8)) u_counter (.clk(clk));\n  fifo u_fifo(.clk(clk));\nendmodule\n",
+            ),
+            record(
+                "rtl/counter.v",
+                "Verilog",
+                "module counter #(parameter W = 4) (input clk);\nendmodule\n",
+            ),
+            record(
+                "rtl/fifo.v",
+                "Verilog",
+                "module fifo (input clk);\n  counter c (.clk(clk));\nendmodule\n",
+            ),
+            record("include/defs.svh", "SystemVerilog", "`define W 8\n"),
+        ];
+        assert_eq!(
+            order(&records),
+            [
+                "rtl/counter.v",
+                "rtl/fifo.v",
+                "include/defs.svh",
+                "rtl/top.sv"
+            ]
+        );
+        // Cycles still order every file once
+        let records = [
+            record("a.py", "Python", "import b\n"),
+            record("b.py", "Python", "import a\n"),
+        ];
+        assert_eq!(order(&records), ["b.py", "a.py"]);
+    }
+}
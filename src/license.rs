@@ -0,0 +1,328 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::sync::LazyLock;
+
+use regex::Regex;
+use spdx::{Expression, LicenseItem};
+use zip::ZipArchive;
+
+use crate::encoding::{Content, decode};
+use crate::error::ExtractionError;
+use crate::extractor::repo_relative;
+
+// Licenses a license file is matched against, the ones most repositories
+// use. GNU texts do not say whether later versions apply, so they map to
+// the "-only" ids.
+const CANDIDATES: &[&str] = &[
+    "0BSD",
+    "AGPL-3.0-only",
+    "Apache-2.0",
+    "Artistic-2.0",
+    "BSD-2-Clause",
+    "BSD-3-Clause",
+    "BSL-1.0",
+    "CC-BY-4.0",
+    "CC-BY-SA-4.0",
+    "CC0-1.0",
+    "CDDL-1.0",
+    "EPL-1.0",
+    "EPL-2.0",
+    "EUPL-1.2",
+    "GPL-2.0-only",
+    "GPL-3.0-only",
+    "ISC",
+    "LGPL-2.1-only",
+    "LGPL-3.0-only",
+    "MIT",
+    "MIT-0",
+    "MPL-2.0",
+    "MS-PL",
+    "NCSA",
+    "OFL-1.1",
+    "PostgreSQL",
+    "Python-2.0",
+    "UPL-1.0",
+    "Unlicense",
+    "WTFPL",
+    "Zlib",
+];
+
+// Licenses `--licenses permissive` expands to
+pub const PERMISSIVE: &[&str] = &[
+    "0BSD",
+    "Apache-2.0",
+    "BSD-2-Clause",
+    "BSD-3-Clause",
+    "BSL-1.0",
+    "CC0-1.0",
+    "ISC",
+    "MIT",
+    "MIT-0",
+    "NCSA",
+    "PostgreSQL",
+    "Python-2.0",
+    "UPL-1.0",
+    "Unlicense",
+    "Zlib",
+];
+
+// License of a repository whose license files match no bundled text
+pub const NOASSERTION: &str = "NOASSERTION";
+
+// Dice coefficient of the word sets a license file must share with a
+// bundled text, low enough to absorb filled-in names and reflowed lines
+const MIN_SIMILARITY: f64 = 0.9;
+// Leading lines searched for an SPDX-License-Identifier header
+const HEADER_LINES: usize = 30;
+
+static SPDX_HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"SPDX-License-Identifier:\s*(.+)").unwrap());
+// Copyright lines differ between copies of the same license
+static COPYRIGHT_LINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)^\W*(?:copyright\b|\(c\)|©)").unwrap());
+// "Copyright (c) 2019-2024 Jane Doe", a notice needs a year or a (c) mark
+static COPYRIGHT_NOTICE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)^\W*copyright\s*(\(c\)|©)?\s*((?:\d{4}(?:\s*[-–,]\s*(?:\d{4}|present))*)?)[\s,]*(?:by\s+)?(.*)$",
+    )
+    .unwrap()
+});
+// E-mail addresses and URLs after a holder name
+static CONTACT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<[^>]*>|\(?https?://\S+\)?").unwrap());
+static RIGHTS_RESERVED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)all rights reserved.*").unwrap());
+static WORD: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[a-z0-9]+").unwrap());
+
+static BUNDLED: LazyLock<Vec<(&'static str, HashSet<String>)>> = LazyLock::new(|| {
+    CANDIDATES
+        .iter()
+        .filter_map(|id| spdx::license_id(id))
+        .map(|id| (id.name, words(id.text())))
+        .collect()
+});
+
+// Words of a license text, without copyright lines and in one spelling
+fn words(text: &str) -> HashSet<String> {
+    text.lines()
+        .filter(|l| !COPYRIGHT_LINE.is_match(l))
+        .flat_map(|l| {
+            let l = l.to_lowercase().replace("licence", "license");
+            WORD.find_iter(&l)
+                .map(|m| m.as_str().to_owned())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 0.0;
+    }
+    2.0 * a.intersection(b).count() as f64 / (a.len() + b.len()) as f64
+}
+
+// "LICENSE", "LICENSE-MIT", "COPYING.LESSER", "UNLICENSE", at the repository root
+pub fn is_license_file(rel_path: &str) -> bool {
+    if rel_path.contains('/') {
+        return false;
+    }
+    let name = rel_path.to_lowercase();
+    let stem = name.split(['.', '-', '_']).next().unwrap_or_default();
+    matches!(stem, "license" | "licence" | "copying" | "unlicense")
+}
+
+// SPDX id of a license file, None when no bundled text is close enough
+pub fn classify(text: &str) -> Option<&'static str> {
+    let words = words(text);
+    BUNDLED
+        .iter()
+        .map(|(id, bundled)| (*id, similarity(&words, bundled)))
+        .filter(|(_, score)| *score >= MIN_SIMILARITY)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(id, _)| id)
+}
+
+// License of a repository from its license files, "A AND B" when several
+// licenses apply and NOASSERTION when one of them is not recognized. None
+// without license files.
+pub fn repo_license(texts: &[String]) -> Option<String> {
+    if texts.is_empty() {
+        return None;
+    }
+    let ids: Option<BTreeSet<&str>> = texts.iter().map(|t| classify(t)).collect();
+    Some(match ids {
+        Some(ids) => ids.into_iter().collect::<Vec<_>>().join(" AND "),
+        None => NOASSERTION.to_owned(),
+    })
+}
+
+// Reads the root license files of an archive
+pub fn license_files(
+    zip: &mut ZipArchive<BufReader<File>>,
+) -> Result<Vec<String>, ExtractionError> {
+    let mut texts = Vec::new();
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        if !file.is_file() || !is_license_file(repo_relative(file.name())) {
+            continue;
+        }
+        // Latin-1 and UTF-16 license files are common in older projects
+        let mut bytes = Vec::new();
+        if file.read_to_end(&mut bytes).is_err() {
+            continue;
+        }
+        if let Content::Text { text, .. } = decode(&bytes) {
+            texts.push(text);
+        }
+    }
+    Ok(texts)
+}
+
+// Canonical SPDX expression of a file's SPDX-License-Identifier header
+pub fn file_license(text: &str) -> Option<String> {
+    let header = text
+        .lines()
+        .take(HEADER_LINES)
+        .find_map(|l| SPDX_HEADER.captures(l))?;
+    // Comment terminators of the header line, "*/", "-->", "*)"
+    let expr = header[1]
+        .split("*/")
+        .next()
+        .and_then(|e| e.split("-->").next())
+        .and_then(|e| e.split("*)").next())
+        .unwrap_or_default()
+        .trim();
+    let canonical = Expression::canonicalize(expr)
+        .ok()?
+        .unwrap_or_else(|| expr.to_owned());
+    Expression::parse(&canonical).ok()?;
+    Some(canonical)
+}
+
+// Holders named by the copyright notices in the leading lines of a text,
+// without years, contacts and template placeholders
+pub fn copyright_holders(text: &str) -> Vec<String> {
+    let mut holders: Vec<String> = Vec::new();
+    for line in text.lines().take(HEADER_LINES) {
+        let Some(caps) = COPYRIGHT_NOTICE.captures(line) else {
+            continue;
+        };
+        if caps.get(1).is_none() && caps[2].is_empty() {
+            continue;
+        }
+        let holder = CONTACT.replace_all(&caps[3], "");
+        let holder = RIGHTS_RESERVED.replace(&holder, "");
+        let holder = holder
+            .split("*/")
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_end_matches(['.', ',', ';', '*'])
+            .trim();
+        if holder.is_empty()
+            || holder.contains(['<', '[', '{'])
+            || holders.iter().any(|h| h == holder)
+        {
+            continue;
+        }
+        holders.push(holder.to_owned());
+    }
+    holders
+}
+
+// Holders of a license file, leaving out the ones its license text names
+// itself, like the Free Software Foundation in the GPL
+pub fn license_file_holders(text: &str) -> Vec<String> {
+    let bundled = classify(text)
+        .and_then(spdx::license_id)
+        .map(|id| copyright_holders(id.text()))
+        .unwrap_or_default();
+    copyright_holders(text)
+        .into_iter()
+        .filter(|h| !bundled.contains(h))
+        .collect()
+}
+
+// SPDX ids of `--licenses`, "permissive" expands to `PERMISSIVE`
+pub fn allowlist(names: &[String]) -> Result<HashSet<&'static str>, String> {
+    let mut ids = HashSet::new();
+    for name in names {
+        if name == "permissive" {
+            ids.extend(PERMISSIVE);
+            continue;
+        }
+        let id = spdx::license_id(name)
+            .or_else(|| spdx::imprecise_license_id(name).map(|(id, _)| id))
+            .ok_or_else(|| format!("unknown SPDX license id {name:?}"))?;
+        ids.insert(id.name);
+    }
+    Ok(ids)
+}
+
+// Whether a license expression can be satisfied with allowed licenses only,
+// an AND needs both sides and an OR either
+pub fn is_allowed(license: &str, allowed: &HashSet<&str>) -> bool {
+    Expression::parse(license).is_ok_and(|expr| {
+        expr.evaluate(|req| match &req.license {
+            LicenseItem::Spdx { id, .. } => allowed.contains(id.name),
+            LicenseItem::Other { .. } => false,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::license::{
+        NOASSERTION, classify, copyright_holders, file_license, is_allowed, license_file_holders,
+        repo_license,
+    };
+
+    #[test]
+    fn test_classify() {
+        let mit = spdx::license_id("MIT")
+            .unwrap()
+            .text()
+            .replace("<year> <copyright holders>", "2024 Jane Doe");
+        assert_eq!(classify(&mit), Some("MIT"));
+        let bsd = spdx::license_id("BSD-3-Clause").unwrap().text();
+        assert_eq!(classify(bsd), Some("BSD-3-Clause"));
+        assert_eq!(classify("All rights reserved."), None);
+
+        let texts = vec![mit, bsd.to_owned()];
+        assert_eq!(
+            repo_license(&texts).as_deref(),
+            Some("BSD-3-Clause AND MIT")
+        );
+        let texts = vec![String::from("Do what you want, but ask first.")];
+        assert_eq!(repo_license(&texts).as_deref(), Some(NOASSERTION));
+    }
+
+    #[test]
+    fn test_file_license() {
+        let text = "/* SPDX-License-Identifier: MIT OR Apache-2.0 */\nint x;\n";
+        assert_eq!(file_license(text).as_deref(), Some("MIT OR Apache-2.0"));
+        let text = "# SPDX-License-Identifier: GPL-2.0+\n";
+        assert_eq!(file_license(text).as_deref(), Some("GPL-2.0-or-later"));
+        assert_eq!(file_license("int x;\n"), None);
+
+        let allowed = HashSet::from(["MIT", "Apache-2.0"]);
+        assert!(is_allowed("MIT OR GPL-3.0-only", &allowed));
+        assert!(!is_allowed("MIT AND GPL-3.0-only", &allowed));
+        assert!(!is_allowed(NOASSERTION, &allowed));
+    }
+
+    #[test]
+    fn test_copyright_holders() {
+        let text = "// Copyright (c) 2019-2024, Jane Doe <jane@example.com>\n// Copyright 2020 The Go Authors. All rights reserved.\n//    copyright notice, this list of conditions\n";
+        assert_eq!(
+            copyright_holders(text),
+            vec!["Jane Doe".to_owned(), "The Go Authors".to_owned()]
+        );
+        let gpl = spdx::license_id("GPL-3.0-only").unwrap().text();
+        assert!(license_file_holders(gpl).is_empty());
+    }
+}
@@ -1,14 +1,37 @@
+mod attribution;
+mod chunk;
 mod cli;
+mod concat;
 mod config;
 mod deduplication;
+mod definitions;
 mod downloader;
+mod encoding;
 mod error;
+mod exclusion;
+mod export;
 mod extractor;
+mod file_class;
+mod fim;
+mod gitattributes;
+mod imports;
+mod license;
+mod metrics;
+mod normalize;
+mod notebook;
+mod quality;
+mod shard;
+mod sink;
 mod source;
+mod syntax;
+mod tokenization;
 
 pub mod commands;
 use cli::{Command, Opt};
-use config::{DedupeConfig, DownloadConfig, ExtractionConfig};
+use config::{
+    AttributionConfig, ConcatConfig, DedupeConfig, DefinitionsConfig, DownloadConfig, ExportConfig,
+    ExtractionConfig, FimConfig,
+};
 use structopt::StructOpt;
 
 #[tokio::main]
@@ -28,5 +51,25 @@ async fn main() {
             let config = DedupeConfig::from_cli(&opts.cmd);
             commands::dedupe(&config).await;
         }
+        Command::Export { .. } => {
+            let config = ExportConfig::from_cli(&opts.cmd);
+            commands::export(&config).await;
+        }
+        Command::Concat { .. } => {
+            let config = ConcatConfig::from_cli(&opts.cmd);
+            commands::concat(&config).await;
+        }
+        Command::Fim { .. } => {
+            let config = FimConfig::from_cli(&opts.cmd);
+            commands::fim(&config).await;
+        }
+        Command::Definitions { .. } => {
+            let config = DefinitionsConfig::from_cli(&opts.cmd);
+            commands::definitions(&config).await;
+        }
+        Command::Attribution { .. } => {
+            let config = AttributionConfig::from_cli(&opts.cmd);
+            commands::attribution(&config).await;
+        }
     }
 }
@@ -0,0 +1,99 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::quality::comment_mask;
+
+// Decision points of each language family, counted on code lines. Strings
+// are not skipped, so the complexity is an approximation.
+static C_STYLE_BRANCHES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(?:if|for|while|case|catch)\b|&&|\|\|").unwrap());
+static PYTHON_BRANCHES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(?:if|elif|for|while|except|case|and|or)\b").unwrap());
+static RUBY_BRANCHES: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(?:if|elsif|unless|while|until|for|when|rescue|and|or)\b|&&|\|\|").unwrap()
+});
+static SHELL_BRANCHES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(?:if|elif|for|while|until|case)\b|&&|\|\|").unwrap());
+static VERILOG_BRANCHES: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b(?:if|for|while|repeat|case|casex|casez)\b|&&|\|\|").unwrap());
+
+fn branches(language: &str) -> Option<&'static Regex> {
+    let re = match language {
+        "C" | "C++" | "C#" | "Cuda" | "Dart" | "Go" | "Groovy" | "Java" | "JavaScript"
+        | "Kotlin" | "Objective-C" | "Objective-C++" | "PHP" | "Rust" | "Scala" | "Swift"
+        | "TypeScript" | "TSX" | "Zig" => &C_STYLE_BRANCHES,
+        "Python" => &PYTHON_BRANCHES,
+        "Ruby" => &RUBY_BRANCHES,
+        "Shell" => &SHELL_BRANCHES,
+        "Verilog" | "SystemVerilog" => &VERILOG_BRANCHES,
+        _ => return None,
+    };
+    Some(re)
+}
+
+// Line counts and sizes of a file, lengths in characters
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Metrics {
+    pub lines: u64,
+    pub code_lines: u64,
+    // 0 when the comment syntax of the language is unknown
+    pub comment_lines: u64,
+    pub blank_lines: u64,
+    pub max_line_length: u64,
+    pub avg_line_length: f64,
+    // 1 + decision points of the whole file, None for unknown languages
+    pub complexity: Option<u64>,
+}
+
+impl Metrics {
+    pub fn compute(language: &str, text: &str) -> Self {
+        let lines: Vec<&str> = text.lines().collect();
+        let comments = comment_mask(language, text).unwrap_or_else(|| vec![false; lines.len()]);
+        let lengths: Vec<usize> = lines.iter().map(|l| l.chars().count()).collect();
+
+        let mut m = Metrics {
+            lines: lines.len() as u64,
+            max_line_length: lengths.iter().copied().max().unwrap_or(0) as u64,
+            avg_line_length: lengths.iter().sum::<usize>() as f64 / lengths.len().max(1) as f64,
+            ..Default::default()
+        };
+        let mut decisions = 0;
+        let re = branches(language);
+        for (line, is_comment) in lines.iter().zip(comments) {
+            if line.trim().is_empty() {
+                m.blank_lines += 1;
+            } else if is_comment {
+                m.comment_lines += 1;
+            } else {
+                m.code_lines += 1;
+                if let Some(re) = re {
+                    decisions += re.find_iter(line).count() as u64;
+                }
+            }
+        }
+        m.complexity = re.map(|_| 1 + decisions);
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_metrics() {
+        let text = "# Sign of x\ndef sign(x):\n\n    if x > 0 and x < 10:\n        return 1\n    elif x < 0:\n        return -1\n    return 0\n";
+        let m = Metrics::compute("Python", text);
+        assert_eq!(
+            (m.lines, m.code_lines, m.comment_lines, m.blank_lines),
+            (8, 6, 1, 1)
+        );
+        assert_eq!(m.max_line_length, 24);
+        // if, and, elif
+        assert_eq!(m.complexity, Some(4));
+
+        let m = Metrics::compute("Brainfuck", "+++\n\n");
+        assert_eq!((m.code_lines, m.blank_lines, m.complexity), (1, 1, None));
+    }
+}
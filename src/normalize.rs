@@ -0,0 +1,190 @@
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_TAB_WIDTH: usize = 4;
+
+// Opt-in text transforms, by default the decoded text is stored untouched.
+// They always run in declaration order, whatever order they are given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transform {
+    StripBom,
+    Crlf,
+    ExpandTabs(usize),
+    TrailingWhitespace,
+    Trim,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip-bom" => Ok(Transform::StripBom),
+            "crlf" => Ok(Transform::Crlf),
+            "expand-tabs" => Ok(Transform::ExpandTabs(DEFAULT_TAB_WIDTH)),
+            "trailing-whitespace" => Ok(Transform::TrailingWhitespace),
+            "trim" => Ok(Transform::Trim),
+            _ => match s.strip_prefix("expand-tabs=").map(str::parse) {
+                Some(Ok(width)) if width > 0 => Ok(Transform::ExpandTabs(width)),
+                _ => Err(format!(
+                    "unknown transform {s:?}, expected one of: strip-bom, crlf, \
+                     expand-tabs[=N], trailing-whitespace, trim"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::StripBom => write!(f, "strip-bom"),
+            Transform::Crlf => write!(f, "crlf"),
+            Transform::ExpandTabs(width) => write!(f, "expand-tabs={width}"),
+            Transform::TrailingWhitespace => write!(f, "trailing-whitespace"),
+            Transform::Trim => write!(f, "trim"),
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, text: &str) -> String {
+        match self {
+            Transform::StripBom => text.strip_prefix('\u{feff}').unwrap_or(text).to_owned(),
+            Transform::Crlf => text.replace("\r\n", "\n"),
+            Transform::ExpandTabs(width) => text
+                .split_inclusive('\n')
+                .map(|line| expand_tabs(line, *width))
+                .collect(),
+            Transform::TrailingWhitespace => text
+                .split_inclusive('\n')
+                .map(|line| {
+                    let (content, eol) = split_eol(line);
+                    format!("{}{eol}", content.trim_end_matches([' ', '\t']))
+                })
+                .collect(),
+            Transform::Trim => text.trim().to_owned(),
+        }
+    }
+}
+
+fn split_eol(line: &str) -> (&str, &str) {
+    let content = line.trim_end_matches(['\r', '\n']);
+    (content, &line[content.len()..])
+}
+
+fn expand_tabs(line: &str, width: usize) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = width - column % width;
+            out.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            out.push(c);
+            column += 1;
+        }
+    }
+    out
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Normalizer {
+    transforms: Vec<Transform>,
+}
+
+impl Normalizer {
+    // Of several expand-tabs widths the last one given wins, the others
+    // would find no tabs left
+    pub fn new(mut transforms: Vec<Transform>) -> Self {
+        let tab_width = transforms.iter().rev().find_map(|t| match t {
+            Transform::ExpandTabs(width) => Some(*width),
+            _ => None,
+        });
+        transforms.retain(|t| !matches!(t, Transform::ExpandTabs(w) if Some(*w) != tab_width));
+        transforms.sort();
+        transforms.dedup();
+        Self { transforms }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.transforms.iter().map(Transform::to_string).collect()
+    }
+
+    // Normalised text and the names of the transforms that changed it
+    pub fn apply(&self, mut text: String) -> (String, Vec<String>) {
+        let mut applied = Vec::new();
+        for t in &self.transforms {
+            let out = t.apply(&text);
+            if out != text {
+                applied.push(t.to_string());
+                text = out;
+            }
+        }
+        (text, applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::normalize::{Normalizer, Transform};
+
+    #[test]
+    fn test_transforms() {
+        let cases = [
+            (Transform::StripBom, "\u{feff}x = 1\n", "x = 1\n"),
+            (Transform::Crlf, "a\r\nb\r\n", "a\nb\n"),
+            (Transform::ExpandTabs(4), "\tx\n  \ty\n", "    x\n    y\n"),
+            (Transform::TrailingWhitespace, "a  \r\nb\t\nc", "a\r\nb\nc"),
+            (Transform::Trim, "\n\nfn main() {}\n\n", "fn main() {}"),
+        ];
+        for (t, text, expected) in cases {
+            let once = t.apply(text);
+            assert_eq!(once, expected, "{t}");
+            // Applying a transform again changes nothing
+            assert_eq!(t.apply(&once), once, "{t}");
+        }
+        assert_eq!("expand-tabs=2".parse(), Ok(Transform::ExpandTabs(2)));
+        assert!("expand-tabs=0".parse::<Transform>().is_err());
+    }
+
+    #[test]
+    fn test_normalizer() {
+        let normalizer = Normalizer::new(vec![
+            Transform::Trim,
+            Transform::Crlf,
+            Transform::StripBom,
+            Transform::TrailingWhitespace,
+        ]);
+        // Only the transforms that changed the text are listed, in order
+        let (text, applied) = normalizer.apply(String::from("\u{feff}a \r\nb\r\n"));
+        assert_eq!(text, "a\nb");
+        assert_eq!(
+            applied,
+            ["strip-bom", "crlf", "trailing-whitespace", "trim"]
+        );
+        let (text, applied) = normalizer.apply(String::from("a\nb \n"));
+        assert_eq!(text, "a\nb");
+        assert_eq!(applied, ["trailing-whitespace", "trim"]);
+        let (again, applied) = normalizer.apply(text.clone());
+        assert_eq!(again, text);
+        assert!(applied.is_empty());
+
+        // The last expand-tabs width wins
+        let normalizer = Normalizer::new(vec![
+            Transform::ExpandTabs(2),
+            Transform::Crlf,
+            Transform::ExpandTabs(8),
+            Transform::ExpandTabs(2),
+            Transform::ExpandTabs(8),
+        ]);
+        let (text, applied) = normalizer.apply(String::from("\tx\r\n"));
+        assert_eq!(text, format!("{}x\n", " ".repeat(8)));
+        assert_eq!(applied, ["crlf", "expand-tabs=8"]);
+
+        let (text, applied) = Normalizer::default().apply(String::from("a\r\n"));
+        assert_eq!(text, "a\r\n");
+        assert!(applied.is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use serde_json::Value;
+
+use crate::quality::comment_syntax;
+
+// Linguist language of .ipynb files
+pub const NOTEBOOK_LANGUAGE: &str = "Jupyter Notebook";
+pub const NOTEBOOK_EXTENSION: &str = ".ipynb";
+// Raw notebooks carry their outputs and images, so they are read up to this
+// size and --max-file-size applies to their script instead
+pub const MAX_NOTEBOOK_SIZE: u64 = 2u64.pow(25); // 32MB
+
+// Kernel language names as found in notebook metadata -> Linguist names
+const KERNEL_LANGUAGES: &[(&str, &str)] = &[
+    ("python", "Python"),
+    ("python3", "Python"),
+    ("r", "R"),
+    ("julia", "Julia"),
+    ("scala", "Scala"),
+    ("java", "Java"),
+    ("kotlin", "Kotlin"),
+    ("c", "C"),
+    ("c++", "C++"),
+    ("cpp", "C++"),
+    ("c#", "C#"),
+    ("csharp", "C#"),
+    ("f#", "F#"),
+    ("go", "Go"),
+    ("rust", "Rust"),
+    ("javascript", "JavaScript"),
+    ("typescript", "TypeScript"),
+    ("ruby", "Ruby"),
+    ("haskell", "Haskell"),
+    ("octave", "MATLAB"),
+    ("matlab", "MATLAB"),
+    ("bash", "Shell"),
+    ("sh", "Shell"),
+    ("powershell", "PowerShell"),
+    ("sql", "SQL"),
+    ("sas", "SAS"),
+    ("wolfram language", "Mathematica"),
+];
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NotebookOptions {
+    // Keep markdown cells as comments of the kernel language
+    pub keep_markdown: bool,
+}
+
+// Script form of a notebook
+#[derive(Debug, PartialEq)]
+pub struct Script {
+    pub text: String,
+    pub language: String,
+}
+
+// Cell sources are either a string or a list of lines
+fn cell_source(cell: &Value) -> String {
+    match &cell["source"] {
+        Value::String(s) => s.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+fn kernel_language(notebook: &Value) -> String {
+    let metadata = &notebook["metadata"];
+    let name = metadata["kernelspec"]["language"]
+        .as_str()
+        .or_else(|| metadata["language_info"]["name"].as_str())
+        .unwrap_or("python");
+    let lower = name.to_lowercase();
+    KERNEL_LANGUAGES
+        .iter()
+        .find(|(kernel, _)| *kernel == lower)
+        .map_or(name, |(_, language)| language)
+        .to_owned()
+}
+
+// Code cells of a notebook joined by blank lines, with outputs dropped.
+// IPython magics and shell escapes are commented out so Python notebooks
+// stay valid scripts. None when the file is not a notebook.
+pub fn to_script(text: &str, opts: &NotebookOptions) -> Option<Script> {
+    let notebook: Value = serde_json::from_str(text).ok()?;
+    let cells = notebook["cells"].as_array()?;
+    let language = kernel_language(&notebook);
+    let comment = comment_syntax(&language).map_or("#", |s| s.line[0]);
+
+    let mut blocks: Vec<String> = Vec::new();
+    for cell in cells {
+        let source = cell_source(cell);
+        if source.trim().is_empty() {
+            continue;
+        }
+        let block = match cell["cell_type"].as_str() {
+            Some("code") if language == "Python" => source
+                .lines()
+                .map(|l| {
+                    if l.starts_with('%') || l.starts_with('!') {
+                        format!("{comment} {l}")
+                    } else {
+                        l.to_owned()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Some("code") => source.trim_end().to_owned(),
+            Some("markdown") if opts.keep_markdown => source
+                .trim_end()
+                .lines()
+                .map(|l| format!("{comment} {l}").trim_end().to_owned())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => continue,
+        };
+        blocks.push(block.trim_end().to_owned());
+    }
+    let mut text = blocks.join("\n\n");
+    text.push('\n');
+    Some(Script { text, language })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::notebook::{NotebookOptions, to_script};
+
+    const NOTEBOOK: &str = r##"{
+        "metadata": {"kernelspec": {"name": "python3", "language": "python"}},
+        "cells": [
+            {"cell_type": "markdown", "source": ["# Title\n", "Some text"]},
+            {"cell_type": "code", "source": ["%matplotlib inline\n", "import numpy as np"],
+             "outputs": [{"data": {"image/png": "iVBORw0KGgo="}}]},
+            {"cell_type": "code", "source": "x = np.zeros(3)\n", "outputs": []},
+            {"cell_type": "raw", "source": "raw text"}
+        ]
+    }"##;
+
+    #[test]
+    fn test_to_script() {
+        let script = to_script(NOTEBOOK, &NotebookOptions::default()).unwrap();
+        assert_eq!(script.language, "Python");
+        assert_eq!(
+            script.text,
+            "# %matplotlib inline\nimport numpy as np\n\nx = np.zeros(3)\n"
+        );
+
+        let opts = NotebookOptions {
+            keep_markdown: true,
+        };
+        let script = to_script(NOTEBOOK, &opts).unwrap();
+        assert!(
+            script
+                .text
+                .starts_with("# # Title\n# Some text\n\n# %matplotlib")
+        );
+
+        let julia = r#"{"metadata": {"language_info": {"name": "julia"}}, "cells": []}"#;
+        assert_eq!(to_script(julia, &opts).unwrap().language, "Julia");
+        assert_eq!(to_script("not json", &opts), None);
+    }
+}
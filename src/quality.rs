@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use yaml_rust::{Yaml, YamlLoader};
+
+use crate::error::ExtractionError;
+
+// Thresholds used when the quality config file is missing
+const BUILTIN_CONFIG: &str = include_str!("../configs/quality.yml");
+
+// Encoded data patterns from the StarCoder preprocessing pipeline
+static BASE64_BLOB: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[a-zA-Z0-9+/\n=]{64,}").unwrap());
+static HEX_BLOB: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:\b(?:0x|\\x)?[0-9a-fA-F]{2}(?:,|\b\s*)){8,}").unwrap());
+static UNICODE_BLOB: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:\\u[0-9a-fA-F]{4}){8,}").unwrap());
+
+// Line comment prefixes and block comment delimiters per language
+pub struct CommentSyntax {
+    pub line: &'static [&'static str],
+    pub block: Option<(&'static str, &'static str)>,
+}
+
+const C_STYLE: CommentSyntax = CommentSyntax {
+    line: &["//"],
+    block: Some(("/*", "*/")),
+};
+const HASH: CommentSyntax = CommentSyntax {
+    line: &["#"],
+    block: None,
+};
+const DASHES: CommentSyntax = CommentSyntax {
+    line: &["--"],
+    block: None,
+};
+const SEMICOLON: CommentSyntax = CommentSyntax {
+    line: &[";"],
+    block: None,
+};
+const PERCENT: CommentSyntax = CommentSyntax {
+    line: &["%"],
+    block: None,
+};
+
+pub fn comment_syntax(language: &str) -> Option<&'static CommentSyntax> {
+    let syntax = match language {
+        "C" | "C++" | "C#" | "Cuda" | "Chisel" | "Dart" | "Go" | "Groovy" | "Java"
+        | "JavaScript" | "Kotlin" | "Objective-C" | "Objective-C++" | "Rust" | "Scala"
+        | "Solidity" | "Swift" | "SystemVerilog" | "TypeScript" | "TSX" | "Verilog" | "Zig" => {
+            &C_STYLE
+        }
+        "PHP" => &CommentSyntax {
+            line: &["//", "#"],
+            block: Some(("/*", "*/")),
+        },
+        "CMake" | "Dockerfile" | "Elixir" | "Julia" | "Makefile" | "Nim" | "Perl"
+        | "PowerShell" | "Python" | "R" | "Ruby" | "Shell" | "Tcl" => &HASH,
+        "Ada" | "Elm" | "Haskell" | "SQL" | "VHDL" => &DASHES,
+        "Lua" => &CommentSyntax {
+            line: &["--"],
+            block: Some(("--[[", "]]")),
+        },
+        "Assembly" | "Clojure" | "Common Lisp" | "Emacs Lisp" | "Scheme" => &SEMICOLON,
+        "Erlang" | "MATLAB" | "Prolog" | "TeX" => &PERCENT,
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+// Whether each line of `text` is a comment line, None if the comment
+// syntax is unknown
+pub fn comment_mask(language: &str, text: &str) -> Option<Vec<bool>> {
+    let syntax = comment_syntax(language)?;
+    let mut in_block = false;
+    let mut mask = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            mask.push(false);
+            continue;
+        }
+        if in_block {
+            mask.push(true);
+            if let Some((_, end)) = syntax.block
+                && line.contains(end)
+            {
+                in_block = false;
+            }
+            continue;
+        }
+        if syntax.line.iter().any(|p| line.starts_with(p)) {
+            mask.push(true);
+        } else if let Some((start, end)) = syntax.block
+            && let Some(rest) = line.strip_prefix(start)
+        {
+            mask.push(true);
+            in_block = !rest.contains(end);
+        } else {
+            mask.push(false);
+        }
+    }
+    Some(mask)
+}
+
+// Number of comment lines in `text`, None if the comment syntax is unknown
+pub fn count_comment_lines(language: &str, text: &str) -> Option<usize> {
+    comment_mask(language, text).map(|mask| mask.iter().filter(|c| **c).count())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityStats {
+    pub max_line_length: usize,
+    pub mean_line_length: f64,
+    pub alphanum_fraction: f64,
+    pub comment_fraction: Option<f64>,
+    pub encoded_fraction: f64,
+}
+
+impl QualityStats {
+    pub fn compute(language: &str, text: &str) -> Self {
+        let lengths: Vec<usize> = text.lines().map(|l| l.chars().count()).collect();
+        let n_chars = text.chars().count().max(1);
+        let non_blank = text.lines().filter(|l| !l.trim().is_empty()).count();
+
+        let alphanum = text.chars().filter(|c| c.is_alphanumeric()).count();
+        let encoded: usize = [&BASE64_BLOB, &HEX_BLOB, &UNICODE_BLOB]
+            .iter()
+            .flat_map(|re| re.find_iter(text))
+            .map(|m| m.as_str().chars().count())
+            .sum();
+        let comment_fraction = count_comment_lines(language, text)
+            .filter(|_| non_blank > 0)
+            .map(|c| c as f64 / non_blank as f64);
+
+        Self {
+            max_line_length: lengths.iter().copied().max().unwrap_or(0),
+            mean_line_length: lengths.iter().sum::<usize>() as f64 / lengths.len().max(1) as f64,
+            alphanum_fraction: alphanum as f64 / n_chars as f64,
+            comment_fraction,
+            encoded_fraction: (encoded as f64 / n_chars as f64).min(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Thresholds {
+    pub max_line_length: Option<usize>,
+    pub max_mean_line_length: Option<f64>,
+    pub min_alphanum_fraction: Option<f64>,
+    pub min_comment_fraction: Option<f64>,
+    pub max_comment_fraction: Option<f64>,
+    pub max_encoded_fraction: Option<f64>,
+}
+
+fn as_number(y: &Yaml) -> Option<f64> {
+    y.as_f64().or_else(|| y.as_i64().map(|i| i as f64))
+}
+
+// A missing key keeps `base`, a null one (`key: ~`) disables the check
+fn threshold<T>(y: &Yaml, key: &str, base: Option<T>, parse: fn(&Yaml) -> Option<T>) -> Option<T> {
+    match &y[key] {
+        Yaml::BadValue => base,
+        Yaml::Null => None,
+        v => parse(v),
+    }
+}
+
+impl Thresholds {
+    // Thresholds of a config section, keys it leaves out come from `base`
+    fn from_yaml(y: &Yaml, base: &Thresholds) -> Self {
+        Self {
+            max_line_length: threshold(y, "max_line_length", base.max_line_length, |v| {
+                v.as_i64().map(|v| v as usize)
+            }),
+            max_mean_line_length: threshold(
+                y,
+                "max_mean_line_length",
+                base.max_mean_line_length,
+                as_number,
+            ),
+            min_alphanum_fraction: threshold(
+                y,
+                "min_alphanum_fraction",
+                base.min_alphanum_fraction,
+                as_number,
+            ),
+            min_comment_fraction: threshold(
+                y,
+                "min_comment_fraction",
+                base.min_comment_fraction,
+                as_number,
+            ),
+            max_comment_fraction: threshold(
+                y,
+                "max_comment_fraction",
+                base.max_comment_fraction,
+                as_number,
+            ),
+            max_encoded_fraction: threshold(
+                y,
+                "max_encoded_fraction",
+                base.max_encoded_fraction,
+                as_number,
+            ),
+        }
+    }
+
+    // First threshold the stats fall outside of, formatted for the audit log
+    pub fn violation(&self, s: &QualityStats) -> Option<String> {
+        if let Some(max) = self.max_line_length
+            && s.max_line_length > max
+        {
+            return Some(format!("max_line_length>{max} ({})", s.max_line_length));
+        }
+        if let Some(max) = self.max_mean_line_length
+            && s.mean_line_length > max
+        {
+            return Some(format!(
+                "max_mean_line_length>{max} ({:.1})",
+                s.mean_line_length
+            ));
+        }
+        if let Some(min) = self.min_alphanum_fraction
+            && s.alphanum_fraction < min
+        {
+            return Some(format!(
+                "min_alphanum_fraction<{min} ({:.3})",
+                s.alphanum_fraction
+            ));
+        }
+        if let Some(cf) = s.comment_fraction {
+            if let Some(min) = self.min_comment_fraction
+                && cf < min
+            {
+                return Some(format!("min_comment_fraction<{min} ({cf:.3})"));
+            }
+            if let Some(max) = self.max_comment_fraction
+                && cf > max
+            {
+                return Some(format!("max_comment_fraction>{max} ({cf:.3})"));
+            }
+        }
+        if let Some(max) = self.max_encoded_fraction
+            && s.encoded_fraction > max
+        {
+            return Some(format!(
+                "max_encoded_fraction>{max} ({:.3})",
+                s.encoded_fraction
+            ));
+        }
+        None
+    }
+}
+
+// Enabled checks as "key=value", the way the config file names them
+impl fmt::Display for Thresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks: Vec<String> = [
+            ("max_line_length", self.max_line_length.map(|v| v as f64)),
+            ("max_mean_line_length", self.max_mean_line_length),
+            ("min_alphanum_fraction", self.min_alphanum_fraction),
+            ("min_comment_fraction", self.min_comment_fraction),
+            ("max_comment_fraction", self.max_comment_fraction),
+            ("max_encoded_fraction", self.max_encoded_fraction),
+        ]
+        .into_iter()
+        .filter_map(|(key, v)| v.map(|v| format!("{key}={v}")))
+        .collect();
+        if checks.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", checks.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct QualityFilter {
+    default: Thresholds,
+    languages: HashMap<String, Thresholds>,
+}
+
+impl QualityFilter {
+    pub fn load(path: &Path) -> Result<Self, ExtractionError> {
+        let fc = fs::read_to_string(path)?;
+        Self::parse(&fc, &path.display().to_string())
+    }
+
+    // The thresholds of configs/quality.yml as shipped
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_CONFIG, "built-in").expect("Unable to parse built-in quality config")
+    }
+
+    fn parse(config: &str, name: &str) -> Result<Self, ExtractionError> {
+        let docs = YamlLoader::load_from_str(config)?;
+        let Some(doc) = docs.first() else {
+            return Err(ExtractionError::Validation {
+                message: format!("Quality config {name} is empty"),
+            });
+        };
+        let default = Thresholds::from_yaml(&doc["default"], &Thresholds::default());
+        let mut languages = HashMap::new();
+        if let Some(langs) = doc["languages"].as_hash() {
+            for (k, v) in langs {
+                if let Some(lang) = k.as_str() {
+                    languages.insert(lang.to_owned(), Thresholds::from_yaml(v, &default));
+                }
+            }
+        }
+        Ok(Self { default, languages })
+    }
+
+    // Thresholds of every config section, "default" first and then the
+    // languages by name
+    pub fn sections(&self) -> Vec<(String, String)> {
+        let mut languages: Vec<_> = self.languages.iter().collect();
+        languages.sort_by(|a, b| a.0.cmp(b.0));
+        std::iter::once((String::from("default"), self.default.to_string()))
+            .chain(
+                languages
+                    .into_iter()
+                    .map(|(l, t)| (l.clone(), t.to_string())),
+            )
+            .collect()
+    }
+
+    pub fn thresholds(&self, language: &str) -> &Thresholds {
+        self.languages.get(language).unwrap_or(&self.default)
+    }
+
+    // Rejection reason for a file, None if it passes every threshold
+    pub fn check(&self, language: &str, text: &str) -> Option<String> {
+        let stats = QualityStats::compute(language, text);
+        self.thresholds(language).violation(&stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quality::{QualityFilter, QualityStats, Thresholds, count_comment_lines};
+
+    #[test]
+    fn test_comment_lines() {
+        let src = "// header\nfn main() {\n    /* block\n       still */\n    let a = 1; // trailing\n}\n";
+        assert_eq!(count_comment_lines("Rust", src), Some(3));
+        assert_eq!(
+            count_comment_lines("Python", "# a\nx = 1\n\n# b\n"),
+            Some(2)
+        );
+        assert_eq!(count_comment_lines("Brainfuck", "+++"), None);
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let t = Thresholds {
+            max_line_length: Some(1000),
+            min_alphanum_fraction: Some(0.25),
+            max_encoded_fraction: Some(0.5),
+            ..Default::default()
+        };
+        let code = "def add(a, b):\n    return a + b\n";
+        assert_eq!(t.violation(&QualityStats::compute("Python", code)), None);
+
+        let blob = format!("DATA = \"{}\"\n", "QUJD".repeat(64));
+        let v = t
+            .violation(&QualityStats::compute("Python", &blob))
+            .unwrap();
+        assert!(v.starts_with("max_encoded_fraction"));
+
+        let symbols = "{}[]();;,,..\n".repeat(10);
+        let v = t.violation(&QualityStats::compute("C", &symbols)).unwrap();
+        assert!(v.starts_with("min_alphanum_fraction"));
+    }
+
+    #[test]
+    fn test_language_thresholds() {
+        let config = "default:\n  max_line_length: 100\n  max_encoded_fraction: 0.5\nlanguages:\n  Verilog:\n    max_encoded_fraction: 0.7\n  JSON:\n    max_line_length: ~\n";
+        let filter = QualityFilter::parse(config, "test").unwrap();
+        // Keys a language leaves out are inherited, null ones disabled
+        let verilog = filter.thresholds("Verilog");
+        assert_eq!(verilog.max_line_length, Some(100));
+        assert_eq!(verilog.max_encoded_fraction, Some(0.7));
+        let json = filter.thresholds("JSON");
+        assert_eq!(json.max_line_length, None);
+        assert_eq!(json.max_encoded_fraction, Some(0.5));
+        assert_eq!(filter.thresholds("Rust").max_line_length, Some(100));
+
+        let builtin = QualityFilter::builtin();
+        assert_eq!(builtin.thresholds("Python").max_line_length, Some(1000));
+    }
+}
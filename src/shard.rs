@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::extractor::Record;
+use crate::sink::{RecordSink, SinkOptions};
+
+// Shards go to "<dest>/data/", or "<dest>/language=<name>/" when partitioned,
+// the index to "<dest>/shards.json"
+pub const SHARDS_DIR: &str = "data";
+pub const SHARD_INDEX: &str = "shards.json";
+const LANGUAGE_PARTITION: &str = "language=";
+
+// A shard is closed once it reaches either limit, without limits every
+// partition is written as a single part
+#[derive(Debug, Clone)]
+pub struct ShardOptions {
+    // Uncompressed text bytes
+    pub max_bytes: Option<u64>,
+    pub max_records: Option<usize>,
+    pub split: String,
+    // Hive-style "language=Rust/part-000.<ext>" layout
+    pub partition_by_language: bool,
+}
+
+impl ShardOptions {
+    // None keeps the one file per repository layout
+    pub fn new(
+        max_bytes: Option<u64>,
+        max_records: Option<usize>,
+        partition_by_language: bool,
+    ) -> Option<Self> {
+        if max_bytes.is_none() && max_records.is_none() && !partition_by_language {
+            return None;
+        }
+        Some(Self {
+            max_bytes,
+            max_records,
+            split: String::from("train"),
+            partition_by_language,
+        })
+    }
+
+    fn is_full(&self, shard: &ShardEntry, next_bytes: u64) -> bool {
+        shard.records > 0
+            && (self.max_records.is_some_and(|max| shard.records >= max)
+                || self
+                    .max_bytes
+                    .is_some_and(|max| shard.bytes + next_bytes > max))
+    }
+
+    // File name of shard `i` out of `total` of a split, without extension
+    fn shard_name(&self, split: &str, i: usize, total: usize) -> String {
+        if !self.partition_by_language {
+            format!("{split}-{i:05}-of-{total:05}")
+        } else if split == self.split {
+            format!("part-{i:03}")
+        } else {
+            format!("{split}-part-{i:03}")
+        }
+    }
+
+    fn is_shard_name(&self, name: &str) -> bool {
+        if self.partition_by_language {
+            name.starts_with("part-") || name.contains("-part-")
+        } else {
+            name.contains("-of-")
+        }
+    }
+}
+
+// Partition directory of a language. Values are percent-encoded the way
+// Hive readers decode them ("C#" -> "language=C%23").
+fn language_dir(language: &str) -> String {
+    let mut dir = String::from(LANGUAGE_PARTITION);
+    for b in language.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'+') {
+            dir.push(b as char);
+        } else {
+            dir.push_str(&format!("%{b:02X}"));
+        }
+    }
+    dir
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ShardEntry {
+    // Relative to the output directory, "data/train-00000-of-00128.parquet"
+    pub file: String,
+    pub split: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub records: usize,
+    pub bytes: u64,
+    // "<user>/<repo>" of every record in the shard, in write order
+    pub repos: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ShardIndex {
+    pub format: String,
+    pub shards: Vec<ShardEntry>,
+}
+
+impl ShardIndex {
+    pub fn records(&self) -> usize {
+        self.shards.iter().map(|s| s.records).sum()
+    }
+}
+
+// Shards of one output directory
+#[derive(Default)]
+struct Partition {
+    current: Option<(RecordSink<Record>, ShardEntry)>,
+    closed: Vec<(PathBuf, ShardEntry)>,
+}
+
+impl Partition {
+    fn close_shard(&mut self) -> io::Result<()> {
+        if let Some((sink, shard)) = self.current.take() {
+            self.closed.push((sink.close()?, shard));
+        }
+        Ok(())
+    }
+}
+
+// Writes records into numbered shards, one set per partition directory.
+// Shards stay at their temporary path until `finish`, when the total is
+// known and they are renamed to "<split>-00000-of-00128.<ext>" (or
+// "part-000.<ext>" inside language partitions).
+pub struct ShardedSink {
+    root: PathBuf,
+    opts: ShardOptions,
+    output: SinkOptions,
+    // Keyed by directory relative to `root` and split
+    partitions: BTreeMap<(String, String), Partition>,
+}
+
+impl ShardedSink {
+    pub fn create(dest_dir: &Path, opts: &ShardOptions, output: &SinkOptions) -> io::Result<Self> {
+        fs::create_dir_all(dest_dir)?;
+        Ok(Self {
+            root: dest_dir.to_path_buf(),
+            opts: opts.clone(),
+            output: *output,
+            partitions: BTreeMap::new(),
+        })
+    }
+
+    pub fn write(&mut self, record: Record) -> io::Result<()> {
+        let split = self.opts.split.clone();
+        self.write_split(&split, record)
+    }
+
+    // Writes to the shards of another split than the default one
+    pub fn write_split(&mut self, split: &str, record: Record) -> io::Result<()> {
+        let bytes = record.text.len() as u64;
+        let (dir, language) = if self.opts.partition_by_language {
+            (language_dir(&record.language), Some(&record.language))
+        } else {
+            (String::from(SHARDS_DIR), None)
+        };
+        let partition = self
+            .partitions
+            .entry((dir.clone(), split.to_owned()))
+            .or_default();
+        if let Some((_, shard)) = &partition.current
+            && self.opts.is_full(shard, bytes)
+        {
+            partition.close_shard()?;
+        }
+        if partition.current.is_none() {
+            let name = format!("{split}-{:05}", partition.closed.len());
+            let sink = RecordSink::create(&self.root.join(&dir), &name, &self.output)?;
+            let shard = ShardEntry {
+                split: split.to_owned(),
+                language: language.cloned(),
+                ..Default::default()
+            };
+            partition.current = Some((sink, shard));
+        }
+        let Some((sink, shard)) = &mut partition.current else {
+            unreachable!("a shard was just opened");
+        };
+        shard.records += 1;
+        shard.bytes += bytes;
+        if !shard.repos.contains(&record.repo_name) {
+            shard.repos.push(record.repo_name.clone());
+        }
+        sink.write(record)
+    }
+
+    // Renames the shards into place and writes the index, which is returned
+    pub fn finish(mut self) -> io::Result<ShardIndex> {
+        let ext = self.output.format.extension();
+        let mut index = ShardIndex {
+            format: ext.to_owned(),
+            shards: Vec::new(),
+        };
+        for ((dir, split), partition) in self.partitions.iter_mut() {
+            partition.close_shard()?;
+            let total = partition.closed.len();
+            for (i, (tmp_path, mut shard)) in std::mem::take(&mut partition.closed)
+                .into_iter()
+                .enumerate()
+            {
+                let file = format!("{dir}/{}.{ext}", self.opts.shard_name(split, i, total));
+                if let Err(e) = fs::rename(&tmp_path, self.root.join(&file)) {
+                    let _ = fs::remove_file(&tmp_path);
+                    return Err(e);
+                }
+                shard.file = file;
+                index.shards.push(shard);
+            }
+        }
+        self.remove_stale_shards(&index)?;
+
+        write_index(&self.root, &index)?;
+        Ok(index)
+    }
+
+    // Shards of a previous run that this one did not overwrite
+    fn remove_stale_shards(&self, index: &ShardIndex) -> io::Result<()> {
+        let suffix = format!(".{}", index.format);
+        for entry in fs::read_dir(&self.root)? {
+            let dir = entry?.path();
+            let Some(dir_name) = dir.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let is_partition = if self.opts.partition_by_language {
+                dir_name.starts_with(LANGUAGE_PARTITION)
+            } else {
+                dir_name == SHARDS_DIR
+            };
+            if !is_partition || !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                    continue;
+                };
+                let file = format!("{dir_name}/{name}");
+                if name.ends_with(&suffix)
+                    && self.opts.is_shard_name(name)
+                    && !index.shards.iter().any(|s| s.file == file)
+                {
+                    fs::remove_file(&path)?;
+                }
+            }
+            // Partitions of languages that are gone
+            let _ = fs::remove_dir(&dir);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ShardedSink {
+    fn drop(&mut self) {
+        // Dropped before `finish`: discard the closed shards
+        for partition in self.partitions.values() {
+            for (tmp_path, _) in &partition.closed {
+                let _ = fs::remove_file(tmp_path);
+            }
+        }
+    }
+}
+
+pub fn load_index(dir: &Path) -> Option<ShardIndex> {
+    let content = fs::read_to_string(dir.join(SHARD_INDEX)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+// Shard files listed in the index of a sharded output directory
+pub fn read_index(dir: &Path) -> Option<Vec<PathBuf>> {
+    let index = load_index(dir)?;
+    Some(index.shards.iter().map(|s| dir.join(&s.file)).collect())
+}
+
+// Removes the shards and index of a previous sharded run, which readers of
+// the directory would otherwise prefer over a per-repository output
+pub fn remove_index(dir: &Path) -> io::Result<()> {
+    let Some(index) = load_index(dir) else {
+        return Ok(());
+    };
+    for shard in &index.shards {
+        let path = dir.join(&shard.file);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Partition directories left empty
+        if let Some(parent) = path.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+    fs::remove_file(dir.join(SHARD_INDEX))
+}
+
+// Writes the index of a sharded output directory
+pub fn write_index(dir: &Path, index: &ShardIndex) -> io::Result<()> {
+    let tmp_path = dir.join(format!("{SHARD_INDEX}.tmp"));
+    fs::write(&tmp_path, serde_json::to_string_pretty(index)?)?;
+    fs::rename(tmp_path, dir.join(SHARD_INDEX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::extractor::Record;
+    use crate::shard::{ShardIndex, ShardOptions, ShardedSink};
+    use crate::sink::SinkOptions;
+
+    fn record(repo_name: &str, path: &str) -> Record {
+        let mut r = Record::for_test(repo_name, path, "Python", "x");
+        r.id = format!("{repo_name}:{path}");
+        r
+    }
+
+    #[test]
+    fn test_sharded_sink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let opts = ShardOptions::new(None, Some(2), false).unwrap();
+        let mut sink = ShardedSink::create(dir, &opts, &SinkOptions::default()).unwrap();
+        for (repo, path) in [
+            ("a/x", "1.py"),
+            ("a/x", "2.py"),
+            ("a/x", "3.py"),
+            ("b/y", "1.py"),
+        ] {
+            sink.write(record(repo, path)).unwrap();
+        }
+        assert_eq!(sink.finish().unwrap().records(), 4);
+
+        let index: ShardIndex =
+            serde_json::from_str(&fs::read_to_string(dir.join("shards.json")).unwrap()).unwrap();
+        let files: Vec<&str> = index.shards.iter().map(|s| s.file.as_str()).collect();
+        assert_eq!(
+            files,
+            [
+                "data/train-00000-of-00002.jsonl",
+                "data/train-00001-of-00002.jsonl"
+            ]
+        );
+        assert_eq!(index.shards[1].repos, ["a/x", "b/y"]);
+        assert!(dir.join(&index.shards[1].file).exists());
+    }
+
+    #[test]
+    fn test_language_partitions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let opts = ShardOptions::new(None, None, true).unwrap();
+        let mut sink = ShardedSink::create(dir, &opts, &SinkOptions::default()).unwrap();
+        for language in ["Rust", "C#", "Rust"] {
+            let mut r = record("a/x", "1");
+            r.language = language.to_owned();
+            sink.write(r).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let index: ShardIndex =
+            serde_json::from_str(&fs::read_to_string(dir.join("shards.json")).unwrap()).unwrap();
+        let files: Vec<(&str, usize)> = index
+            .shards
+            .iter()
+            .map(|s| (s.file.as_str(), s.records))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("language=C%23/part-000.jsonl", 1),
+                ("language=Rust/part-000.jsonl", 2)
+            ]
+        );
+        assert!(dir.join("language=Rust/part-000.jsonl").exists());
+    }
+}
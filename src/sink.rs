@@ -0,0 +1,406 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use polars::io::parquet::write::BatchedWriter;
+use polars::prelude::{
+    DataFrame, ParquetCompression, ParquetReader, ParquetWriter, PolarsError, PolarsResult,
+    SerReader,
+};
+use serde::Serialize;
+
+use crate::extractor::Record;
+
+// Rows per Parquet row group unless --row-group-size is given
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 10_000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Format {
+    #[default]
+    Jsonl,
+    JsonlGz,
+    JsonlZst,
+    Parquet,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [
+        Format::Jsonl,
+        Format::JsonlGz,
+        Format::JsonlZst,
+        Format::Parquet,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Jsonl => "jsonl",
+            Format::JsonlGz => "jsonl.gz",
+            Format::JsonlZst => "jsonl.zst",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    // Format of an output file and its name without the extension
+    pub fn split_name(file_name: &str) -> Option<(Format, &str)> {
+        Format::ALL.into_iter().find_map(|f| {
+            let stem = file_name.strip_suffix(f.extension())?.strip_suffix('.')?;
+            Some((f, stem))
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL
+            .into_iter()
+            .find(|f| f.extension() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown format {s:?}, expected one of: jsonl, jsonl.gz, jsonl.zst, parquet"
+                )
+            })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SinkOptions {
+    pub format: Format,
+    pub row_group_size: usize,
+}
+
+impl Default for SinkOptions {
+    fn default() -> Self {
+        Self {
+            format: Format::Jsonl,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+        }
+    }
+}
+
+// Rows that can be written as a Parquet row group
+pub trait Tabular: Serialize + Sized {
+    fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame>;
+}
+
+enum Writer<T> {
+    Jsonl(BufWriter<File>),
+    JsonlGz(GzEncoder<BufWriter<File>>),
+    JsonlZst(zstd::Encoder<'static, BufWriter<File>>),
+    Parquet {
+        file: Option<File>,
+        writer: Option<Box<BatchedWriter<File>>>,
+        rows: Vec<T>,
+        row_group_size: usize,
+    },
+}
+
+fn to_io_error(e: PolarsError) -> io::Error {
+    io::Error::other(e)
+}
+
+// Streams records to one output file through a single buffered writer.
+// Records go to "<path>.tmp", which `finish` renames over `path`, so an
+// interrupted run never leaves a truncated output behind.
+pub struct RecordSink<T: Tabular> {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: Writer<T>,
+    count: usize,
+    finished: bool,
+}
+
+impl<T: Tabular> RecordSink<T> {
+    // Sink for "<dir>/<name>.<extension>"
+    pub fn create(dir: &Path, name: &str, opts: &SinkOptions) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{name}.{}", opts.format.extension()));
+        let tmp_path = dir.join(format!("{name}.{}.tmp", opts.format.extension()));
+        let file = File::create(&tmp_path)?;
+        let writer = match opts.format {
+            Format::Jsonl => Writer::Jsonl(BufWriter::new(file)),
+            Format::JsonlGz => {
+                Writer::JsonlGz(GzEncoder::new(BufWriter::new(file), Compression::default()))
+            }
+            Format::JsonlZst => Writer::JsonlZst(zstd::Encoder::new(BufWriter::new(file), 0)?),
+            // The schema is known once the first row group is built
+            Format::Parquet => Writer::Parquet {
+                file: Some(file),
+                writer: None,
+                rows: Vec::new(),
+                row_group_size: opts.row_group_size.max(1),
+            },
+        };
+        Ok(Self {
+            path,
+            tmp_path,
+            writer,
+            count: 0,
+            finished: false,
+        })
+    }
+
+    pub fn write(&mut self, record: T) -> io::Result<()> {
+        match &mut self.writer {
+            Writer::Jsonl(w) => write_line(w, &record)?,
+            Writer::JsonlGz(w) => write_line(w, &record)?,
+            Writer::JsonlZst(w) => write_line(w, &record)?,
+            Writer::Parquet {
+                rows,
+                row_group_size,
+                ..
+            } => {
+                rows.push(record);
+                if rows.len() >= *row_group_size {
+                    self.flush_row_group()?;
+                }
+            }
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    // Writes the buffered Parquet rows as one zstd-compressed row group
+    fn flush_row_group(&mut self) -> io::Result<()> {
+        let Writer::Parquet {
+            file, writer, rows, ..
+        } = &mut self.writer
+        else {
+            return Ok(());
+        };
+        if rows.is_empty() && writer.is_some() {
+            return Ok(());
+        }
+        let mut df = T::to_dataframe(rows).map_err(to_io_error)?;
+        df.rechunk_mut();
+        if writer.is_none()
+            && let Some(file) = file.take()
+        {
+            let batched = ParquetWriter::new(file)
+                .with_compression(ParquetCompression::Zstd(None))
+                .batched(df.schema())
+                .map_err(to_io_error)?;
+            *writer = Some(Box::new(batched));
+        }
+        if let Some(writer) = writer
+            && df.height() > 0
+        {
+            writer.write_batch(&df).map_err(to_io_error)?;
+        }
+        rows.clear();
+        Ok(())
+    }
+
+    // Flushes the output into place, returns the number of records written
+    pub fn finish(self) -> io::Result<usize> {
+        let count = self.count;
+        let path = self.path.clone();
+        fs::rename(self.close()?, path)?;
+        Ok(count)
+    }
+
+    // Flushes the output but leaves it at its temporary path, which is
+    // returned for the caller to rename
+    pub fn close(mut self) -> io::Result<PathBuf> {
+        self.flush_row_group()?;
+        match &mut self.writer {
+            Writer::Jsonl(w) => w.flush()?,
+            // The encoders write their trailers to the buffered file
+            Writer::JsonlGz(w) => {
+                w.try_finish()?;
+                w.get_mut().flush()?;
+            }
+            Writer::JsonlZst(w) => {
+                w.do_finish()?;
+                w.get_mut().flush()?;
+            }
+            Writer::Parquet { writer, .. } => {
+                if let Some(writer) = writer {
+                    writer.finish().map_err(to_io_error)?;
+                }
+            }
+        }
+        File::open(&self.tmp_path)?.sync_all()?;
+        self.finished = true;
+        Ok(self.tmp_path.clone())
+    }
+}
+
+// Reads every record of an extracted or deduplicated output, in any format
+pub fn read_records(path: &Path) -> io::Result<Vec<Record>> {
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let Some((format, _)) = Format::split_name(file_name) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown output format of {}", path.display()),
+        ));
+    };
+    let file = File::open(path)?;
+    match format {
+        Format::Jsonl => read_jsonl(BufReader::new(file)),
+        Format::JsonlGz => read_jsonl(BufReader::new(MultiGzDecoder::new(file))),
+        Format::JsonlZst => read_jsonl(BufReader::new(zstd::Decoder::new(file)?)),
+        Format::Parquet => {
+            let df = ParquetReader::new(file).finish().map_err(to_io_error)?;
+            Record::from_dataframe(&df).map_err(to_io_error)
+        }
+    }
+}
+
+// Skips lines that are not a record, but fails on a read error: a truncated
+// or corrupt compressed stream returns the same error on every call
+fn read_jsonl<R: BufRead>(mut reader: R) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    loop {
+        match jsonl::read::<_, Record>(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(jsonl::ReadError::Eof) => break,
+            Err(jsonl::ReadError::Deserialize(e)) => eprintln!("{}", e),
+            Err(jsonl::ReadError::Io(e)) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, record: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+impl<T: Tabular> Drop for RecordSink<T> {
+    fn drop(&mut self) {
+        // Dropped before `finish` or `close`: discard the partial output
+        if !self.finished {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use polars::prelude::*;
+    use serde::Serialize;
+
+    use crate::extractor::Record;
+    use crate::sink::{Format, RecordSink, SinkOptions, Tabular, read_records};
+
+    #[derive(Serialize)]
+    struct Row(String);
+
+    impl Tabular for Row {
+        fn to_dataframe(rows: &[Self]) -> PolarsResult<DataFrame> {
+            let values: Vec<&str> = rows.iter().map(|r| r.0.as_str()).collect();
+            DataFrame::new(vec![Column::new("value".into(), values)])
+        }
+    }
+
+    #[test]
+    fn test_record_sink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("user-repo.jsonl");
+        let tmp_path = dir.join("user-repo.jsonl.tmp");
+        let opts = SinkOptions::default();
+
+        let mut sink = RecordSink::create(dir, "user-repo", &opts).unwrap();
+        sink.write(Row("a".into())).unwrap();
+        sink.write(Row("b".into())).unwrap();
+        assert!(tmp_path.exists() && !path.exists());
+        assert_eq!(sink.finish().unwrap(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\"a\"\n\"b\"\n");
+        assert!(!tmp_path.exists());
+
+        // An unfinished sink leaves the previous output untouched
+        let mut sink = RecordSink::create(dir, "user-repo", &opts).unwrap();
+        sink.write(Row("c".into())).unwrap();
+        drop(sink);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\"a\"\n\"b\"\n");
+        assert!(!tmp_path.exists());
+
+        // Row groups of two rows
+        let opts = SinkOptions {
+            format: Format::Parquet,
+            row_group_size: 2,
+        };
+        let mut sink = RecordSink::create(dir, "user-repo", &opts).unwrap();
+        for v in ["a", "b", "c"] {
+            sink.write(Row(v.into())).unwrap();
+        }
+        sink.finish().unwrap();
+        let df = ParquetReader::new(fs::File::open(dir.join("user-repo.parquet")).unwrap())
+            .finish()
+            .unwrap();
+        assert_eq!(df.height(), 3);
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Larger than the write buffer, so the encoders flush mid-stream
+        let text = "x = 1\n".repeat(4096);
+        for format in [Format::JsonlGz, Format::JsonlZst] {
+            let opts = SinkOptions {
+                format,
+                ..SinkOptions::default()
+            };
+            let mut sink = RecordSink::create(tmp.path(), "user-repo", &opts).unwrap();
+            for path in ["a.py", "b.py"] {
+                sink.write(Record::for_test("user/repo", path, "Python", &text))
+                    .unwrap();
+            }
+            sink.finish().unwrap();
+
+            let path = tmp.path().join(format!("user-repo.{}", format.extension()));
+            let records = read_records(&path).unwrap();
+            let paths: Vec<&str> = records.iter().map(|r| r.path.as_str()).collect();
+            assert_eq!(paths, ["a.py", "b.py"]);
+            assert_eq!(records[1].text, text);
+        }
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let opts = SinkOptions {
+            format: Format::JsonlGz,
+            ..SinkOptions::default()
+        };
+        let mut sink = RecordSink::create(tmp.path(), "user-repo", &opts).unwrap();
+        let text = "x = 1\n".repeat(4096);
+        sink.write(Record::for_test("user/repo", "a.py", "Python", &text))
+            .unwrap();
+        sink.finish().unwrap();
+
+        let path = tmp.path().join("user-repo.jsonl.gz");
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(read_records(&path).is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!("jsonl.zst".parse::<Format>(), Ok(Format::JsonlZst));
+        assert_eq!(
+            Format::split_name("user-socket.io.jsonl.gz"),
+            Some((Format::JsonlGz, "user-socket.io"))
+        );
+        assert_eq!(Format::split_name("user-repo.zip"), None);
+    }
+}
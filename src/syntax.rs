@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use tree_sitter::{Language, Parser, Tree};
+
+// What happens to files with more parse errors than allowed
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SyntaxMode {
+    // Excluded, logged with reason "syntax"
+    #[default]
+    Reject,
+    // Kept, with their error count in `syntax_errors`
+    Tag,
+}
+
+impl FromStr for SyntaxMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(SyntaxMode::Reject),
+            "tag" => Ok(SyntaxMode::Tag),
+            _ => Err(format!(
+                "unknown syntax check {s:?}, expected one of: reject, tag"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SyntaxCheck {
+    pub mode: SyntaxMode,
+    // ERROR and MISSING nodes tolerated per file
+    pub max_errors: usize,
+}
+
+// Tree-sitter grammar of a Linguist language, None when it has no bundled grammar
+pub fn grammar(language: &str) -> Option<Language> {
+    let grammar = match language {
+        "C" => tree_sitter_c::LANGUAGE,
+        "C++" => tree_sitter_cpp::LANGUAGE,
+        "Go" => tree_sitter_go::LANGUAGE,
+        "Java" => tree_sitter_java::LANGUAGE,
+        "JavaScript" => tree_sitter_javascript::LANGUAGE,
+        "Python" => tree_sitter_python::LANGUAGE,
+        "Rust" => tree_sitter_rust::LANGUAGE,
+        "TypeScript" => tree_sitter_typescript::LANGUAGE_TYPESCRIPT,
+        "TSX" => tree_sitter_typescript::LANGUAGE_TSX,
+        // One grammar covers Verilog and SystemVerilog
+        "Verilog" | "SystemVerilog" => tree_sitter_verilog::LANGUAGE,
+        _ => return None,
+    };
+    Some(grammar.into())
+}
+
+// Syntax tree of `text`, None when the language has no grammar
+pub fn parse(language: &str, text: &str) -> Option<Tree> {
+    let mut parser = Parser::new();
+    parser.set_language(&grammar(language)?).ok()?;
+    parser.parse(text, None)
+}
+
+// ERROR and MISSING nodes of a tree, only descending into subtrees that
+// hold one
+fn error_nodes(tree: &Tree) -> usize {
+    let mut count = 0;
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        if node.is_error() || node.is_missing() {
+            count += 1;
+        }
+        let mut cursor = node.walk();
+        stack.extend(node.children(&mut cursor).filter(|c| c.has_error()));
+    }
+    count
+}
+
+// Parse errors of `text`, None when the language has no grammar
+pub fn syntax_errors(language: &str, text: &str) -> Option<usize> {
+    parse(language, text).map(|tree| error_nodes(&tree))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::syntax::syntax_errors;
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(
+            syntax_errors("Python", "def f(x):\n    return x\n"),
+            Some(0)
+        );
+        assert!(syntax_errors("Python", "def f(x:\n    return x\n").unwrap() > 0);
+        // Truncated file, the closing brace is missing
+        assert!(syntax_errors("Rust", "fn main() {\n    let x = 1;\n").unwrap() > 0);
+        assert_eq!(syntax_errors("Verilog", "module m; endmodule\n"), Some(0));
+        assert!(syntax_errors("Verilog", "module m;\n  assign a = ;\n").unwrap() > 0);
+        assert_eq!(syntax_errors("Brainfuck", "+++"), None);
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use colored::Colorize;
+use tokenizers::Tokenizer;
+
+use crate::error::ExtractionError;
+
+const TOKENIZER_FILE: &str = "tokenizer.json";
+
+// `[name=]source` where source is a local tokenizer.json (or a directory
+// holding one) or a Hugging Face hub id
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizerSpec {
+    pub name: String,
+    pub source: String,
+}
+
+impl FromStr for TokenizerSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, source) = match s.split_once('=') {
+            Some((name, source)) => (name.to_owned(), source.to_owned()),
+            None => (default_name(s), s.to_owned()),
+        };
+        if name.is_empty() || source.is_empty() {
+            return Err(format!(
+                "invalid tokenizer {s:?}, expected [name=]path-or-hub-id"
+            ));
+        }
+        Ok(Self { name, source })
+    }
+}
+
+impl fmt::Display for TokenizerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.source)
+    }
+}
+
+impl Default for TokenizerSpec {
+    fn default() -> Self {
+        Self {
+            name: String::from("gpt2"),
+            source: String::from("openai-community/gpt2"),
+        }
+    }
+}
+
+// "openai-community/gpt2" -> "gpt2", "models/llama3/tokenizer.json" -> "llama3"
+fn default_name(source: &str) -> String {
+    let path = Path::new(source);
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(TOKENIZER_FILE) => path.parent().and_then(|p| p.file_name()),
+        _ => path.file_stem(),
+    };
+    name.map(|n| n.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+// Hugging Face hub cache, same lookup order as huggingface_hub
+fn hub_cache_dir() -> Option<PathBuf> {
+    if let Ok(dir) = std::env::var("HF_HUB_CACHE") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Ok(home) = std::env::var("HF_HOME") {
+        return Some(PathBuf::from(home).join("hub"));
+    }
+    let home = std::env::var("HOME").ok()?;
+    Some(PathBuf::from(home).join(".cache/huggingface/hub"))
+}
+
+// tokenizer.json of `repo_id` in the hub cache, preferring the snapshot `main` points to
+fn cached_tokenizer(repo_id: &str) -> Option<PathBuf> {
+    let repo_dir = hub_cache_dir()?.join(format!("models--{}", repo_id.replace('/', "--")));
+    let snapshots = repo_dir.join("snapshots");
+    if let Ok(commit) = fs::read_to_string(repo_dir.join("refs/main")) {
+        let path = snapshots.join(commit.trim()).join(TOKENIZER_FILE);
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    let mut candidates: Vec<PathBuf> = fs::read_dir(snapshots)
+        .ok()?
+        .filter_map(|e| Some(e.ok()?.path().join(TOKENIZER_FILE)))
+        .filter(|p| p.is_file())
+        .collect();
+    candidates.sort();
+    candidates.pop()
+}
+
+fn load_tokenizer(spec: &TokenizerSpec, offline: bool) -> Result<Tokenizer, ExtractionError> {
+    let local = Path::new(&spec.source);
+    let path = if local.is_dir() {
+        Some(local.join(TOKENIZER_FILE))
+    } else if local.is_file() {
+        Some(local.to_path_buf())
+    } else {
+        cached_tokenizer(&spec.source)
+    };
+
+    let tokenizer = match path {
+        Some(path) => Tokenizer::from_file(&path),
+        None if offline => {
+            return Err(ExtractionError::Tokenizer {
+                message: format!(
+                    "Tokenizer {} is neither a local file nor in the hub cache, and downloads are disabled",
+                    spec.source
+                ),
+            });
+        }
+        None => Tokenizer::from_pretrained(&spec.source, None),
+    };
+    let mut tokenizer = tokenizer.map_err(|e| ExtractionError::Tokenizer {
+        message: format!("Unable to load tokenizer {}: {}", spec, e),
+    })?;
+    // Padding or truncation in tokenizer.json would skew batched counts
+    tokenizer
+        .with_padding(None)
+        .with_truncation(None)
+        .map_err(|e| ExtractionError::Tokenizer {
+            message: format!("{}: {}", spec, e),
+        })?;
+    Ok(tokenizer)
+}
+
+// Named tokenizers used to count tokens for every record, none with --no-tokenize
+pub struct Tokenizers {
+    tokenizers: Vec<(String, Tokenizer)>,
+}
+
+impl Tokenizers {
+    // With `offline` (or HF_HUB_OFFLINE=1) hub ids must already be cached
+    pub fn load(specs: &[TokenizerSpec], offline: bool) -> Result<Self, ExtractionError> {
+        let offline = offline || std::env::var("HF_HUB_OFFLINE").is_ok_and(|v| v == "1");
+        let mut tokenizers = Vec::new();
+        for spec in specs {
+            if tokenizers.iter().any(|(name, _)| name == &spec.name) {
+                eprintln!(
+                    "{} Duplicated tokenizer name {}, skipping {}",
+                    "[WARNING]".truecolor(214, 143, 0),
+                    spec.name,
+                    spec.source
+                );
+                continue;
+            }
+            tokenizers.push((spec.name.clone(), load_tokenizer(spec, offline)?));
+        }
+        Ok(Self { tokenizers })
+    }
+
+    // "test" tokenizer splitting on whitespace and punctuation, that fails
+    // on words outside `vocab`
+    #[cfg(test)]
+    pub fn for_test(vocab: &[&str]) -> Self {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (w.to_string(), i as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token(String::from("[UNK]"))
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        Self {
+            tokenizers: vec![(String::from("test"), tokenizer)],
+        }
+    }
+
+    // "test" tokenizer splitting like GPT-2, whitespace leading the next
+    // word ("\n   ", " return"), with every piece in one unknown token
+    #[cfg(test)]
+    pub fn for_test_byte_level() -> Self {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+
+        let model = WordLevel::builder()
+            .vocab([(String::from("[UNK]"), 0)].into())
+            .unk_token(String::from("[UNK]"))
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, false, true)));
+        Self {
+            tokenizers: vec![(String::from("test"), tokenizer)],
+        }
+    }
+
+    // Token counts of every text, tokenizing the whole batch at once
+    pub fn count_batch(
+        &self,
+        texts: &[&str],
+    ) -> Result<Vec<BTreeMap<String, usize>>, ExtractionError> {
+        let mut counts = vec![BTreeMap::new(); texts.len()];
+        for (name, tokenizer) in &self.tokenizers {
+            let encodings = tokenizer.encode_batch(texts.to_vec(), false);
+            let encodings = encodings.map_err(|e| ExtractionError::Tokenizer {
+                message: format!("{name}: {e}"),
+            })?;
+            for (c, encoding) in counts.iter_mut().zip(encodings) {
+                c.insert(name.clone(), encoding.len());
+            }
+        }
+        Ok(counts)
+    }
+
+    // Name of the tokenizer token offsets come from
+    pub fn primary(&self) -> Option<&str> {
+        self.tokenizers.first().map(|(name, _)| name.as_str())
+    }
+
+    // Byte offsets where the tokens of the first tokenizer start
+    pub fn token_starts(&self, text: &str) -> Result<Vec<usize>, ExtractionError> {
+        let Some((name, tokenizer)) = self.tokenizers.first() else {
+            return Ok(Vec::new());
+        };
+        let encoding = tokenizer
+            .encode(text, false)
+            .map_err(|e| ExtractionError::Tokenizer {
+                message: format!("{name}: {e}"),
+            })?;
+        let mut starts: Vec<usize> = encoding
+            .get_offsets()
+            .iter()
+            .map(|(start, _)| *start)
+            .filter(|start| text.is_char_boundary(*start))
+            .collect();
+        starts.dedup();
+        Ok(starts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokenization::TokenizerSpec;
+
+    #[test]
+    fn test_tokenizer_spec() {
+        let spec: TokenizerSpec = "openai-community/gpt2".parse().unwrap();
+        assert_eq!(spec, TokenizerSpec::default());
+
+        let spec: TokenizerSpec = "models/llama3/tokenizer.json".parse().unwrap();
+        assert_eq!(spec.name, "llama3");
+
+        let spec: TokenizerSpec = "code=./tok/code.json".parse().unwrap();
+        assert_eq!(
+            (spec.name.as_str(), spec.source.as_str()),
+            ("code", "./tok/code.json")
+        );
+
+        assert!("=gpt2".parse::<TokenizerSpec>().is_err());
+    }
+}
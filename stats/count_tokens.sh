@@ -2,15 +2,24 @@
 
 set -e
 if [ -z "$1" ]; then
-    echo "Usage: $0 <directory>"
+    echo "Usage: $0 <directory> [tokenizer]"
     exit 1
 fi
 
 DIR="$1"
-total=0
-for file in "$DIR"/*.jsonl; do
-    file_count=$(jq -s '[.[] | .tokens] | add' "$file")
-    total=$((total + file_count))
-done
+# Counts of different tokenizers do not add up, so each one is totalled
+# separately unless one is named
+TOKENIZER="$2"
+# Older outputs hold a single gpt2 count
+totals=$(jq -n -r 'reduce (inputs | .tokens // {} | if type == "number" then {gpt2: .} else . end
+        | to_entries[]) as $e ({}; .[$e.key] += $e.value)
+    | to_entries[] | "\(.key) \(.value)"' "$DIR"/*.jsonl)
 
-echo "Total tokens: $total"
+if [ -n "$TOKENIZER" ]; then
+    total=$(echo "$totals" | awk -v t="$TOKENIZER" '$1 == t { print $2 }')
+    echo "Total tokens: ${total:-0}"
+else
+    echo "$totals" | while read -r name total; do
+        echo "Total $name tokens: $total"
+    done
+fi